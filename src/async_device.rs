@@ -0,0 +1,972 @@
+//! An async flavour of [`crate::device::DeviceManager`], running on tokio.
+//! Only available with the `tokio` feature.
+
+use std::future::Future;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use tokio::net::UdpSocket;
+use tokio::sync::{mpsc, Notify};
+
+use crate::commands::{self, Command};
+use crate::device::{
+    self, DeliveryOutcome, Device, DeviceManagerConfig, DeviceManagerEvent, DeviceRegistry,
+    PacketMatcher, RetryPolicy, ThreadsafeDeviceDiscoveryImpl, ThreadsafeNetworkImpl,
+};
+use crate::discovery_reply::DiscoveryReply;
+use crate::error::IoContext;
+use crate::protocol::{self, Direction, Packet, PacketSender};
+use crate::{Error, Result};
+
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+pub trait AsyncPacketSender: Send + Sync {
+    fn send_packet<'a>(
+        &'a self,
+        packet: &'a Packet,
+        to: SocketAddr,
+    ) -> BoxFuture<'a, Result<usize>>;
+}
+
+pub trait AsyncPacketReceiver: Send {
+    fn receive_packet(&mut self) -> BoxFuture<'_, Result<(SocketAddr, Packet)>>;
+}
+
+pub trait AsyncNetworkImpl: Send + Sync {
+    fn packet_sender(&self) -> Result<Box<dyn AsyncPacketSender>>;
+    fn packet_receiver(&self, port: u16) -> Result<Box<dyn AsyncPacketReceiver>>;
+
+    /// Returns the address of the local interface used to reach
+    /// `device_addr`, which is where the device should send notifications.
+    fn local_addr_for(&self, device_addr: IpAddr) -> Result<IpAddr> {
+        device::route_local_addr(device_addr)
+    }
+}
+
+pub trait AsyncDeviceDiscoveryImpl: Send + Sync {
+    fn discover(&self) -> BoxFuture<'_, Result<()>>;
+    fn poll(&self) -> BoxFuture<'_, Result<DiscoveryReply>>;
+}
+
+impl AsyncPacketSender for UdpSocket {
+    fn send_packet<'a>(
+        &'a self,
+        packet: &'a Packet,
+        to: SocketAddr,
+    ) -> BoxFuture<'a, Result<usize>> {
+        Box::pin(async move {
+            self.send_to(&packet.data(), to)
+                .await
+                .with_context(|| format!("error sending packet to {}", to))
+        })
+    }
+}
+
+impl AsyncPacketReceiver for UdpSocket {
+    fn receive_packet(&mut self) -> BoxFuture<'_, Result<(SocketAddr, Packet)>> {
+        Box::pin(async move {
+            let mut recv_buffer = vec![0; 65536];
+            let (count, from) = self
+                .recv_from(&mut recv_buffer)
+                .await
+                .context("error receiving packet")?;
+
+            Ok((from, Packet::parse(&recv_buffer[..count])?))
+        })
+    }
+}
+
+fn tokio_socket(port: u16) -> Result<UdpSocket> {
+    let sock = device::reusable_socket(Ipv4Addr::UNSPECIFIED, port)?;
+    sock.set_nonblocking(true)
+        .and_then(|_| UdpSocket::from_std(sock))
+        .with_context(|| format!("error creating socket on port {}", port))
+}
+
+struct TokioNetworkImpl;
+
+impl AsyncNetworkImpl for TokioNetworkImpl {
+    fn packet_sender(&self) -> Result<Box<dyn AsyncPacketSender>> {
+        Ok(Box::new(tokio_socket(0)?))
+    }
+
+    fn packet_receiver(&self, port: u16) -> Result<Box<dyn AsyncPacketReceiver>> {
+        Ok(Box::new(tokio_socket(port)?))
+    }
+}
+
+struct TokioSSDPDiscovery {
+    sock: UdpSocket,
+}
+
+impl AsyncDeviceDiscoveryImpl for TokioSSDPDiscovery {
+    fn discover(&self) -> BoxFuture<'_, Result<()>> {
+        Box::pin(async move {
+            self.sock
+                .send_to(
+                    device::SSDP_SEARCH_REQUEST.as_bytes(),
+                    SocketAddr::new(
+                        IpAddr::V4(protocol::SSDP_MULTICAST_ADDR),
+                        protocol::SSDP_MULTICAST_PORT,
+                    ),
+                )
+                .await
+                .context("error sending discovery packet")?;
+            Ok(())
+        })
+    }
+
+    fn poll(&self) -> BoxFuture<'_, Result<DiscoveryReply>> {
+        Box::pin(async move {
+            let mut recv_buffer = vec![0; 4096];
+
+            loop {
+                let (count, _) = self
+                    .sock
+                    .recv_from(&mut recv_buffer)
+                    .await
+                    .context("error receiving discovery packet")?;
+
+                if recv_buffer[..count].starts_with(b"M-SEARCH") {
+                    continue;
+                }
+
+                return DiscoveryReply::parse(&recv_buffer[..count]);
+            }
+        })
+    }
+}
+
+/// Runs a blocking [`crate::device::NetworkImpl`] under an async device
+/// manager. Each receiver gets a thread of its own, which forwards packets
+/// to the async side until the receiving end goes away.
+struct BlockingNetworkImpl(Arc<ThreadsafeNetworkImpl>);
+
+struct BlockingPacketSender(Mutex<Box<dyn PacketSender + Send>>);
+
+struct ForwardedPackets(mpsc::UnboundedReceiver<Result<(SocketAddr, Packet)>>);
+
+impl AsyncNetworkImpl for BlockingNetworkImpl {
+    fn packet_sender(&self) -> Result<Box<dyn AsyncPacketSender>> {
+        Ok(Box::new(BlockingPacketSender(Mutex::new(
+            self.0.packet_sender()?,
+        ))))
+    }
+
+    fn packet_receiver(&self, port: u16) -> Result<Box<dyn AsyncPacketReceiver>> {
+        let packet_receiver = self.0.packet_receiver(port)?;
+        let (tx, rx) = mpsc::unbounded_channel();
+
+        std::thread::spawn(move || {
+            while !tx.is_closed() {
+                let result = match packet_receiver.receive_packet(device::RECEIVE_TIMEOUT) {
+                    Ok(Some(x)) => Ok(x),
+                    Ok(None) => continue,
+                    Err(err) => Err(err),
+                };
+                let shutdown = matches!(result, Err(Error::Shutdown));
+
+                if tx.send(result).is_err() || shutdown {
+                    return;
+                }
+            }
+        });
+
+        Ok(Box::new(ForwardedPackets(rx)))
+    }
+
+    fn local_addr_for(&self, device_addr: IpAddr) -> Result<IpAddr> {
+        self.0.local_addr_for(device_addr)
+    }
+}
+
+impl AsyncPacketSender for BlockingPacketSender {
+    fn send_packet<'a>(
+        &'a self,
+        packet: &'a Packet,
+        to: SocketAddr,
+    ) -> BoxFuture<'a, Result<usize>> {
+        Box::pin(async move { self.0.lock().unwrap().send_packet(packet, to) })
+    }
+}
+
+impl AsyncPacketReceiver for ForwardedPackets {
+    fn receive_packet(&mut self) -> BoxFuture<'_, Result<(SocketAddr, Packet)>> {
+        Box::pin(async move { self.0.recv().await.unwrap_or(Err(Error::Shutdown)) })
+    }
+}
+
+/// Runs a blocking [`crate::device::DeviceDiscoveryImpl`] under an async
+/// device manager, polling it from a thread of its own until the
+/// implementation goes away.
+struct BlockingDeviceDiscoveryImpl {
+    device_discovery_impl: Arc<ThreadsafeDeviceDiscoveryImpl>,
+    replies: tokio::sync::Mutex<mpsc::UnboundedReceiver<Result<DiscoveryReply>>>,
+}
+
+impl BlockingDeviceDiscoveryImpl {
+    fn new(device_discovery_impl: Arc<ThreadsafeDeviceDiscoveryImpl>) -> Self {
+        let (tx, rx) = mpsc::unbounded_channel();
+
+        {
+            let device_discovery_impl = Arc::clone(&device_discovery_impl);
+
+            std::thread::spawn(move || {
+                while !tx.is_closed() {
+                    let result = match device_discovery_impl.poll(device::RECEIVE_TIMEOUT) {
+                        Ok(Some(reply)) => Ok(reply),
+                        Ok(None) => continue,
+                        Err(err) => Err(err),
+                    };
+                    let shutdown = matches!(result, Err(Error::Shutdown));
+
+                    if tx.send(result).is_err() || shutdown {
+                        return;
+                    }
+                }
+            });
+        }
+
+        BlockingDeviceDiscoveryImpl {
+            device_discovery_impl,
+            replies: tokio::sync::Mutex::new(rx),
+        }
+    }
+}
+
+impl AsyncDeviceDiscoveryImpl for BlockingDeviceDiscoveryImpl {
+    fn discover(&self) -> BoxFuture<'_, Result<()>> {
+        Box::pin(async move { self.device_discovery_impl.discover() })
+    }
+
+    fn poll(&self) -> BoxFuture<'_, Result<DiscoveryReply>> {
+        Box::pin(async move {
+            self.replies
+                .lock()
+                .await
+                .recv()
+                .await
+                .unwrap_or(Err(Error::Shutdown))
+        })
+    }
+}
+
+pub struct AsyncDeviceManagerConfig {
+    network_impl: Arc<dyn AsyncNetworkImpl>,
+    device_discovery_impl: Arc<dyn AsyncDeviceDiscoveryImpl>,
+    retry_policy: RetryPolicy,
+    device_expiry: Duration,
+    discovery_interval: Option<Duration>,
+    subscription_interval: Duration,
+}
+
+impl AsyncDeviceManagerConfig {
+    /// Returns a configuration talking to devices on the local network.
+    /// Must be called from within a tokio runtime.
+    #[allow(clippy::should_implement_trait)]
+    pub fn default() -> Result<Self> {
+        Ok(Self::new(
+            Box::new(TokioNetworkImpl {}),
+            Box::new(TokioSSDPDiscovery {
+                sock: tokio_socket(protocol::SSDP_MULTICAST_PORT)?,
+            }),
+        ))
+    }
+
+    pub fn new(
+        network_impl: Box<dyn AsyncNetworkImpl>,
+        device_discovery_impl: Box<dyn AsyncDeviceDiscoveryImpl>,
+    ) -> Self {
+        AsyncDeviceManagerConfig {
+            network_impl: Arc::from(network_impl),
+            device_discovery_impl: Arc::from(device_discovery_impl),
+            retry_policy: RetryPolicy::default(),
+            device_expiry: device::DEFAULT_DEVICE_EXPIRY,
+            discovery_interval: Some(device::DEFAULT_DISCOVERY_INTERVAL),
+            subscription_interval: device::DEFAULT_SUBSCRIPTION_INTERVAL,
+        }
+    }
+
+    /// Runs the blocking implementations of a [`DeviceManagerConfig`], like
+    /// the ones from [`crate::fake`], under an async device manager. Their
+    /// receiving ends each get a thread of their own.
+    pub fn from_blocking(config: DeviceManagerConfig) -> Self {
+        AsyncDeviceManagerConfig {
+            network_impl: Arc::new(BlockingNetworkImpl(config.network_impl)),
+            device_discovery_impl: Arc::new(BlockingDeviceDiscoveryImpl::new(
+                config.device_discovery_impl,
+            )),
+            retry_policy: config.retry_policy,
+            device_expiry: config.device_expiry,
+            discovery_interval: config.discovery_interval,
+            subscription_interval: config.subscription_interval,
+        }
+    }
+
+    /// Sets how commands are retransmitted. Fails if the backoff is 0, see
+    /// [`DeviceManagerConfig::with_retry_policy`].
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Result<Self> {
+        retry_policy.validate()?;
+        self.retry_policy = retry_policy;
+        Ok(self)
+    }
+
+    /// Sets how long a device can stay silent before it is reported as lost.
+    pub fn with_device_expiry(mut self, device_expiry: Duration) -> Self {
+        self.device_expiry = device_expiry;
+        self
+    }
+
+    /// Sets how often discovery requests are sent. With `None`, discovery
+    /// requests are only sent at startup and when calling
+    /// [`AsyncDeviceManager::rediscover`].
+    pub fn with_discovery_interval(mut self, discovery_interval: Option<Duration>) -> Self {
+        self.discovery_interval = discovery_interval;
+        self
+    }
+
+    /// Sets how often we register again with devices for notifications, and
+    /// how long to wait before trying again when a device did not confirm
+    /// the registration.
+    pub fn with_subscription_interval(mut self, subscription_interval: Duration) -> Self {
+        self.subscription_interval = subscription_interval;
+        self
+    }
+}
+
+/// The events of an [`AsyncDeviceManager`], see
+/// [`AsyncDeviceManager::listen`].
+pub struct EventStream(mpsc::UnboundedReceiver<DeviceManagerEvent>);
+
+impl EventStream {
+    /// Waits for the next event, returns `None` once the device manager is
+    /// gone.
+    pub async fn recv(&mut self) -> Option<DeviceManagerEvent> {
+        self.0.recv().await
+    }
+}
+
+impl futures_core::Stream for EventStream {
+    type Item = DeviceManagerEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.0.poll_recv(cx)
+    }
+}
+
+struct AsyncDeviceManagerState {
+    event_listeners: Vec<mpsc::UnboundedSender<DeviceManagerEvent>>,
+    /// `None` once the device manager is shut down
+    sock_send: Option<Arc<dyn AsyncPacketSender>>,
+    registry: DeviceRegistry,
+}
+
+/// What the tasks of an [`AsyncDeviceManager`] share. The state lock is never
+/// held across an await point.
+struct AsyncDeviceManagerData {
+    state: Mutex<AsyncDeviceManagerState>,
+    network_impl: Arc<dyn AsyncNetworkImpl>,
+    search_requests: Notify,
+}
+
+/// Same as [`crate::device::DeviceManager`], but for async code running on
+/// tokio. Devices are kept track of by tasks spawned on the runtime
+/// [`AsyncDeviceManager::new`] runs on.
+///
+/// Dropping the device manager shuts it down, see
+/// [`AsyncDeviceManager::shutdown`].
+pub struct AsyncDeviceManager {
+    data: Arc<AsyncDeviceManagerData>,
+    retry_policy: RetryPolicy,
+    tasks: Mutex<Vec<tokio::task::JoinHandle<()>>>,
+}
+
+impl AsyncDeviceManager {
+    pub async fn new(config: AsyncDeviceManagerConfig) -> Result<AsyncDeviceManager> {
+        let data = Arc::new(AsyncDeviceManagerData {
+            state: Mutex::new(AsyncDeviceManagerState {
+                event_listeners: vec![],
+                sock_send: Some(Arc::from(config.network_impl.packet_sender()?)),
+                registry: DeviceRegistry::new(config.subscription_interval),
+            }),
+            network_impl: Arc::clone(&config.network_impl),
+            search_requests: Notify::new(),
+        });
+
+        let mut tasks = vec![];
+
+        {
+            let data = Arc::clone(&data);
+            let device_discovery_impl = Arc::clone(&config.device_discovery_impl);
+
+            tasks.push(Self::spawn_task("discovery", move || {
+                Self::discovery_task(Arc::clone(&data), Arc::clone(&device_discovery_impl))
+            }));
+        }
+
+        {
+            let data = Arc::clone(&data);
+            let device_discovery_impl = Arc::clone(&config.device_discovery_impl);
+            let interval = config.discovery_interval;
+
+            tasks.push(Self::spawn_task("search", move || {
+                Self::search_task(
+                    Arc::clone(&data),
+                    Arc::clone(&device_discovery_impl),
+                    interval,
+                )
+            }));
+        }
+
+        {
+            let data = Arc::clone(&data);
+
+            tasks.push(Self::spawn_task("notification", move || {
+                Self::notification_task(Arc::clone(&data))
+            }));
+        }
+
+        {
+            let data = Arc::clone(&data);
+
+            tasks.push(Self::spawn_task("command reply", move || {
+                Self::command_reply_task(Arc::clone(&data))
+            }));
+        }
+
+        {
+            let data = Arc::clone(&data);
+            let device_expiry = config.device_expiry;
+
+            tasks.push(Self::spawn_task("liveness", move || {
+                Self::liveness_task(Arc::clone(&data), device_expiry)
+            }));
+        }
+
+        {
+            let data = Arc::clone(&data);
+            let retry_policy = config.retry_policy;
+
+            tasks.push(Self::spawn_task("subscription", move || {
+                Self::subscription_task(Arc::clone(&data), retry_policy)
+            }));
+        }
+
+        Ok(AsyncDeviceManager {
+            data,
+            retry_policy: config.retry_policy,
+            tasks: Mutex::new(tasks),
+        })
+    }
+
+    /// Spawns a task, restarting it when it fails. The task ends when it
+    /// returns successfully, or once the network or discovery implementation
+    /// it relies on is shut down.
+    fn spawn_task<F, T>(task_name: &'static str, task_func: F) -> tokio::task::JoinHandle<()>
+    where
+        F: Fn() -> T + Send + 'static,
+        T: Future<Output = Result<()>> + Send + 'static,
+    {
+        tokio::spawn(async move {
+            loop {
+                match task_func().await {
+                    Ok(()) | Err(Error::Shutdown) => {
+                        tracing::debug!(task = task_name, "task stopped");
+                        return;
+                    }
+                    Err(err) => {
+                        tracing::error!(task = task_name, error = %err, "task failed, restarting it");
+                        tokio::time::sleep(Duration::from_secs(5)).await;
+                    }
+                }
+            }
+        })
+    }
+
+    async fn discovery_task(
+        data: Arc<AsyncDeviceManagerData>,
+        device_discovery_impl: Arc<dyn AsyncDeviceDiscoveryImpl>,
+    ) -> Result<()> {
+        loop {
+            match device_discovery_impl.poll().await {
+                Ok(x) => data.with_registry(|registry| registry.register_device(&x)),
+                Err(Error::Shutdown) => return Err(Error::Shutdown),
+                Err(err) => {
+                    tracing::warn!(error = %err, "error processing discovery reply");
+                    continue;
+                }
+            };
+        }
+    }
+
+    async fn search_task(
+        data: Arc<AsyncDeviceManagerData>,
+        device_discovery_impl: Arc<dyn AsyncDeviceDiscoveryImpl>,
+        interval: Option<Duration>,
+    ) -> Result<()> {
+        loop {
+            device_discovery_impl.discover().await?;
+
+            // Wait for the next periodic search, or for someone to call
+            // rediscover()
+            let requested = data.search_requests.notified();
+
+            match interval {
+                Some(interval) => {
+                    let _ = tokio::time::timeout(interval, requested).await;
+                }
+                None => requested.await,
+            }
+        }
+    }
+
+    async fn liveness_task(
+        data: Arc<AsyncDeviceManagerData>,
+        device_expiry: Duration,
+    ) -> Result<()> {
+        let check_interval = (device_expiry / 4).min(Duration::from_secs(5));
+
+        loop {
+            tokio::time::sleep(check_interval).await;
+            data.with_registry(|registry| registry.expire_devices(device_expiry));
+        }
+    }
+
+    async fn subscription_task(
+        data: Arc<AsyncDeviceManagerData>,
+        retry_policy: RetryPolicy,
+    ) -> Result<()> {
+        loop {
+            tokio::time::sleep(device::SUBSCRIPTION_CHECK_INTERVAL).await;
+            data.send_hellos(&retry_policy).await;
+        }
+    }
+
+    async fn notification_task(data: Arc<AsyncDeviceManagerData>) -> Result<()> {
+        let mut packet_receiver = data
+            .network_impl
+            .packet_receiver(protocol::NOTIF_RECV_PORT)?;
+
+        loop {
+            match packet_receiver.receive_packet().await {
+                Ok((from_addr, packet)) => {
+                    if let Err(err) = data.handle_notification(from_addr, &packet).await {
+                        tracing::warn!(addr = %from_addr, error = %err, "error handling notification");
+                    }
+                }
+                Err(Error::Shutdown) => return Err(Error::Shutdown),
+                Err(err) => {
+                    tracing::warn!(port = protocol::NOTIF_RECV_PORT, error = %err, "invalid packet")
+                }
+            }
+        }
+    }
+
+    async fn command_reply_task(data: Arc<AsyncDeviceManagerData>) -> Result<()> {
+        let mut packet_receiver = data.network_impl.packet_receiver(protocol::CMD_RESP_PORT)?;
+
+        loop {
+            match packet_receiver.receive_packet().await {
+                Ok((from_addr, packet)) => {
+                    let result =
+                        data.with_registry(|registry| registry.handle_reply(from_addr, &packet));
+
+                    if let Err(err) = result {
+                        tracing::warn!(addr = %from_addr, error = %err, "error handling reply");
+                    }
+                }
+                Err(Error::Shutdown) => return Err(Error::Shutdown),
+                Err(err) => {
+                    tracing::warn!(port = protocol::CMD_RESP_PORT, error = %err, "invalid packet")
+                }
+            }
+        }
+    }
+
+    /// Stops the tasks of the device manager and closes its sockets. The
+    /// event streams end, pending and later commands fail with
+    /// [`Error::Shutdown`].
+    ///
+    /// Dropping the device manager shuts it down too.
+    pub fn shutdown(&self) {
+        for task in std::mem::take(&mut *self.tasks.lock().unwrap()) {
+            task.abort();
+        }
+
+        let mut state = self.data.state.lock().unwrap();
+        state.event_listeners.clear();
+        state.sock_send = None;
+        state.registry.cancel_pending_replies();
+    }
+
+    pub fn listen(&self) -> EventStream {
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.data.state.lock().unwrap().event_listeners.push(tx);
+        EventStream(rx)
+    }
+
+    /// Returns the devices discovered so far.
+    pub fn devices(&self) -> Vec<Device> {
+        self.data.state.lock().unwrap().registry.devices()
+    }
+
+    pub fn device(&self, device_id: &str) -> Option<Device> {
+        self.data.state.lock().unwrap().registry.device(device_id)
+    }
+
+    /// Sends a new discovery request right away, instead of waiting for the
+    /// next periodic one.
+    pub fn rediscover(&self) {
+        self.data.search_requests.notify_one();
+    }
+
+    pub async fn fetch_info(&self, device_id: &str) -> Result<()> {
+        for packet in device::info_fetches() {
+            self.data.send_packet(device_id, &packet).await?;
+        }
+
+        Ok(())
+    }
+
+    pub async fn set_volume(&self, device_id: &str, volume: u8) -> Result<DeliveryOutcome> {
+        self.set::<commands::Volume>(device_id, volume.clamp(0, 100))
+            .await
+    }
+
+    pub async fn send_packet(&self, device_id: &str, packet: &Packet) -> Result<()> {
+        self.data.send_packet(device_id, packet).await
+    }
+
+    /// Same as [`crate::device::DeviceManager::request`].
+    pub async fn request(
+        &self,
+        device_id: &str,
+        packet: &Packet,
+        reply_command_id: u16,
+        timeout: Duration,
+    ) -> Result<Packet> {
+        let (pending_reply, mut replies) = self.data.add_pending_reply(
+            device_id,
+            Box::new(move |reply| reply.command == reply_command_id),
+        );
+
+        let packet = Packet {
+            sequence: pending_reply.sequence,
+            ..packet.clone()
+        };
+
+        self.data.send_packet(device_id, &packet).await?;
+
+        let reply = tokio::time::timeout(timeout, replies.recv())
+            .await
+            .map_err(|_| Error::Timeout)?
+            .ok_or(Error::Shutdown)?;
+
+        device::check_status(&reply)?;
+        Ok(reply)
+    }
+
+    /// Same as [`crate::device::DeviceManager::get`].
+    pub async fn get<C: Command>(
+        &self,
+        device_id: &str,
+        timeout: Duration,
+    ) -> Result<C::ResponseData> {
+        let reply = self
+            .request(device_id, &C::fetch(), C::GET_REPLY_COMMAND_ID, timeout)
+            .await?;
+        C::unmarshal_data(reply.command_data.as_deref().unwrap_or_default())
+    }
+
+    /// Same as [`crate::device::DeviceManager::set`].
+    pub async fn set<C: Command + 'static>(
+        &self,
+        device_id: &str,
+        value: C::RequestData,
+    ) -> Result<DeliveryOutcome> {
+        let (packets, matches) = device::set_request::<C>(value)?;
+        let (pending_reply, mut replies) = self.data.add_pending_reply(device_id, matches);
+
+        // retransmissions keep the sequence, since they are the same request
+        let packets: Vec<Packet> = packets
+            .into_iter()
+            .map(|packet| Packet {
+                sequence: pending_reply.sequence,
+                ..packet
+            })
+            .collect();
+
+        for attempt in 1..=self.retry_policy.attempts.max(1) {
+            for packet in &packets {
+                self.data.send_packet(device_id, packet).await?;
+            }
+
+            let timeout = self.retry_policy.attempt_timeout(attempt);
+
+            match tokio::time::timeout(timeout, replies.recv()).await {
+                Ok(Some(reply)) => {
+                    device::check_status(&reply)?;
+                    return Ok(DeliveryOutcome::Delivered { attempts: attempt });
+                }
+                Ok(None) => return Err(Error::Shutdown),
+                // timed out, send again
+                Err(_) => (),
+            }
+        }
+
+        Ok(DeliveryOutcome::Failed {
+            attempts: self.retry_policy.attempts.max(1),
+        })
+    }
+}
+
+impl Drop for AsyncDeviceManager {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+/// Removes a pending reply from the registry once the request waiting for it
+/// completes, or gets cancelled.
+struct PendingReplyGuard<'a> {
+    data: &'a AsyncDeviceManagerData,
+    id: u64,
+    sequence: u16,
+}
+
+impl Drop for PendingReplyGuard<'_> {
+    fn drop(&mut self) {
+        self.data
+            .with_registry(|registry| registry.remove_pending_reply(self.id));
+    }
+}
+
+impl AsyncDeviceManagerData {
+    /// Runs `f` on the registry, then delivers the events it queued.
+    fn with_registry<R>(&self, f: impl FnOnce(&mut DeviceRegistry) -> R) -> R {
+        let mut state = self.state.lock().unwrap();
+        let result = f(&mut state.registry);
+
+        for event in state.registry.take_events() {
+            state
+                .event_listeners
+                .retain(|tx| tx.send(event.clone()).is_ok());
+        }
+
+        result
+    }
+
+    fn sock_send(&self) -> Result<Arc<dyn AsyncPacketSender>> {
+        self.state
+            .lock()
+            .unwrap()
+            .sock_send
+            .clone()
+            .ok_or(Error::Shutdown)
+    }
+
+    /// Allocates a sequence for a request, and registers it as waiting for
+    /// the replies `matches` accepts.
+    fn add_pending_reply(
+        &self,
+        device_id: &str,
+        matches: PacketMatcher,
+    ) -> (PendingReplyGuard<'_>, mpsc::UnboundedReceiver<Packet>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+
+        let (id, sequence) = self.with_registry(|registry| {
+            let sequence = registry.next_sequence();
+            let id = registry.add_pending_reply(
+                device_id,
+                sequence,
+                matches,
+                Box::new(move |reply| {
+                    let _ = tx.send(reply);
+                }),
+            );
+
+            (id, sequence)
+        });
+
+        (
+            PendingReplyGuard {
+                data: self,
+                id,
+                sequence,
+            },
+            rx,
+        )
+    }
+
+    /// Registers for notifications with the devices that are due for it.
+    async fn send_hellos(&self, retry_policy: &RetryPolicy) {
+        let hellos_due = self.with_registry(|registry| registry.hellos_due(retry_policy));
+
+        for (device_id, addr) in hellos_due {
+            let hello = self
+                .network_impl
+                .local_addr_for(addr)
+                .and_then(|local_addr| commands::hello(&local_addr));
+
+            let result = match hello.and_then(|hello| Ok((hello, self.sock_send()?))) {
+                Ok((hello, sock_send)) => {
+                    sock_send
+                        .send_packet(&hello, SocketAddr::new(addr, protocol::CMD_SEND_PORT))
+                        .await
+                }
+                Err(err) => Err(err),
+            };
+
+            if let Err(err) = result {
+                tracing::warn!(
+                    device_id = device_id.as_str(),
+                    %addr,
+                    error = %err,
+                    "error registering for notifications"
+                );
+            }
+        }
+    }
+
+    async fn send_packet(&self, device_id: &str, packet: &Packet) -> Result<()> {
+        let addr = self.state.lock().unwrap().registry.device_addr(device_id)?;
+
+        device::log_packet(Some(device_id), addr, Direction::Command, packet);
+
+        self.sock_send()?
+            .send_packet(packet, SocketAddr::new(addr, protocol::CMD_SEND_PORT))
+            .await?;
+        Ok(())
+    }
+
+    async fn handle_notification(&self, addr: SocketAddr, packet: &Packet) -> Result<()> {
+        let ack = protocol::NotificationAck::from_packet(packet);
+        self.sock_send()?
+            .send_packet(
+                &ack.packet(),
+                SocketAddr::new(addr.ip(), protocol::NOTIF_ACK_PORT),
+            )
+            .await?;
+
+        self.with_registry(|registry| registry.handle_notification(addr, packet))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::PropertyValue;
+    use crate::fake;
+
+    async fn device_manager() -> AsyncDeviceManager {
+        let config = fake::device_manager_config()
+            .unwrap()
+            .with_retry_policy(RetryPolicy {
+                attempts: 2,
+                timeout: Duration::from_millis(100),
+                backoff: 2,
+            })
+            .unwrap();
+
+        AsyncDeviceManager::new(AsyncDeviceManagerConfig::from_blocking(config))
+            .await
+            .unwrap()
+    }
+
+    async fn next_event(events: &mut EventStream) -> DeviceManagerEvent {
+        tokio::time::timeout(Duration::from_secs(5), events.recv())
+            .await
+            .unwrap()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn get_test() {
+        let device_manager = device_manager().await;
+        let mut events = device_manager.listen();
+
+        let device = match next_event(&mut events).await {
+            DeviceManagerEvent::DeviceDiscovered(device) => device,
+            event => panic!("unexpected event: {:?}", event),
+        };
+
+        assert_eq!(
+            device_manager
+                .get::<commands::Volume>(&device.id(), Duration::from_secs(1))
+                .await
+                .unwrap(),
+            35
+        );
+
+        let err = device_manager
+            .get::<commands::Volume>("no-such-device", Duration::from_millis(100))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::UnknownDevice(_)));
+    }
+
+    #[tokio::test]
+    async fn set_test() {
+        let device_manager = device_manager().await;
+        let mut events = device_manager.listen();
+
+        let device = match next_event(&mut events).await {
+            DeviceManagerEvent::DeviceDiscovered(device) => device,
+            event => panic!("unexpected event: {:?}", event),
+        };
+
+        assert_eq!(
+            device_manager.set_volume(&device.id(), 42).await.unwrap(),
+            DeliveryOutcome::Delivered { attempts: 1 }
+        );
+
+        // the volume notification also updates the device
+        loop {
+            if let DeviceManagerEvent::DevicePropertyChanged { new, .. } =
+                next_event(&mut events).await
+            {
+                if new == PropertyValue::Volume(42) {
+                    break;
+                }
+            }
+        }
+
+        assert_eq!(
+            device_manager.device(&device.id()).unwrap().volume(),
+            Some(42)
+        );
+    }
+
+    #[tokio::test]
+    async fn shutdown_test() {
+        let device_manager = device_manager().await;
+        let mut events = device_manager.listen();
+
+        let device = match next_event(&mut events).await {
+            DeviceManagerEvent::DeviceDiscovered(device) => device,
+            event => panic!("unexpected event: {:?}", event),
+        };
+
+        device_manager.shutdown();
+
+        // the stream ends once the queued events are read
+        while tokio::time::timeout(Duration::from_secs(1), events.recv())
+            .await
+            .unwrap()
+            .is_some()
+        {}
+
+        assert!(matches!(
+            device_manager
+                .get::<commands::Volume>(&device.id(), Duration::from_secs(1))
+                .await,
+            Err(Error::Shutdown)
+        ));
+
+        // shutting down again, or dropping, is harmless
+        device_manager.shutdown();
+    }
+}
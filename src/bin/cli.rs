@@ -1,30 +1,564 @@
-use std::thread;
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+use clap::{Parser, Subcommand};
+use serde::Serialize;
 
+use libratone_rs::capture;
+use libratone_rs::cli_util::{init_logging, parse_seconds};
+use libratone_rs::commands;
+use libratone_rs::commands::{PlayControlCommand, PowerState};
 use libratone_rs::device;
+use libratone_rs::device::{DeliveryOutcome, Device, DeviceManager, DeviceManagerEvent};
+use libratone_rs::device_handle::DeviceHandle;
+use libratone_rs::fake;
+use libratone_rs::monitor::Monitor;
 
-fn main() -> Result<()> {
-    let device_manager = device::DeviceManager::new(device::DeviceManagerConfig::default()?)?;
-    let device_manager_events = device_manager.listen();
+#[derive(Parser)]
+#[command(about = "Control Libratone speakers")]
+struct Args {
+    /// How long to wait for devices to show up, in seconds
+    #[arg(long, default_value = "5", value_parser = parse_seconds)]
+    discovery_timeout: Duration,
 
-    let events_thread = thread::spawn(move || {
-        for event in device_manager_events {
-            println!("Device manager event: {:?}", &event);
+    /// How long to wait for each reply from a device, in seconds
+    #[arg(long, default_value = "2", value_parser = parse_seconds)]
+    timeout: Duration,
+
+    /// Talk to a simulated device instead of the network
+    #[arg(long)]
+    fake: bool,
+
+    /// Record all the traffic to a capture file
+    #[arg(long)]
+    record: Option<PathBuf>,
+
+    /// Replay a capture file instead of talking to the network
+    #[arg(long, conflicts_with = "fake")]
+    replay: Option<PathBuf>,
+
+    /// Print JSON documents instead of text, and newline-delimited JSON
+    /// events in watch mode
+    #[arg(long)]
+    json: bool,
+
+    /// Log more of what the library does to stderr, repeat for more detail
+    #[arg(short, long, action = clap::ArgAction::Count, global = true)]
+    verbose: u8,
+
+    #[command(subcommand)]
+    command: Option<CliCommand>,
+}
+
+#[derive(Subcommand)]
+enum CliCommand {
+    /// List the devices found on the network
+    List,
+    /// Show the state of a device
+    Info {
+        device: String,
+    },
+    /// Show or change the volume of a device
+    Volume {
+        device: String,
+        value: Option<u8>,
+    },
+    Play {
+        device: String,
+    },
+    Pause {
+        device: String,
+    },
+    Next {
+        device: String,
+    },
+    Prev {
+        device: String,
+    },
+    Toggle {
+        device: String,
+    },
+    Mute {
+        device: String,
+    },
+    Unmute {
+        device: String,
+    },
+    /// List the favorites of a device
+    Favorites {
+        device: String,
+    },
+    /// Play a favorite, as numbered by the favorites command
+    PlayFavorite {
+        device: String,
+        n: usize,
+    },
+    Rename {
+        device: String,
+        name: String,
+    },
+    Sleep {
+        device: String,
+    },
+    Wake {
+        device: String,
+    },
+    /// Print device manager events as they happen (the default)
+    Watch,
+    /// Decode all Libratone traffic reaching this host, without sending
+    /// anything
+    Monitor,
+    /// List the commands of the protocol this tool knows about, with their
+    /// IDs
+    Commands,
+}
+
+enum CliError {
+    DeviceNotFound(String),
+    NotConfirmed(DeliveryOutcome),
+    Other(anyhow::Error),
+}
+
+impl From<anyhow::Error> for CliError {
+    fn from(err: anyhow::Error) -> Self {
+        CliError::Other(err)
+    }
+}
+
+impl From<libratone_rs::Error> for CliError {
+    fn from(err: libratone_rs::Error) -> Self {
+        CliError::Other(err.into())
+    }
+}
+
+impl CliError {
+    fn exit_code(&self) -> u8 {
+        match self {
+            CliError::Other(_) => 1,
+            CliError::DeviceNotFound(_) => 3,
+            CliError::NotConfirmed(_) => 4,
+        }
+    }
+}
+
+impl std::fmt::Display for CliError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CliError::DeviceNotFound(query) => write!(f, "no device matching {:?}", query),
+            CliError::NotConfirmed(outcome) => {
+                write!(f, "the device did not confirm the command ({:?})", outcome)
+            }
+            CliError::Other(err) => write!(f, "{:#}", err),
+        }
+    }
+}
+
+struct Cli {
+    device_manager: DeviceManager,
+    events: mpsc::Receiver<DeviceManagerEvent>,
+    known_devices: RefCell<Vec<Device>>,
+    seen_device_ids: RefCell<HashSet<String>>,
+    discovery_timeout: Duration,
+    timeout: Duration,
+    json: bool,
+}
+
+impl Cli {
+    /// Prints `value` as JSON in JSON mode, or the result of `format_text`
+    /// otherwise.
+    fn print<T, F>(&self, value: &T, format_text: F) -> Result<(), CliError>
+    where
+        T: Serialize + ?Sized,
+        F: FnOnce(&T) -> String,
+    {
+        if self.json {
+            println!(
+                "{}",
+                serde_json::to_string(value).map_err(anyhow::Error::from)?
+            );
+        } else {
+            let text = format_text(value);
+
+            if !text.is_empty() {
+                println!("{}", text);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn discovered_devices(&self) -> Vec<Device> {
+        let deadline = Instant::now() + self.discovery_timeout;
+        let mut devices = vec![];
+
+        while let Some(device) = self.next_discovered_device(deadline) {
+            devices.push(device);
+        }
+
+        devices
+    }
+
+    fn next_discovered_device(&self, deadline: Instant) -> Option<Device> {
+        loop {
+            let device = match self.known_devices.borrow_mut().pop() {
+                Some(device) => device,
+                None => {
+                    let remaining = deadline.checked_duration_since(Instant::now())?;
+
+                    match self.events.recv_timeout(remaining) {
+                        Ok(DeviceManagerEvent::DeviceDiscovered(device)) => device,
+                        Ok(_) => continue,
+                        Err(_) => return None,
+                    }
+                }
+            };
+
+            // devices discovered between listen() and devices() are
+            // reported twice
+            if self.seen_device_ids.borrow_mut().insert(device.id()) {
+                return Some(device);
+            }
+        }
+    }
+
+    fn device_name(&self, device: &Device) -> Option<String> {
+        self.device_manager
+            .get::<commands::DeviceName>(&device.id(), self.timeout)
+            .ok()
+    }
+
+    /// Waits for a device whose ID, name or IP address matches `query`.
+    fn find_device(&self, query: &str) -> Result<DeviceHandle, CliError> {
+        let deadline = Instant::now() + self.discovery_timeout;
+
+        while let Some(device) = self.next_discovered_device(deadline) {
+            // the name is only fetched if nothing else matches
+            let matches = device.matches(query)
+                || (self.device_name(&device).is_some()
+                    && self.refreshed(device.clone()).matches(query));
+
+            if matches {
+                return Ok(self
+                    .device_manager
+                    .handle(&device.id())?
+                    .with_timeout(self.timeout));
+            }
+        }
+
+        Err(CliError::DeviceNotFound(query.to_owned()))
+    }
+
+    fn confirmed(&self, outcome: DeliveryOutcome) -> Result<(), CliError> {
+        self.print(&outcome, |_| String::new())?;
+
+        match outcome {
+            DeliveryOutcome::Delivered { .. } => Ok(()),
+            DeliveryOutcome::Failed { .. } => Err(CliError::NotConfirmed(outcome)),
+        }
+    }
+
+    fn play_control(&self, query: &str, command: PlayControlCommand) -> Result<(), CliError> {
+        let device = self.find_device(query)?;
+        self.confirmed(device.play_control(command)?)
+    }
+
+    fn power(&self, query: &str, state: PowerState) -> Result<(), CliError> {
+        let device = self.find_device(query)?;
+        self.confirmed(match state {
+            PowerState::Sleep => device.sleep()?,
+            PowerState::WakeUp => device.wake_up()?,
+        })
+    }
+
+    /// Returns the latest state of a device, including the values
+    /// received since it was discovered.
+    fn refreshed(&self, device: Device) -> Device {
+        self.device_manager.device(&device.id()).unwrap_or(device)
+    }
+
+    fn display_name(device: &Device) -> String {
+        device.name().unwrap_or_else(|| device.advertised_name())
+    }
+
+    fn list(&self) -> Result<(), CliError> {
+        let devices: Vec<Device> = self
+            .discovered_devices()
+            .into_iter()
+            .map(|device| {
+                // only fetched to have the up to date name in the output
+                let _ = self.device_name(&device);
+                self.refreshed(device)
+            })
+            .collect();
+
+        self.print(&devices, |devices| {
+            devices
+                .iter()
+                .map(|device| {
+                    format!(
+                        "{}\t{}\t{}\t{}",
+                        device.id(),
+                        device.addr(),
+                        Self::display_name(device),
+                        device.firmware_version(),
+                    )
+                })
+                .collect::<Vec<_>>()
+                .join("\n")
+        })
+    }
+
+    fn info(&self, query: &str) -> Result<(), CliError> {
+        let device = self.find_device(query)?;
+
+        // The replies to these requests update the state of the device, which
+        // is what gets printed. Devices don't necessarily support all of
+        // them, so errors are ignored.
+        let _ = device.name();
+        let _ = device.volume();
+        let _ = device.play_status();
+        let _ = device.get::<commands::PlayInfo>();
+        let _ = device.battery();
+        let _ = device.charging_state();
+
+        self.print(&device.device()?, |device| {
+            let mut lines = vec![
+                format!("ID: {}", device.id()),
+                format!("Address: {}", device.addr()),
+                format!("Name: {}", Self::display_name(device)),
+                format!("Firmware version: {}", device.firmware_version()),
+                format!("Notifications: {:?}", device.subscription()),
+            ];
+
+            if let Some(volume) = device.volume() {
+                lines.push(format!("Volume: {}", volume));
+            }
+
+            if let Some(play_status) = device.play_status() {
+                lines.push(format!("Play status: {:?}", play_status));
+            }
+
+            if let Some(title) = device.play_info().and_then(|x| x.play_title) {
+                lines.push(format!("Now playing: {}", title));
+            }
+
+            if let Some(battery_level) = device.battery_level() {
+                lines.push(format!("Battery level: {}%", battery_level));
+            }
+
+            if let Some(charging_state) = device.charging_state() {
+                lines.push(format!("Charging state: {:?}", charging_state));
+            }
+
+            lines.join("\n")
+        })
+    }
+
+    fn volume(&self, query: &str, value: Option<u8>) -> Result<(), CliError> {
+        let device = self.find_device(query)?;
+
+        match value {
+            Some(value) => {
+                if value > 100 {
+                    return Err(anyhow!("volume must be between 0 and 100").into());
+                }
+
+                self.confirmed(device.set_volume(value)?)
+            }
+            None => {
+                let volume = device.volume()?;
+                self.print(&volume, |volume| volume.to_string())
+            }
+        }
+    }
+
+    fn favorites(&self, query: &str) -> Result<(), CliError> {
+        let channels = self.find_device(query)?.favorites()?;
+
+        self.print(&channels, |channels| {
+            channels
+                .iter()
+                .enumerate()
+                .map(|(i, channel)| format!("{}\t{}", i + 1, channel.channel_name))
+                .collect::<Vec<_>>()
+                .join("\n")
+        })
+    }
+
+    fn play_favorite(&self, query: &str, n: usize) -> Result<(), CliError> {
+        let device = self.find_device(query)?;
+        let index = n
+            .checked_sub(1)
+            .ok_or_else(|| anyhow!("favorites are numbered from 1"))?;
+
+        match device.play_favorite(index) {
+            Err(libratone_rs::Error::NoSuchFavorite { count, .. }) => {
+                Err(anyhow!("no favorite number {} ({} available)", n, count).into())
+            }
+            result => self.confirmed(result?),
+        }
+    }
+
+    fn rename(&self, query: &str, name: &str) -> Result<(), CliError> {
+        self.confirmed(self.find_device(query)?.rename(name)?)
+    }
+
+    fn watch(&self) -> Result<(), CliError> {
+        for event in &self.events {
+            self.print(&event, |event| format!("Device manager event: {:?}", event))?;
 
             match event {
-                device::DeviceManagerEvent::DeviceDiscovered(device) => {
-                    if let Err(err) = device_manager.fetch_info(&device.id()) {
-                        println!("error fetching device info: {}", err);
+                DeviceManagerEvent::DeviceDiscovered(device)
+                | DeviceManagerEvent::DeviceReappeared(device) => {
+                    if let Err(err) = self.device_manager.fetch_info(&device.id()) {
+                        eprintln!("error fetching device info: {}", err);
                     };
                 }
-                device::DeviceManagerEvent::DeviceUpdated(device) => {
-                    println!("Device update: {:?}", device);
-                }
+                DeviceManagerEvent::DeviceUpdated(_)
+                | DeviceManagerEvent::DeviceLost(_)
+                | DeviceManagerEvent::DevicePropertyChanged { .. } => {}
             }
         }
-    });
 
-    events_thread.join().unwrap();
+        Ok(())
+    }
+
+    fn run(&self, command: CliCommand) -> Result<(), CliError> {
+        match command {
+            CliCommand::List => self.list(),
+            CliCommand::Info { device } => self.info(&device),
+            CliCommand::Volume { device, value } => self.volume(&device, value),
+            CliCommand::Play { device } => self.play_control(&device, PlayControlCommand::Play),
+            CliCommand::Pause { device } => self.play_control(&device, PlayControlCommand::Pause),
+            CliCommand::Next { device } => self.play_control(&device, PlayControlCommand::Next),
+            CliCommand::Prev { device } => self.play_control(&device, PlayControlCommand::Previous),
+            CliCommand::Toggle { device } => self.play_control(&device, PlayControlCommand::Toggle),
+            CliCommand::Mute { device } => self.play_control(&device, PlayControlCommand::Mute),
+            CliCommand::Unmute { device } => self.play_control(&device, PlayControlCommand::Unmute),
+            CliCommand::Favorites { device } => self.favorites(&device),
+            CliCommand::PlayFavorite { device, n } => self.play_favorite(&device, n),
+            CliCommand::Rename { device, name } => self.rename(&device, &name),
+            CliCommand::Sleep { device } => self.power(&device, PowerState::Sleep),
+            CliCommand::Wake { device } => self.power(&device, PowerState::WakeUp),
+            CliCommand::Watch => self.watch(),
+            CliCommand::Monitor | CliCommand::Commands => {
+                unreachable!("command does not use a device manager")
+            }
+        }
+    }
+}
+
+fn print_commands(json: bool) -> Result<()> {
+    if json {
+        println!("{}", serde_json::to_string(commands::COMMANDS)?);
+        return Ok(());
+    }
+
+    let id = |id: u16| {
+        if id == 0 {
+            "-".to_owned()
+        } else {
+            id.to_string()
+        }
+    };
+
+    for command in commands::COMMANDS {
+        println!(
+            "{:<16} get {:>5}  reply {:>5}  set {:>5}  notify {:>5}",
+            command.name,
+            id(command.get_id),
+            id(command.get_reply_id),
+            id(command.set_id),
+            id(command.notify_id),
+        );
+    }
+
     Ok(())
 }
+
+fn main() -> ExitCode {
+    let args = Args::parse();
+    init_logging(args.verbose);
+
+    if let Some(CliCommand::Commands) = args.command {
+        return match print_commands(args.json) {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(err) => {
+                eprintln!("{:#}", err);
+                ExitCode::FAILURE
+            }
+        };
+    }
+
+    let config = if let Some(path) = &args.replay {
+        capture::replay(path)
+    } else if args.fake {
+        fake::device_manager_config()
+    } else {
+        device::DeviceManagerConfig::default()
+    };
+
+    let config = match &args.record {
+        Some(path) => config.and_then(|config| capture::record(config, path)),
+        None => config,
+    };
+
+    let command = args.command.unwrap_or(CliCommand::Watch);
+
+    if let CliCommand::Monitor = command {
+        return match config.and_then(Monitor::new) {
+            Ok(monitor) => {
+                for packet in monitor {
+                    if args.json {
+                        match serde_json::to_string(&packet) {
+                            Ok(json) => println!("{}", json),
+                            Err(err) => eprintln!("error serializing packet: {}", err),
+                        }
+                    } else {
+                        println!("{}", packet);
+                    }
+                }
+
+                ExitCode::SUCCESS
+            }
+            Err(err) => {
+                eprintln!("error starting monitor: {:#}", err);
+                ExitCode::FAILURE
+            }
+        };
+    }
+
+    let device_manager = match config.and_then(DeviceManager::new) {
+        Ok(device_manager) => device_manager,
+        Err(err) => {
+            eprintln!("error creating device manager: {:#}", err);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let events = device_manager.listen();
+    let known_devices = device_manager.devices();
+
+    let cli = Cli {
+        events,
+        known_devices: RefCell::new(known_devices),
+        seen_device_ids: RefCell::new(HashSet::new()),
+        device_manager,
+        discovery_timeout: args.discovery_timeout,
+        timeout: args.timeout,
+        json: args.json,
+    };
+
+    match cli.run(command) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("{}", err);
+            ExitCode::from(err.exit_code())
+        }
+    }
+}
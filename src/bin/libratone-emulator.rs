@@ -0,0 +1,224 @@
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+use clap::Parser;
+
+use libratone_rs::cli_util::{init_logging, parse_seconds};
+use libratone_rs::commands;
+use libratone_rs::device::reusable_socket;
+use libratone_rs::fake::{FakeSpeaker, FakeSpeakerState, PortAndPacket};
+use libratone_rs::protocol;
+use libratone_rs::protocol::{PacketReceiver, PacketSender};
+
+#[derive(Parser)]
+#[command(about = "Emulate a Libratone speaker on the local network")]
+struct Args {
+    /// Address to serve the speaker on. Run several emulators on different
+    /// loopback addresses to emulate several speakers.
+    #[arg(long, default_value = "127.0.0.2")]
+    address: Ipv4Addr,
+
+    /// Device ID announced over SSDP
+    #[arg(long, default_value = "emulator00001")]
+    id: String,
+
+    /// Name of the speaker
+    #[arg(long, default_value = "Emulated speaker")]
+    name: String,
+
+    /// Time it takes for the battery to lose one percent, in seconds
+    #[arg(long, default_value = "60", value_parser = parse_seconds)]
+    battery_interval: Duration,
+
+    /// Log more of what the library does to stderr, repeat for more detail
+    #[arg(short, long, action = clap::ArgAction::Count)]
+    verbose: u8,
+}
+
+const TICK_INTERVAL: Duration = Duration::from_millis(100);
+const RECEIVE_TIMEOUT: Duration = Duration::from_secs(1);
+
+struct Emulator {
+    speaker: FakeSpeaker,
+    /// Socket bound to the command port, replies and notifications are sent
+    /// from it too
+    sock: Arc<UdpSocket>,
+}
+
+impl Emulator {
+    fn serve_commands(&self) -> Result<()> {
+        loop {
+            let (from, packet) = match self.sock.receive_packet(RECEIVE_TIMEOUT) {
+                Ok(Some(x)) => x,
+                Ok(None) => continue,
+                Err(err) => {
+                    tracing::warn!(error = %format!("{:#}", err), "error receiving command");
+                    continue;
+                }
+            };
+
+            println!("{} -> {}", from.ip(), commands::format_command(&packet));
+
+            self.send(from.ip(), self.speaker.handle_packet(&packet))?;
+        }
+    }
+
+    fn serve_acks(&self, sock: UdpSocket) -> Result<()> {
+        loop {
+            let (from, packet) = match sock.receive_packet(RECEIVE_TIMEOUT) {
+                Ok(Some(x)) => x,
+                Ok(None) => continue,
+                Err(err) => {
+                    tracing::warn!(error = %format!("{:#}", err), "error receiving ack");
+                    continue;
+                }
+            };
+
+            if self.speaker.handle_ack(from.ip(), &packet) {
+                println!(
+                    "{} acked {} (sequence {})",
+                    from.ip(),
+                    commands::command_name(packet.command).unwrap_or("??"),
+                    packet.sequence
+                );
+            } else {
+                tracing::warn!(addr = %from.ip(), "unexpected ack: {:?}", packet);
+            }
+        }
+    }
+
+    fn serve_discovery(&self, sock: UdpSocket) -> Result<()> {
+        let mut recv_buffer = vec![0; 4096];
+
+        loop {
+            let (count, from) = sock
+                .recv_from(&mut recv_buffer)
+                .context("error receiving discovery request")?;
+
+            if !recv_buffer[..count].starts_with(b"M-SEARCH") {
+                continue;
+            }
+
+            println!("{} -> M-SEARCH", from.ip());
+
+            sock.send_to(&self.speaker.discovery_reply().encode(), from)
+                .context("error answering discovery request")?;
+        }
+    }
+
+    /// Lets the battery drain, and sends notifications again until they are
+    /// acknowledged
+    fn tick(&self) -> Result<()> {
+        loop {
+            std::thread::sleep(TICK_INTERVAL);
+
+            for (_, packet) in self.speaker.tick() {
+                self.notify(packet)?;
+            }
+
+            for (client, packet) in self.speaker.notifications_to_resend() {
+                tracing::warn!(
+                    %client,
+                    command = commands::command_name(packet.command).unwrap_or("unknown"),
+                    sequence = packet.sequence,
+                    "notification not acknowledged, sending it again"
+                );
+
+                self.sock.send_packet(&packet, client)?;
+            }
+        }
+    }
+
+    fn send(&self, requester: IpAddr, packets: Vec<PortAndPacket>) -> Result<()> {
+        for (port, packet) in packets {
+            if port == protocol::NOTIF_RECV_PORT {
+                self.notify(packet)?;
+            } else {
+                println!("{} <- {}", requester, commands::format_reply(&packet));
+                self.sock
+                    .send_packet(&packet, SocketAddr::new(requester, port))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Sends a notification to all the clients that registered for them
+    fn notify(&self, packet: protocol::Packet) -> Result<()> {
+        for client in self.speaker.state().subscribers {
+            println!("{} <- {}", client, commands::format_notification(&packet));
+            self.sock.send_packet(&packet, client)?;
+            self.speaker.notification_sent(client, &packet);
+        }
+
+        Ok(())
+    }
+}
+
+fn discovery_socket(addr: Ipv4Addr) -> Result<UdpSocket> {
+    // Binding to the multicast address keeps us from stealing the unicast
+    // discovery replies meant for clients running on the same machine.
+    let sock = reusable_socket(protocol::SSDP_MULTICAST_ADDR, protocol::SSDP_MULTICAST_PORT)?;
+
+    let mut joined = false;
+
+    for interface in [addr, Ipv4Addr::UNSPECIFIED] {
+        match sock.join_multicast_v4(&protocol::SSDP_MULTICAST_ADDR, &interface) {
+            Ok(()) => joined = true,
+            Err(err) => tracing::warn!(
+                %interface,
+                error = %err,
+                "could not join SSDP group"
+            ),
+        }
+    }
+
+    if !joined {
+        return Err(anyhow!("could not join the SSDP multicast group"));
+    }
+
+    Ok(sock)
+}
+
+fn spawn(name: &'static str, func: impl FnOnce() -> Result<()> + Send + 'static) {
+    std::thread::spawn(move || {
+        if let Err(err) = func() {
+            tracing::error!(thread = name, error = %format!("{:#}", err), "thread failed");
+            std::process::exit(1);
+        }
+    });
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+    init_logging(args.verbose);
+
+    let mut state = FakeSpeakerState::new(&args.id, &args.name, IpAddr::V4(args.address));
+    state.battery_interval = args.battery_interval;
+
+    let emulator = Arc::new(Emulator {
+        speaker: FakeSpeaker::new(state),
+        sock: Arc::new(reusable_socket(args.address, protocol::CMD_SEND_PORT)?),
+    });
+
+    let ack_sock = reusable_socket(args.address, protocol::NOTIF_ACK_PORT)?;
+    let discovery_sock = discovery_socket(args.address)?;
+
+    println!(
+        "emulating speaker {} ({:?}) on {}",
+        args.id, args.name, args.address
+    );
+
+    let e = Arc::clone(&emulator);
+    spawn("ack", move || e.serve_acks(ack_sock));
+
+    let e = Arc::clone(&emulator);
+    spawn("discovery", move || e.serve_discovery(discovery_sock));
+
+    let e = Arc::clone(&emulator);
+    spawn("tick", move || e.tick());
+
+    emulator.serve_commands()
+}
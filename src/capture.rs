@@ -0,0 +1,556 @@
+//! Recording of the traffic seen by a `DeviceManager`, and replay of such
+//! recordings.
+//!
+//! Captures are stored as JSON lines, one [`CaptureRecord`] per line. Packets
+//! are stored as the hex encoding of their wire format.
+
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::Path;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use crate::commands;
+use crate::device::{
+    DeviceDiscoveryImpl, DeviceManagerConfig, NetworkImpl, ThreadsafeDeviceDiscoveryImpl,
+    ThreadsafeNetworkImpl,
+};
+use crate::discovery_reply::DiscoveryReply;
+use crate::error::IoContext;
+use crate::protocol;
+use crate::protocol::{Packet, PacketReceiver, PacketSender};
+use crate::{Error, Result};
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum CaptureEvent {
+    Sent {
+        to: SocketAddr,
+        packet: String,
+    },
+    Received {
+        port: u16,
+        from: SocketAddr,
+        packet: String,
+    },
+    Discovery {
+        reply: DiscoveryReply,
+    },
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct CaptureRecord {
+    /// Milliseconds since the beginning of the capture
+    pub time_ms: u64,
+    #[serde(flatten)]
+    pub event: CaptureEvent,
+}
+
+fn encode_packet(packet: &Packet) -> String {
+    packet.data().iter().map(|x| format!("{:02x}", x)).collect()
+}
+
+fn decode_packet(hex: &str) -> Result<Packet> {
+    let invalid_hex = || Error::InvalidCapture(format!("invalid hex data {:?}", hex));
+
+    let data = hex
+        .as_bytes()
+        .chunks(2)
+        .map(|digits| match digits {
+            [_, _] => std::str::from_utf8(digits)
+                .ok()
+                .and_then(|digits| u8::from_str_radix(digits, 16).ok())
+                .ok_or_else(invalid_hex),
+            _ => Err(invalid_hex()),
+        })
+        .collect::<Result<Vec<u8>>>()?;
+
+    Packet::parse(&data)
+}
+
+struct CaptureWriter {
+    start: Instant,
+    writer: Mutex<Box<dyn Write + Send>>,
+}
+
+impl CaptureWriter {
+    fn write(&self, event: CaptureEvent) {
+        let record = CaptureRecord {
+            time_ms: self.start.elapsed().as_millis() as u64,
+            event,
+        };
+
+        let mut writer = self.writer.lock().unwrap();
+        let result = serde_json::to_writer(&mut *writer, &record)
+            .map_err(std::io::Error::from)
+            .and_then(|_| writeln!(writer))
+            .and_then(|_| writer.flush());
+
+        if let Err(err) = result {
+            tracing::error!(error = %err, "error writing capture record");
+        }
+    }
+}
+
+struct RecordingNetwork {
+    inner: Arc<ThreadsafeNetworkImpl>,
+    writer: Arc<CaptureWriter>,
+}
+
+impl NetworkImpl for RecordingNetwork {
+    fn packet_sender(&self) -> Result<Box<dyn PacketSender + Send>> {
+        Ok(Box::new(RecordingPacketSender {
+            inner: self.inner.packet_sender()?,
+            writer: Arc::clone(&self.writer),
+        }))
+    }
+
+    fn packet_receiver(&self, port: u16) -> Result<Box<dyn PacketReceiver + Send>> {
+        Ok(Box::new(RecordingPacketReceiver {
+            inner: self.inner.packet_receiver(port)?,
+            port,
+            writer: Arc::clone(&self.writer),
+        }))
+    }
+
+    fn local_addr_for(&self, device_addr: IpAddr) -> Result<IpAddr> {
+        self.inner.local_addr_for(device_addr)
+    }
+}
+
+struct RecordingPacketSender {
+    inner: Box<dyn PacketSender + Send>,
+    writer: Arc<CaptureWriter>,
+}
+
+impl PacketSender for RecordingPacketSender {
+    fn send_packet(&self, packet: &Packet, to: SocketAddr) -> Result<usize> {
+        self.writer.write(CaptureEvent::Sent {
+            to,
+            packet: encode_packet(packet),
+        });
+        self.inner.send_packet(packet, to)
+    }
+}
+
+struct RecordingPacketReceiver {
+    inner: Box<dyn PacketReceiver + Send>,
+    port: u16,
+    writer: Arc<CaptureWriter>,
+}
+
+impl PacketReceiver for RecordingPacketReceiver {
+    fn receive_packet(&self, timeout: Duration) -> Result<Option<(SocketAddr, Packet)>> {
+        let (from, packet) = match self.inner.receive_packet(timeout)? {
+            Some(x) => x,
+            None => return Ok(None),
+        };
+        self.writer.write(CaptureEvent::Received {
+            port: self.port,
+            from,
+            packet: encode_packet(&packet),
+        });
+        Ok(Some((from, packet)))
+    }
+}
+
+struct RecordingDiscovery {
+    inner: Arc<ThreadsafeDeviceDiscoveryImpl>,
+    writer: Arc<CaptureWriter>,
+}
+
+impl DeviceDiscoveryImpl for RecordingDiscovery {
+    fn discover(&self) -> Result<()> {
+        self.inner.discover()
+    }
+
+    fn poll(&self, timeout: Duration) -> Result<Option<DiscoveryReply>> {
+        let reply = match self.inner.poll(timeout)? {
+            Some(reply) => reply,
+            None => return Ok(None),
+        };
+        self.writer.write(CaptureEvent::Discovery {
+            reply: reply.clone(),
+        });
+        Ok(Some(reply))
+    }
+}
+
+/// Wraps the network and discovery implementations of `config` so that all
+/// the packets and discovery replies going through them get written to
+/// `writer`.
+pub fn record_to(
+    mut config: DeviceManagerConfig,
+    writer: Box<dyn Write + Send>,
+) -> DeviceManagerConfig {
+    let writer = Arc::new(CaptureWriter {
+        start: Instant::now(),
+        writer: Mutex::new(writer),
+    });
+
+    config.network_impl = Arc::new(Box::new(RecordingNetwork {
+        inner: config.network_impl,
+        writer: Arc::clone(&writer),
+    }));
+    config.device_discovery_impl = Arc::new(Box::new(RecordingDiscovery {
+        inner: config.device_discovery_impl,
+        writer,
+    }));
+
+    config
+}
+
+/// Like [`record_to`], writing the capture to a file.
+pub fn record(config: DeviceManagerConfig, path: &Path) -> Result<DeviceManagerConfig> {
+    let file = File::create(path)
+        .with_context(|| format!("error creating capture file {}", path.display()))?;
+    Ok(record_to(config, Box::new(BufWriter::new(file))))
+}
+
+/// Shared state of a replayed capture.
+///
+/// Replay is driven by the requests the device manager sends rather than by
+/// the recorded timestamps. Each request is matched with the next recorded
+/// request for the same command, and a reply is only handed out once the
+/// request it answers was matched, with the sequence of the live request.
+/// Other records wait for the request recorded before them, hellos aside:
+/// those are sent on a timer, so how many of them go out depends on timing.
+struct Replay {
+    records: Vec<ReplayRecord>,
+    state: Mutex<ReplayState>,
+    cond: Condvar,
+}
+
+struct ReplayRecord {
+    event: CaptureEvent,
+    /// Command type and command of the recorded request, if the record is
+    /// one
+    request: Option<(u8, u16)>,
+    /// Index of the recorded request that has to be matched before the
+    /// record is handed out
+    waits_for: Option<usize>,
+    /// Whether the record is the reply to the request it waits for
+    is_reply: bool,
+}
+
+struct ReplayState {
+    /// Sequence of the live request each recorded request was matched with
+    matched: Vec<Option<u16>>,
+    taken: Vec<bool>,
+}
+
+impl Replay {
+    fn new(events: Vec<CaptureEvent>) -> Replay {
+        let mut records: Vec<ReplayRecord> = Vec::with_capacity(events.len());
+        let mut last_request = None;
+        // index and sequence of the recorded requests
+        let mut requests: Vec<(usize, u16)> = vec![];
+
+        for event in events {
+            let packet = match &event {
+                CaptureEvent::Sent { packet, .. } | CaptureEvent::Received { packet, .. } => {
+                    decode_packet(packet).ok()
+                }
+                CaptureEvent::Discovery { .. } => None,
+            };
+
+            let record = match (&event, packet) {
+                (CaptureEvent::Sent { .. }, packet) => {
+                    if let Some(packet) = &packet {
+                        requests.push((records.len(), packet.sequence));
+                    }
+
+                    ReplayRecord {
+                        request: packet.map(|p| (p.command_type, p.command)),
+                        waits_for: None,
+                        is_reply: false,
+                        event,
+                    }
+                }
+                (CaptureEvent::Received { port, .. }, Some(packet))
+                    if *port == protocol::CMD_RESP_PORT =>
+                {
+                    let request = requests
+                        .iter()
+                        .rev()
+                        .find(|(_, sequence)| *sequence == packet.sequence)
+                        .map(|(i, _)| *i);
+
+                    ReplayRecord {
+                        request: None,
+                        waits_for: request.or(last_request),
+                        is_reply: request.is_some(),
+                        event,
+                    }
+                }
+                _ => ReplayRecord {
+                    request: None,
+                    waits_for: last_request,
+                    is_reply: false,
+                    event,
+                },
+            };
+
+            if matches!(record.request, Some((_, command)) if command != commands::HELLO_COMMAND_ID)
+            {
+                last_request = Some(records.len());
+            }
+
+            records.push(record);
+        }
+
+        let count = records.len();
+
+        Replay {
+            records,
+            state: Mutex::new(ReplayState {
+                matched: vec![None; count],
+                taken: vec![false; count],
+            }),
+            cond: Condvar::new(),
+        }
+    }
+
+    fn packet_sent(&self, packet: &Packet) {
+        let mut state = self.state.lock().unwrap();
+
+        let request = self.records.iter().enumerate().position(|(i, record)| {
+            state.matched[i].is_none()
+                && record.request == Some((packet.command_type, packet.command))
+        });
+
+        if let Some(i) = request {
+            state.matched[i] = Some(packet.sequence);
+            self.cond.notify_all();
+        }
+    }
+
+    /// Blocks until one of the records that can be replayed is accepted by
+    /// `take`, and returns what `take` extracted from it. `take` is also
+    /// given the sequence of the live request a reply answers. Gives up
+    /// after `timeout`.
+    fn next<T, F>(&self, timeout: Duration, take: F) -> Option<T>
+    where
+        F: Fn(&CaptureEvent, Option<u16>) -> Option<T>,
+    {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.state.lock().unwrap();
+
+        loop {
+            for (i, record) in self.records.iter().enumerate() {
+                if state.taken[i] || record.request.is_some() {
+                    continue;
+                }
+
+                let sequence = match record.waits_for {
+                    Some(request) => match state.matched[request] {
+                        Some(sequence) => Some(sequence),
+                        None => continue,
+                    },
+                    None => None,
+                };
+
+                if let Some(x) = take(&record.event, sequence.filter(|_| record.is_reply)) {
+                    state.taken[i] = true;
+                    return Some(x);
+                }
+            }
+
+            let now = Instant::now();
+
+            if now >= deadline {
+                return None;
+            }
+
+            state = self.cond.wait_timeout(state, deadline - now).unwrap().0;
+        }
+    }
+}
+
+struct ReplayNetwork {
+    replay: Arc<Replay>,
+}
+
+impl NetworkImpl for ReplayNetwork {
+    fn packet_sender(&self) -> Result<Box<dyn PacketSender + Send>> {
+        Ok(Box::new(ReplayPacketSender {
+            replay: Arc::clone(&self.replay),
+        }))
+    }
+
+    fn packet_receiver(&self, port: u16) -> Result<Box<dyn PacketReceiver + Send>> {
+        Ok(Box::new(ReplayPacketReceiver {
+            replay: Arc::clone(&self.replay),
+            port,
+        }))
+    }
+
+    fn local_addr_for(&self, _device_addr: IpAddr) -> Result<IpAddr> {
+        // nothing goes out on the network anyway
+        Ok(IpAddr::V4(Ipv4Addr::LOCALHOST))
+    }
+}
+
+struct ReplayPacketSender {
+    replay: Arc<Replay>,
+}
+
+impl PacketSender for ReplayPacketSender {
+    fn send_packet(&self, packet: &Packet, _to: SocketAddr) -> Result<usize> {
+        self.replay.packet_sent(packet);
+        Ok(packet.data().len())
+    }
+}
+
+struct ReplayPacketReceiver {
+    replay: Arc<Replay>,
+    port: u16,
+}
+
+impl PacketReceiver for ReplayPacketReceiver {
+    fn receive_packet(&self, timeout: Duration) -> Result<Option<(SocketAddr, Packet)>> {
+        let received = self.replay.next(timeout, |record, sequence| match record {
+            CaptureEvent::Received { port, from, packet } if *port == self.port => {
+                Some((*from, packet.clone(), sequence))
+            }
+            _ => None,
+        });
+
+        match received {
+            Some((from, packet, sequence)) => {
+                let mut packet = decode_packet(&packet)?;
+                packet.sequence = sequence.unwrap_or(packet.sequence);
+                Ok(Some((from, packet)))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+struct ReplayDiscovery {
+    replay: Arc<Replay>,
+}
+
+impl DeviceDiscoveryImpl for ReplayDiscovery {
+    fn discover(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn poll(&self, timeout: Duration) -> Result<Option<DiscoveryReply>> {
+        Ok(self.replay.next(timeout, |record, _| match record {
+            CaptureEvent::Discovery { reply } => Some(reply.clone()),
+            _ => None,
+        }))
+    }
+}
+
+/// Creates a configuration feeding the given records to a `DeviceManager`.
+pub fn replay_records(records: Vec<CaptureRecord>) -> DeviceManagerConfig {
+    let replay = Arc::new(Replay::new(
+        records.into_iter().map(|record| record.event).collect(),
+    ));
+
+    DeviceManagerConfig::new(
+        Box::new(ReplayNetwork {
+            replay: Arc::clone(&replay),
+        }),
+        Box::new(ReplayDiscovery { replay }),
+    )
+}
+
+pub fn read_records(reader: impl BufRead) -> Result<Vec<CaptureRecord>> {
+    reader
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !matches!(line, Ok(line) if line.trim().is_empty()))
+        .map(|(i, line)| {
+            serde_json::from_str(&line.context("error reading capture")?).map_err(|err| {
+                Error::InvalidCapture(format!("invalid record on line {}: {}", i + 1, err))
+            })
+        })
+        .collect()
+}
+
+/// Like [`replay_records`], reading the records from a capture file.
+pub fn replay(path: &Path) -> Result<DeviceManagerConfig> {
+    let file = File::open(path)
+        .with_context(|| format!("error opening capture file {}", path.display()))?;
+    Ok(replay_records(read_records(BufReader::new(file))?))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::commands;
+    use crate::commands::Command;
+    use crate::device::{DeviceManager, DeviceManagerEvent};
+    use crate::fake;
+
+    #[derive(Clone)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn run_session(config: DeviceManagerConfig) -> u8 {
+        let device_manager = DeviceManager::new(config.with_discovery_interval(None)).unwrap();
+        let events = device_manager.listen();
+
+        let device = match events.recv_timeout(Duration::from_secs(5)).unwrap() {
+            DeviceManagerEvent::DeviceDiscovered(device) => device,
+            event => panic!("unexpected event: {:?}", event),
+        };
+
+        device_manager
+            .get::<commands::Volume>(&device.id(), Duration::from_secs(1))
+            .unwrap()
+    }
+
+    #[test]
+    fn packet_encoding_test() {
+        let packet = commands::Volume::set(42).unwrap();
+        assert_eq!(encode_packet(&packet), "aaaa02004000123400023432");
+        assert_eq!(decode_packet(&encode_packet(&packet)).unwrap(), packet);
+        assert!(decode_packet("aaa").is_err());
+    }
+
+    #[test]
+    fn record_replay_test() {
+        let buffer = SharedBuffer(Arc::new(Mutex::new(vec![])));
+        let config = record_to(
+            fake::device_manager_config().unwrap(),
+            Box::new(buffer.clone()),
+        );
+
+        assert_eq!(run_session(config), 35);
+
+        let capture = buffer.0.lock().unwrap().clone();
+        let records = read_records(capture.as_slice()).unwrap();
+
+        assert!(matches!(records[0].event, CaptureEvent::Discovery { .. }));
+        assert!(records
+            .iter()
+            .any(|record| matches!(record.event, CaptureEvent::Sent { .. })));
+        assert!(records
+            .iter()
+            .any(|record| matches!(record.event, CaptureEvent::Received { port: 7778, .. })));
+
+        assert_eq!(run_session(replay_records(records.clone())), 35);
+
+        // hellos sent on a timer don't get replies out of order
+        let config = replay_records(records).with_subscription_interval(Duration::from_millis(1));
+        assert_eq!(run_session(config), 35);
+    }
+}
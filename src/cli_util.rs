@@ -0,0 +1,49 @@
+//! Helpers shared by the command line tools in `src/bin` and the UI, behind
+//! the `cli` feature.
+
+use std::time::Duration;
+
+use tracing_subscriber::EnvFilter;
+
+/// Sends the library's log events to stderr. `RUST_LOG` takes precedence
+/// over `verbose`, the number of times `-v` was given on the command line.
+/// Does nothing if a global subscriber is already installed.
+pub fn init_logging(verbose: u8) {
+    let level = match verbose {
+        0 => "warn",
+        1 => "info",
+        2 => "debug",
+        _ => "trace",
+    };
+
+    let _ = tracing_subscriber::fmt()
+        .with_env_filter(
+            EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(level)),
+        )
+        .with_writer(std::io::stderr)
+        .try_init();
+}
+
+/// Parses a number of seconds given on the command line, rejecting negative,
+/// infinite and NaN values. Meant as a clap `value_parser`.
+pub fn parse_seconds(s: &str) -> std::result::Result<Duration, String> {
+    let seconds: f64 = s.parse().map_err(|err| format!("{}", err))?;
+
+    Duration::try_from_secs_f64(seconds)
+        .map_err(|_| format!("{} is not a valid number of seconds", s))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_seconds_test() {
+        assert_eq!(parse_seconds("1.5"), Ok(Duration::from_millis(1500)));
+        assert_eq!(parse_seconds("0"), Ok(Duration::ZERO));
+
+        for invalid in ["-1", "NaN", "inf", "1e30", "soon"] {
+            assert!(parse_seconds(invalid).is_err(), "{}", invalid);
+        }
+    }
+}
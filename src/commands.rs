@@ -1,9 +1,10 @@
 use std::net::IpAddr;
 
-use anyhow::{anyhow, Context, Result};
 use serde::{Deserialize, Serialize};
 
+use crate::device::{Device, PropertyChange, PropertyValue};
 use crate::protocol;
+use crate::{Error, Result};
 
 pub(crate) const COMMAND_TYPE_FETCH: u8 = 1;
 pub(crate) const COMMAND_TYPE_SET: u8 = 2;
@@ -13,19 +14,39 @@ pub enum CommandType {
     Set,
 }
 
-pub trait Command<RequestDataType, ResponseDataType: std::fmt::Debug> {
+pub trait Command {
+    type RequestData;
+    type ResponseData: std::fmt::Debug;
+
     const GET_COMMAND_ID: u16;
     const GET_REPLY_COMMAND_ID: u16 = Self::GET_COMMAND_ID;
     const SET_COMMAND_ID: u16;
     const NOTIFY_ID: u16;
     const NAME: &'static str;
 
-    fn marshal_data(_: RequestDataType) -> Vec<u8> {
-        vec![]
+    /// Encodes the data of a set command, failing if the device would not
+    /// accept `Self::RequestData` as is.
+    fn marshal_data(_: Self::RequestData) -> Result<Vec<u8>> {
+        Ok(vec![])
+    }
+    fn unmarshal_data(_: &[u8]) -> Result<Self::ResponseData>;
+
+    /// Tells whether a notification or reply carrying `received` confirms
+    /// that a set command carrying `sent` was applied. Devices usually echo
+    /// the value that was set, commands whose notifications are encoded
+    /// differently than their requests compare them their own way.
+    fn confirms_set(sent: &[u8], received: &[u8]) -> bool {
+        sent == received
+    }
+
+    /// Stores a value received in a reply or notification in the device
+    /// state. Returns the change, if the device did not already hold that
+    /// value. Commands that are not part of the device state leave it alone.
+    fn update_device(_device: &mut Device, _value: Self::ResponseData) -> Option<PropertyChange> {
+        None
     }
-    fn unmarshal_data(_: &[u8]) -> Result<ResponseDataType>;
 
-    fn packet(command_type: CommandType, data: Option<RequestDataType>) -> protocol::Packet {
+    fn packet(command_type: CommandType, command_data: Option<Vec<u8>>) -> protocol::Packet {
         protocol::Packet {
             command_type: match command_type {
                 CommandType::Fetch => 1,
@@ -35,7 +56,9 @@ pub trait Command<RequestDataType, ResponseDataType: std::fmt::Debug> {
                 CommandType::Fetch => Self::GET_COMMAND_ID,
                 CommandType::Set => Self::SET_COMMAND_ID,
             },
-            command_data: data.map(Self::marshal_data),
+            status: protocol::PacketStatus::Ok,
+            sequence: protocol::DEFAULT_SEQUENCE,
+            command_data,
         }
     }
 
@@ -43,65 +66,244 @@ pub trait Command<RequestDataType, ResponseDataType: std::fmt::Debug> {
         Self::packet(CommandType::Fetch, None)
     }
 
-    fn set(data: RequestDataType) -> protocol::Packet {
-        Self::packet(CommandType::Set, Some(data))
+    fn set(data: Self::RequestData) -> Result<protocol::Packet> {
+        Ok(Self::packet(
+            CommandType::Set,
+            Some(Self::marshal_data(data)?),
+        ))
     }
+}
 
-    fn format_notification(p: &protocol::Packet) -> String {
-        assert_eq!(Self::NOTIFY_ID, p.command);
-        Self::format(p)
+/// Returns the error for a value that can't be sent as the data of `C`
+fn invalid_value<C: Command + ?Sized, R: std::fmt::Display>(reason: R) -> Error {
+    Error::InvalidValue {
+        command: C::NAME,
+        reason: reason.to_string(),
     }
+}
 
-    fn format_reply(p: &protocol::Packet) -> String {
-        assert_eq!(Self::GET_REPLY_COMMAND_ID, p.command);
-        Self::format(p)
+/// Returns the error for data that can't be decoded as that of `C`
+fn invalid_data<C: Command + ?Sized, R: std::fmt::Display>(reason: R) -> Error {
+    Error::Decode {
+        command: C::NAME,
+        reason: reason.to_string(),
     }
+}
 
-    fn format(p: &protocol::Packet) -> String {
-        let command_type = match p.command_type {
-            COMMAND_TYPE_FETCH => "fetch",
-            COMMAND_TYPE_SET => "set",
-            _ => "??",
-        };
+/// A reply or notification decoded by the command it belongs to, see
+/// [`decode_reply`] and [`decode_notification`].
+#[derive(Debug)]
+pub enum DecodedPacket {
+    BatteryLevel(u8),
+    Capabilities(CapabilitiesData),
+    ChargingState(ChargingStateData),
+    DeviceName(String),
+    FirmwareUpdate(String),
+    /// The address and port the device sends notifications to
+    Hello(String),
+    PlayControl(PlayControlCommand),
+    PlayInfo(Box<PlayInfoData>),
+    Power(PowerState),
+    PowerMode(String),
+    PreChannel(Vec<ChannelObject>),
+    Volume(u8),
+}
 
-        let unmarshal_result = match &p.command_data {
-            Some(data) => Self::unmarshal_data(data)
-                .map(|x| format!("{:?}", x))
-                .unwrap_or_else(|err| format!("{:?}", err)),
-            None => "".to_string(),
-        };
+/// Describes a command of the protocol. IDs are 0 when the command does not
+/// support the corresponding operation.
+#[derive(Clone, Copy, Serialize)]
+pub struct CommandInfo {
+    pub name: &'static str,
+    pub get_id: u16,
+    pub get_reply_id: u16,
+    pub set_id: u16,
+    pub notify_id: u16,
+    #[serde(skip)]
+    decode: fn(&[u8]) -> Result<DecodedPacket>,
+    #[serde(skip)]
+    describe: fn(&[u8]) -> String,
+    #[serde(skip)]
+    update: fn(&mut Device, &[u8]) -> Result<Option<PropertyChange>>,
+}
+
+impl CommandInfo {
+    const fn of<C: Command>(decode: fn(&[u8]) -> Result<DecodedPacket>) -> CommandInfo {
+        CommandInfo {
+            name: C::NAME,
+            get_id: C::GET_COMMAND_ID,
+            get_reply_id: C::GET_REPLY_COMMAND_ID,
+            set_id: C::SET_COMMAND_ID,
+            notify_id: C::NOTIFY_ID,
+            decode,
+            describe: describe_data::<C>,
+            update: update_device_with::<C>,
+        }
+    }
+
+    pub fn uses_id(&self, command: u16) -> bool {
+        command != 0
+            && [self.get_id, self.get_reply_id, self.set_id, self.notify_id].contains(&command)
+    }
+
+    /// Whether the device replies with `command` to a fetch or set of this
+    /// command
+    fn replies_with(&self, command: u16) -> bool {
+        command != 0 && (command == self.get_reply_id || command == self.set_id)
+    }
+
+    /// Whether the device notifies changes of this command with `command`.
+    /// Commands without notifications of their own are pushed by the device
+    /// using their reply ID.
+    fn notifies_with(&self, command: u16) -> bool {
+        command != 0
+            && (command == self.notify_id || (self.notify_id == 0 && command == self.get_reply_id))
+    }
+
+    /// Whether `command` carries the value of this command, be it in a reply
+    /// to a fetch or in a notification
+    fn carries_state(&self, command: u16) -> bool {
+        command != 0 && (command == self.get_reply_id || command == self.notify_id)
+    }
+
+    pub fn decode(&self, data: &[u8]) -> Result<DecodedPacket> {
+        (self.decode)(data)
+    }
+
+    fn format(&self, p: &protocol::Packet) -> String {
+        let data = p
+            .command_data
+            .as_deref()
+            .map(self.describe)
+            .unwrap_or_default();
+
+        format!("{} {} {:?}", command_type_name(p), self.name, data)
+    }
+}
+
+fn update_device_with<C: Command>(
+    device: &mut Device,
+    data: &[u8],
+) -> Result<Option<PropertyChange>> {
+    Ok(C::update_device(device, C::unmarshal_data(data)?))
+}
+
+fn describe_data<C: Command>(data: &[u8]) -> String {
+    C::unmarshal_data(data)
+        .map(|x| format!("{:?}", x))
+        .unwrap_or_else(|err| format!("{:?}", err))
+}
+
+macro_rules! command_info {
+    ($command:ident) => {
+        CommandInfo::of::<$command>(|data| {
+            $command::unmarshal_data(data).map(|x| DecodedPacket::$command(x.into()))
+        })
+    };
+}
+
+/// All the commands we know about
+pub static COMMANDS: &[CommandInfo] = &[
+    command_info!(BatteryLevel),
+    command_info!(Capabilities),
+    command_info!(ChargingState),
+    command_info!(DeviceName),
+    command_info!(FirmwareUpdate),
+    command_info!(Hello),
+    command_info!(PlayControl),
+    command_info!(PlayInfo),
+    command_info!(Power),
+    command_info!(PowerMode),
+    command_info!(PreChannel),
+    command_info!(Volume),
+];
+
+/// Returns the command using `command` as one of its IDs
+pub fn command_info(command: u16) -> Option<&'static CommandInfo> {
+    COMMANDS.iter().find(|x| x.uses_id(command))
+}
+
+fn reply_info(command: u16) -> Option<&'static CommandInfo> {
+    COMMANDS.iter().find(|x| x.replies_with(command))
+}
+
+fn notification_info(command: u16) -> Option<&'static CommandInfo> {
+    COMMANDS.iter().find(|x| x.notifies_with(command))
+}
+
+/// Returns the name of the command using `command` as one of its IDs, or
+/// `None` if the ID is not known.
+pub fn command_name(command: u16) -> Option<&'static str> {
+    command_info(command).map(|x| x.name)
+}
+
+/// Updates `device` with the value carried by `p`, a reply or notification
+/// received from it. Returns `None` if `p` does not carry device state, or if
+/// the device already had that value.
+pub(crate) fn update_device(
+    device: &mut Device,
+    p: &protocol::Packet,
+) -> Result<Option<PropertyChange>> {
+    match COMMANDS.iter().find(|x| x.carries_state(p.command)) {
+        Some(info) => (info.update)(device, p.command_data.as_deref().unwrap_or_default()),
+        None => Ok(None),
+    }
+}
 
-        format!("{} {} {:?}", command_type, Self::NAME, unmarshal_result)
+fn command_type_name(p: &protocol::Packet) -> &'static str {
+    match p.command_type {
+        COMMAND_TYPE_FETCH => "fetch",
+        COMMAND_TYPE_SET => "set",
+        _ => "??",
     }
 }
 
+fn decode(info: Option<&CommandInfo>, p: &protocol::Packet) -> Result<DecodedPacket> {
+    info.ok_or(Error::UnknownCommand(p.command))?
+        .decode(p.command_data.as_deref().unwrap_or_default())
+}
+
+/// Decodes a packet received on the command reply port
+pub fn decode_reply(p: &protocol::Packet) -> Result<DecodedPacket> {
+    decode(reply_info(p.command), p)
+}
+
+/// Decodes a packet received on the notification port
+pub fn decode_notification(p: &protocol::Packet) -> Result<DecodedPacket> {
+    decode(notification_info(p.command), p)
+}
+
 pub fn format_reply(p: &protocol::Packet) -> String {
-    match p.command {
-        BatteryLevel::GET_REPLY_COMMAND_ID => BatteryLevel::format_reply(p),
-        Capabilities::GET_COMMAND_ID => Capabilities::format_reply(p),
-        DeviceName::GET_COMMAND_ID => DeviceName::format_reply(p),
-        FirmwareUpdate::GET_COMMAND_ID => FirmwareUpdate::format_reply(p),
-        PlayControl::GET_COMMAND_ID => PlayControl::format_reply(p),
-        PlayInfo::GET_COMMAND_ID => PlayInfo::format_reply(p),
-        PreChannel::GET_COMMAND_ID => PreChannel::format_reply(p),
-        Volume::GET_COMMAND_ID => Volume::format_reply(p),
-        _ => format!("{:?}", p),
+    match reply_info(p.command) {
+        Some(info) => info.format(p),
+        None => format!("{:?}", p),
     }
 }
 
 pub fn format_notification(p: &protocol::Packet) -> String {
-    match p.command {
-        BatteryLevel::NOTIFY_ID => BatteryLevel::format_notification(p),
-        FirmwareUpdate::NOTIFY_ID => FirmwareUpdate::format_notification(p),
-        PlayControl::NOTIFY_ID => PlayControl::format_notification(p),
-        PlayInfo::NOTIFY_ID => PlayInfo::format_notification(p),
-        Power::NOTIFY_ID => Power::format_notification(p),
-        PowerMode::NOTIFY_ID => PowerMode::format_notification(p),
-        Volume::NOTIFY_ID => Volume::format_notification(p),
-        _ => format!("{:?}", p),
+    match notification_info(p.command) {
+        Some(info) => info.format(p),
+        None => format!("{:?}", p),
     }
 }
 
+/// Formats a command sent to a device. Set commands carry request data,
+/// which is shown as is rather than decoded.
+pub fn format_command(p: &protocol::Packet) -> String {
+    let data = p
+        .command_data
+        .as_ref()
+        .map(|data| String::from_utf8_lossy(data).to_string())
+        .unwrap_or_default();
+
+    format!(
+        "{} {} {:?}",
+        command_type_name(p),
+        command_name(p.command).unwrap_or("??"),
+        data
+    )
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
 pub enum PowerState {
     Sleep,
     WakeUp,
@@ -109,79 +311,127 @@ pub enum PowerState {
 
 pub struct Power;
 
-impl Command<PowerState, ()> for Power {
+impl Command for Power {
+    type RequestData = PowerState;
+    type ResponseData = PowerState;
+
     const GET_COMMAND_ID: u16 = 15;
     const SET_COMMAND_ID: u16 = 15;
     const NOTIFY_ID: u16 = 15;
     const NAME: &'static str = "Power";
 
-    fn marshal_data(s: PowerState) -> Vec<u8> {
-        match s {
+    fn marshal_data(s: PowerState) -> Result<Vec<u8>> {
+        Ok(match s {
             PowerState::Sleep => "02",
             PowerState::WakeUp => "00",
         }
         .as_bytes()
-        .to_vec()
+        .to_vec())
     }
 
-    fn unmarshal_data(_: &[u8]) -> Result<()> {
-        Ok(())
+    fn unmarshal_data(data: &[u8]) -> Result<PowerState> {
+        match data {
+            b"02" => Ok(PowerState::Sleep),
+            b"00" => Ok(PowerState::WakeUp),
+            _ => Err(invalid_data::<Self, _>(format!(
+                "unknown value {:?}",
+                String::from_utf8_lossy(data)
+            ))),
+        }
+    }
+
+    fn update_device(device: &mut Device, state: PowerState) -> Option<PropertyChange> {
+        PropertyChange::update(&mut device.power_state, state, PropertyValue::PowerState)
     }
 }
 
 pub struct DeviceName;
 
-impl Command<String, String> for DeviceName {
+impl Command for DeviceName {
+    type RequestData = String;
+    type ResponseData = String;
+
     const GET_COMMAND_ID: u16 = 90;
     const SET_COMMAND_ID: u16 = 90;
     const NOTIFY_ID: u16 = 0;
     const NAME: &'static str = "Name";
 
-    fn marshal_data(name: String) -> Vec<u8> {
-        name.as_bytes().to_vec()
+    fn marshal_data(name: String) -> Result<Vec<u8>> {
+        Ok(name.into_bytes())
     }
 
     fn unmarshal_data(data: &[u8]) -> Result<String> {
         Ok(String::from_utf8_lossy(data).to_string())
     }
+
+    fn update_device(device: &mut Device, name: String) -> Option<PropertyChange> {
+        PropertyChange::update(&mut device.name, name, PropertyValue::Name)
+    }
 }
 
 pub struct Volume;
 
-impl Command<u8, u8> for Volume {
+impl Command for Volume {
+    type RequestData = u8;
+    type ResponseData = u8;
+
     const GET_COMMAND_ID: u16 = 64;
     const SET_COMMAND_ID: u16 = 64;
     const NOTIFY_ID: u16 = 64;
     const NAME: &'static str = "Volume";
 
-    fn marshal_data(volume: u8) -> Vec<u8> {
+    fn marshal_data(volume: u8) -> Result<Vec<u8>> {
         if volume > 100 {
-            panic!("volume cannot be greater than 100")
+            return Err(invalid_value::<Self, _>(
+                "volume cannot be greater than 100",
+            ));
         }
 
-        volume.to_string().as_bytes().to_vec()
+        Ok(volume.to_string().into_bytes())
     }
 
     fn unmarshal_data(data: &[u8]) -> Result<u8> {
         String::from_utf8_lossy(data)
             .parse()
-            .map_err(|_| anyhow!("invalid volume data"))
+            .map_err(invalid_data::<Self, _>)
+    }
+
+    fn update_device(device: &mut Device, volume: u8) -> Option<PropertyChange> {
+        PropertyChange::update(&mut device.volume, volume, PropertyValue::Volume)
     }
 }
 
-pub fn hello(our_addr: &IpAddr) -> protocol::Packet {
-    protocol::Packet {
-        command_type: COMMAND_TYPE_SET,
-        command: 3,
-        command_data: Some(
-            format!("{},{}", our_addr, protocol::NOTIF_RECV_PORT)
-                .as_bytes()
-                .to_vec(),
-        ),
+pub const HELLO_COMMAND_ID: u16 = 3;
+
+/// Registers for notifications. Devices are expected to reply by echoing the
+/// address they will send them to, but that has not been checked against a
+/// capture, so a device that does not reply may still send notifications.
+pub struct Hello;
+
+impl Command for Hello {
+    type RequestData = IpAddr;
+    type ResponseData = String;
+
+    const GET_COMMAND_ID: u16 = 0;
+    const GET_REPLY_COMMAND_ID: u16 = HELLO_COMMAND_ID;
+    const SET_COMMAND_ID: u16 = HELLO_COMMAND_ID;
+    const NOTIFY_ID: u16 = 0;
+    const NAME: &'static str = "Hello";
+
+    fn marshal_data(our_addr: IpAddr) -> Result<Vec<u8>> {
+        Ok(format!("{},{}", our_addr, protocol::NOTIF_RECV_PORT).into_bytes())
+    }
+
+    fn unmarshal_data(data: &[u8]) -> Result<String> {
+        Ok(String::from_utf8_lossy(data).to_string())
     }
 }
 
-#[derive(Clone, Copy, Debug, PartialEq)]
+pub fn hello(our_addr: &IpAddr) -> Result<protocol::Packet> {
+    Hello::set(*our_addr)
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
 pub enum PlayControlCommand {
     Play,
     Stop,
@@ -195,14 +445,17 @@ pub enum PlayControlCommand {
 
 pub struct PlayControl;
 
-impl Command<PlayControlCommand, PlayControlCommand> for PlayControl {
+impl Command for PlayControl {
+    type RequestData = PlayControlCommand;
+    type ResponseData = PlayControlCommand;
+
     const GET_COMMAND_ID: u16 = 51;
     const SET_COMMAND_ID: u16 = 40;
     const NOTIFY_ID: u16 = 51;
     const NAME: &'static str = "Play control";
 
-    fn marshal_data(cmd: PlayControlCommand) -> Vec<u8> {
-        match cmd {
+    fn marshal_data(cmd: PlayControlCommand) -> Result<Vec<u8>> {
+        Ok(match cmd {
             PlayControlCommand::Play => "PLAY",
             PlayControlCommand::Stop => "STOP",
             PlayControlCommand::Pause => "PAUSE",
@@ -213,7 +466,7 @@ impl Command<PlayControlCommand, PlayControlCommand> for PlayControl {
             PlayControlCommand::Unmute => "UNMUTE",
         }
         .as_bytes()
-        .to_vec()
+        .to_vec())
     }
 
     fn unmarshal_data(data: &[u8]) -> Result<PlayControlCommand> {
@@ -228,13 +481,32 @@ impl Command<PlayControlCommand, PlayControlCommand> for PlayControl {
             [53] => Ok(PlayControlCommand::Toggle),
             [54] => Ok(PlayControlCommand::Mute),
             [55] => Ok(PlayControlCommand::Unmute),
-            [other] => Err(anyhow!("invalid data: {}", other)),
-            _ => Err(anyhow!("invalid data length")),
+            [other] => Err(invalid_data::<Self, _>(format!("unknown value {}", other))),
+            _ => Err(invalid_data::<Self, _>("expected a single byte")),
+        }
+    }
+
+    fn confirms_set(sent: &[u8], received: &[u8]) -> bool {
+        let status = match Self::unmarshal_data(received) {
+            Ok(status) => status,
+            Err(_) => return false,
+        };
+
+        match sent {
+            b"PLAY" => status == PlayControlCommand::Play,
+            b"STOP" => status == PlayControlCommand::Stop,
+            b"PAUSE" => status == PlayControlCommand::Pause,
+            // skipping tracks or muting does not show in the play status
+            _ => true,
         }
     }
+
+    fn update_device(device: &mut Device, status: PlayControlCommand) -> Option<PropertyChange> {
+        PropertyChange::update(&mut device.play_status, status, PropertyValue::PlayStatus)
+    }
 }
 
-#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
 pub struct PlayInfoData {
     #[serde(rename(deserialize = "isFromChannel"))]
     pub is_from_channel: bool,
@@ -255,72 +527,99 @@ pub struct PlayInfoData {
 
 pub struct PlayInfo;
 
-impl Command<PlayInfoData, PlayInfoData> for PlayInfo {
+impl Command for PlayInfo {
+    type RequestData = PlayInfoData;
+    type ResponseData = PlayInfoData;
+
     const SET_COMMAND_ID: u16 = 277;
     const GET_COMMAND_ID: u16 = 278;
     const NOTIFY_ID: u16 = 278;
     const NAME: &'static str = "Play info";
 
-    fn marshal_data(d: PlayInfoData) -> Vec<u8> {
-        match serde_json::to_vec(&d) {
-            Ok(data) => data,
-            Err(err) => {
-                println!("error marshaling PlayInfoData to JSON: {}", err);
-                vec![]
-            }
-        }
+    fn marshal_data(d: PlayInfoData) -> Result<Vec<u8>> {
+        serde_json::to_vec(&d).map_err(invalid_value::<Self, _>)
     }
 
     fn unmarshal_data(data: &[u8]) -> Result<PlayInfoData> {
-        serde_json::from_slice(data).context("error parsing JSON")
+        serde_json::from_slice(data).map_err(invalid_data::<Self, _>)
+    }
+
+    fn confirms_set(_sent: &[u8], received: &[u8]) -> bool {
+        // the device fills in what it knows about the channel it plays, so
+        // the notification never matches the request
+        Self::unmarshal_data(received).is_ok()
+    }
+
+    fn update_device(device: &mut Device, info: PlayInfoData) -> Option<PropertyChange> {
+        PropertyChange::update(&mut device.play_info, info, |x| {
+            PropertyValue::PlayInfo(Box::new(x))
+        })
     }
 }
 
 #[derive(Debug, Deserialize)]
 pub struct Capability {
-    name: String,
+    pub name: String,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct CapabilitiesData {
-    capabilities: Vec<Capability>,
+    pub capabilities: Vec<Capability>,
 }
 
 pub struct Capabilities;
 
-impl Command<(), CapabilitiesData> for Capabilities {
+impl Command for Capabilities {
+    type RequestData = ();
+    type ResponseData = CapabilitiesData;
+
     const SET_COMMAND_ID: u16 = 0;
     const GET_COMMAND_ID: u16 = 281;
     const NOTIFY_ID: u16 = 0;
     const NAME: &'static str = "Capabilities";
 
-    fn marshal_data(_: ()) -> Vec<u8> {
-        vec![] // for now
+    fn marshal_data(_: ()) -> Result<Vec<u8>> {
+        Ok(vec![]) // for now
     }
 
     fn unmarshal_data(data: &[u8]) -> Result<CapabilitiesData> {
-        serde_json::from_slice(data).context("error parsing JSON")
+        serde_json::from_slice(data).map_err(invalid_data::<Self, _>)
+    }
+
+    fn update_device(device: &mut Device, data: CapabilitiesData) -> Option<PropertyChange> {
+        PropertyChange::update(
+            &mut device.capabilities,
+            data.capabilities.into_iter().map(|x| x.name).collect(),
+            PropertyValue::Capabilities,
+        )
     }
 }
 
 pub struct PowerMode;
 
-impl Command<(), String> for PowerMode {
+impl Command for PowerMode {
+    type RequestData = ();
+    type ResponseData = String;
+
     const SET_COMMAND_ID: u16 = 0;
     const GET_COMMAND_ID: u16 = 14;
     const NOTIFY_ID: u16 = 14;
     const NAME: &'static str = "Power mode";
 
-    fn marshal_data(_: ()) -> Vec<u8> {
-        vec![] // for now
+    fn marshal_data(_: ()) -> Result<Vec<u8>> {
+        Ok(vec![]) // for now
     }
 
     fn unmarshal_data(data: &[u8]) -> Result<String> {
         Ok(String::from_utf8_lossy(data).to_string())
     }
+
+    fn update_device(device: &mut Device, mode: String) -> Option<PropertyChange> {
+        PropertyChange::update(&mut device.power_mode, mode, PropertyValue::PowerMode)
+    }
 }
 
-#[derive(Clone, Copy, Debug, PartialEq)]
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
 pub enum ChargingStateData {
     Discharging,
     PluggedInCharging,
@@ -330,14 +629,17 @@ pub enum ChargingStateData {
 
 pub struct ChargingState;
 
-impl Command<(), ChargingStateData> for ChargingState {
+impl Command for ChargingState {
+    type RequestData = ();
+    type ResponseData = ChargingStateData;
+
     const SET_COMMAND_ID: u16 = 0;
     const GET_COMMAND_ID: u16 = 1284;
     const NOTIFY_ID: u16 = 1284;
     const NAME: &'static str = "Charging state";
 
-    fn marshal_data(_: ()) -> Vec<u8> {
-        vec![] // for now
+    fn marshal_data(_: ()) -> Result<Vec<u8>> {
+        Ok(vec![]) // for now
     }
 
     fn unmarshal_data(data: &[u8]) -> Result<ChargingStateData> {
@@ -346,29 +648,48 @@ impl Command<(), ChargingStateData> for ChargingState {
             [49] => Ok(ChargingStateData::PluggedInCharging),
             [50] => Ok(ChargingStateData::PluggedInCharged),
             [51] => Ok(ChargingStateData::PluggedInNotCharging),
-            [other] => Err(anyhow!("invalid data: {}", other)),
-            _ => Err(anyhow!("invalid data length")),
+            [other] => Err(invalid_data::<Self, _>(format!("unknown value {}", other))),
+            _ => Err(invalid_data::<Self, _>("expected a single byte")),
         }
     }
+
+    fn update_device(device: &mut Device, state: ChargingStateData) -> Option<PropertyChange> {
+        PropertyChange::update(
+            &mut device.charging_state,
+            state,
+            PropertyValue::ChargingState,
+        )
+    }
 }
 
 pub struct BatteryLevel;
 
-impl Command<(), u8> for BatteryLevel {
+impl Command for BatteryLevel {
+    type RequestData = ();
+    type ResponseData = u8;
+
     const SET_COMMAND_ID: u16 = 0;
     const GET_COMMAND_ID: u16 = 256;
     const GET_REPLY_COMMAND_ID: u16 = 257;
     const NOTIFY_ID: u16 = 258;
     const NAME: &'static str = "Battery level";
 
-    fn marshal_data(_: ()) -> Vec<u8> {
-        vec![] // for now
+    fn marshal_data(_: ()) -> Result<Vec<u8>> {
+        Ok(vec![]) // for now
     }
 
     fn unmarshal_data(data: &[u8]) -> Result<u8> {
         String::from_utf8_lossy(data)
             .parse()
-            .map_err(|x| anyhow!("error parsing battery level: {}", x))
+            .map_err(invalid_data::<Self, _>)
+    }
+
+    fn update_device(device: &mut Device, level: u8) -> Option<PropertyChange> {
+        PropertyChange::update(
+            &mut device.battery_level,
+            level,
+            PropertyValue::BatteryLevel,
+        )
     }
 }
 
@@ -385,7 +706,7 @@ pub enum ChannelType {
     Napster,
 }
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
 pub struct ChannelObject {
     #[serde(rename(deserialize = "isPlaying"))]
     pub is_playing: Option<bool>,
@@ -421,40 +742,194 @@ impl ChannelObject {
 
 pub struct FirmwareUpdate;
 
-impl Command<(), String> for FirmwareUpdate {
+impl Command for FirmwareUpdate {
+    type RequestData = ();
+    type ResponseData = String;
+
     const SET_COMMAND_ID: u16 = 65;
     const GET_COMMAND_ID: u16 = 65;
     const NOTIFY_ID: u16 = 65;
     const NAME: &'static str = "FM Update";
 
-    fn marshal_data(_: ()) -> Vec<u8> {
-        vec![] // for now
+    fn marshal_data(_: ()) -> Result<Vec<u8>> {
+        Ok(vec![]) // for now
     }
 
     fn unmarshal_data(data: &[u8]) -> Result<String> {
         Ok(String::from_utf8_lossy(data).to_string())
     }
+
+    fn confirms_set(_sent: &[u8], _received: &[u8]) -> bool {
+        // starting an update carries no data, the device answers with its
+        // progress
+        true
+    }
+
+    fn update_device(device: &mut Device, status: String) -> Option<PropertyChange> {
+        PropertyChange::update(
+            &mut device.firmware_update,
+            status,
+            PropertyValue::FirmwareUpdate,
+        )
+    }
 }
 
 pub struct PreChannel;
 
-impl Command<ChannelObject, Vec<ChannelObject>> for PreChannel {
+impl Command for PreChannel {
+    type RequestData = ChannelObject;
+    type ResponseData = Vec<ChannelObject>;
+
     const SET_COMMAND_ID: u16 = 276;
     const GET_COMMAND_ID: u16 = 275;
     const NOTIFY_ID: u16 = 0;
     const NAME: &'static str = "PreChannel";
 
-    fn marshal_data(ch: ChannelObject) -> Vec<u8> {
-        match serde_json::to_vec(&ch) {
-            Ok(data) => data,
-            Err(err) => {
-                println!("error marshaling channel to JSON: {}", err);
-                vec![]
+    fn marshal_data(ch: ChannelObject) -> Result<Vec<u8>> {
+        serde_json::to_vec(&ch).map_err(invalid_value::<Self, _>)
+    }
+
+    fn unmarshal_data(data: &[u8]) -> Result<Vec<ChannelObject>> {
+        serde_json::from_slice(data).map_err(invalid_data::<Self, _>)
+    }
+
+    fn confirms_set(sent: &[u8], received: &[u8]) -> bool {
+        // a single channel is set, and all of them are fetched back
+        match (
+            serde_json::from_slice::<ChannelObject>(sent),
+            Self::unmarshal_data(received),
+        ) {
+            (Ok(channel), Ok(channels)) => {
+                channels.iter().any(|x| x.channel_id == channel.channel_id)
             }
+            _ => false,
         }
     }
 
-    fn unmarshal_data(data: &[u8]) -> Result<Vec<ChannelObject>> {
-        serde_json::from_slice(data).context("error parsing JSON")
+    fn update_device(device: &mut Device, channels: Vec<ChannelObject>) -> Option<PropertyChange> {
+        PropertyChange::update(
+            &mut device.pre_channels,
+            channels,
+            PropertyValue::PreChannels,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packet(command_type: u8, command: u16, data: &str) -> protocol::Packet {
+        protocol::Packet {
+            command_type,
+            command,
+            status: protocol::PacketStatus::Ok,
+            sequence: protocol::DEFAULT_SEQUENCE,
+            command_data: Some(data.as_bytes().to_vec()),
+        }
+    }
+
+    #[test]
+    fn registry_ids_test() {
+        // an ID belongs to a single command, or lookups would be ambiguous
+        for (i, command) in COMMANDS.iter().enumerate() {
+            for other in &COMMANDS[i + 1..] {
+                for id in [
+                    other.get_id,
+                    other.get_reply_id,
+                    other.set_id,
+                    other.notify_id,
+                ] {
+                    assert!(
+                        !command.uses_id(id),
+                        "{} and {} both use ID {}",
+                        command.name,
+                        other.name,
+                        id
+                    );
+                }
+            }
+        }
+
+        assert_eq!(command_name(Volume::GET_COMMAND_ID), Some("Volume"));
+        assert_eq!(command_name(HELLO_COMMAND_ID), Some("Hello"));
+        assert_eq!(command_name(4242), None);
+    }
+
+    #[test]
+    fn format_test() {
+        assert_eq!(
+            format_reply(&packet(
+                COMMAND_TYPE_SET,
+                ChargingState::GET_REPLY_COMMAND_ID,
+                "1"
+            )),
+            "set Charging state \"PluggedInCharging\""
+        );
+        assert_eq!(
+            format_reply(&packet(COMMAND_TYPE_SET, Power::GET_REPLY_COMMAND_ID, "00")),
+            "set Power \"WakeUp\""
+        );
+        assert_eq!(
+            format_reply(&packet(COMMAND_TYPE_SET, HELLO_COMMAND_ID, "10.0.0.1,3333")),
+            "set Hello \"\\\"10.0.0.1,3333\\\"\""
+        );
+        assert_eq!(
+            format_notification(&packet(COMMAND_TYPE_SET, DeviceName::GET_COMMAND_ID, "Den")),
+            "set Name \"\\\"Den\\\"\""
+        );
+        assert_eq!(
+            format_notification(&packet(COMMAND_TYPE_SET, PreChannel::GET_COMMAND_ID, "[]")),
+            "set PreChannel \"[]\""
+        );
+        assert_eq!(
+            format_command(&packet(COMMAND_TYPE_FETCH, Volume::GET_COMMAND_ID, "")),
+            "fetch Volume \"\""
+        );
+    }
+
+    #[test]
+    fn marshal_test() {
+        assert_eq!(Volume::set(42).unwrap().command_data, Some(b"42".to_vec()));
+        assert!(matches!(
+            Volume::set(150),
+            Err(Error::InvalidValue {
+                command: "Volume",
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn confirms_set_test() {
+        assert!(Power::confirms_set(b"02", b"02"));
+        assert!(!Power::confirms_set(b"02", b"00"));
+        assert!(PlayControl::confirms_set(b"PAUSE", b"2"));
+        assert!(!PlayControl::confirms_set(b"PAUSE", b"0"));
+        assert!(PlayControl::confirms_set(b"NEXT", b"0"));
+        assert!(!PlayControl::confirms_set(b"NEXT", b"?"));
+    }
+
+    #[test]
+    fn decode_test() {
+        assert!(matches!(
+            decode_notification(&packet(COMMAND_TYPE_SET, BatteryLevel::NOTIFY_ID, "42")),
+            Ok(DecodedPacket::BatteryLevel(42))
+        ));
+        assert!(matches!(
+            decode_reply(&packet(
+                COMMAND_TYPE_SET,
+                Volume::GET_REPLY_COMMAND_ID,
+                "loud"
+            )),
+            Err(Error::Decode {
+                command: "Volume",
+                ..
+            })
+        ));
+        assert!(matches!(
+            decode_reply(&packet(COMMAND_TYPE_SET, 4242, "")),
+            Err(Error::UnknownCommand(4242))
+        ));
     }
 }
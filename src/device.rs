@@ -1,37 +1,92 @@
+use std::io::ErrorKind;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
 use std::sync::Arc;
-
-use anyhow::{anyhow, Context, Result};
+use std::time::{Duration, Instant};
 
 use net2::unix::UnixUdpBuilderExt;
+use serde::Serialize;
 
 use crate::commands;
 use crate::commands::{
-    ChannelObject, ChargingStateData, Command, PlayControlCommand, PlayInfoData,
+    ChannelObject, ChargingStateData, Command, PlayControlCommand, PlayInfoData, PowerState,
 };
+use crate::device_handle::DeviceHandle;
 use crate::discovery_reply;
+use crate::error::IoContext;
 use crate::protocol;
-use crate::protocol::{PacketReceiver, PacketSender};
+use crate::protocol::{Direction, PacketReceiver, PacketSender};
+use crate::subscription::{EventFilter, Subscriber, Subscription};
+use crate::{Error, Result};
+
+/// Whether a device agreed to send us notifications, after we registered with
+/// it using [`commands::hello`].
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub enum SubscriptionStatus {
+    /// Registration is in progress
+    Pending,
+    /// The device replied to the registration, or sent us a notification
+    Subscribed,
+    /// The device did not reply to the registration. It may still send
+    /// notifications, since how devices reply to [`commands::hello`] is not
+    /// known from a capture. The registration is retried later.
+    Unknown,
+}
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize)]
 pub struct Device {
     id: String,
     addr: IpAddr,
 
-    name: Option<String>,
-    volume: Option<u8>,
-    play_status: Option<PlayControlCommand>,
-    play_info: Option<PlayInfoData>,
-    pre_channels: Option<Vec<ChannelObject>>,
-    charging_state: Option<ChargingStateData>,
-    battery_level: Option<u8>,
+    // updated by each command from the replies and notifications carrying
+    // its value, see [`Command::update_device`]
+    pub(crate) name: Option<String>,
+    pub(crate) volume: Option<u8>,
+    pub(crate) play_status: Option<PlayControlCommand>,
+    pub(crate) play_info: Option<PlayInfoData>,
+    pub(crate) pre_channels: Option<Vec<ChannelObject>>,
+    pub(crate) charging_state: Option<ChargingStateData>,
+    pub(crate) battery_level: Option<u8>,
+    pub(crate) power_state: Option<PowerState>,
+    pub(crate) power_mode: Option<String>,
+    pub(crate) capabilities: Option<Vec<String>>,
+    pub(crate) firmware_update: Option<String>,
+
+    advertised_name: String,
+    firmware_version: String,
+    color_code: String,
+    zone_id: String,
+    creator: String,
+    device_state: String,
+    stereo_pair_id: String,
+
+    #[serde(skip)]
+    last_seen: Instant,
+    online: bool,
+
+    subscription: SubscriptionStatus,
+    #[serde(skip)]
+    hello_attempts: u32,
+    #[serde(skip)]
+    next_hello: Instant,
 }
 
 impl Device {
-    fn new(id: String, addr: IpAddr) -> Device {
+    fn new(info: &discovery_reply::DiscoveryReply) -> Device {
         Device {
-            id,
-            addr,
+            id: info.device_id.clone(),
+            addr: info.ip_address,
+            advertised_name: info.device_name.clone(),
+            firmware_version: info.firmware_version.clone(),
+            color_code: info.color_code.clone(),
+            zone_id: info.zone_id.clone(),
+            creator: info.creator.clone(),
+            device_state: info.device_state.clone(),
+            stereo_pair_id: info.stereo_pair_id.clone(),
+            last_seen: Instant::now(),
+            online: true,
+            subscription: SubscriptionStatus::Pending,
+            hello_attempts: 0,
+            next_hello: Instant::now(),
             name: None,
             volume: None,
             play_status: None,
@@ -39,6 +94,10 @@ impl Device {
             pre_channels: None,
             charging_state: None,
             battery_level: None,
+            power_state: None,
+            power_mode: None,
+            capabilities: None,
+            firmware_update: None,
         }
     }
 
@@ -69,22 +128,372 @@ impl Device {
     pub fn pre_channels(&self) -> Option<Vec<ChannelObject>> {
         self.pre_channels.clone()
     }
+
+    pub fn charging_state(&self) -> Option<ChargingStateData> {
+        self.charging_state
+    }
+
+    pub fn battery_level(&self) -> Option<u8> {
+        self.battery_level
+    }
+
+    pub fn power_state(&self) -> Option<PowerState> {
+        self.power_state
+    }
+
+    pub fn power_mode(&self) -> Option<String> {
+        self.power_mode.clone()
+    }
+
+    /// Names of the features the device supports
+    pub fn capabilities(&self) -> Option<Vec<String>> {
+        self.capabilities.clone()
+    }
+
+    pub fn firmware_update(&self) -> Option<String> {
+        self.firmware_update.clone()
+    }
+
+    /// Name the device announced itself with when it was last discovered.
+    /// Unlike [`Device::name`], this does not require fetching anything.
+    pub fn advertised_name(&self) -> String {
+        self.advertised_name.clone()
+    }
+
+    pub fn firmware_version(&self) -> String {
+        self.firmware_version.clone()
+    }
+
+    pub fn color_code(&self) -> String {
+        self.color_code.clone()
+    }
+
+    pub fn zone_id(&self) -> String {
+        self.zone_id.clone()
+    }
+
+    pub fn creator(&self) -> String {
+        self.creator.clone()
+    }
+
+    pub fn device_state(&self) -> String {
+        self.device_state.clone()
+    }
+
+    /// ID of the stereo pair the device is part of, empty if it is not
+    /// paired.
+    pub fn stereo_pair_id(&self) -> String {
+        self.stereo_pair_id.clone()
+    }
+
+    /// Last time a discovery reply, command reply or notification was
+    /// received from the device.
+    pub fn last_seen(&self) -> Instant {
+        self.last_seen
+    }
+
+    /// Whether the device was heard from within the configured expiry delay.
+    pub fn is_online(&self) -> bool {
+        self.online
+    }
+
+    /// Whether the device sends us notifications.
+    pub fn subscription(&self) -> SubscriptionStatus {
+        self.subscription
+    }
+
+    /// Whether `query` designates the device, by ID, IP address or name.
+    /// Names are compared ignoring case, and the advertised name is used if
+    /// the name was not fetched.
+    pub fn matches(&self, query: &str) -> bool {
+        let name_matches = |name: &str| name.eq_ignore_ascii_case(query);
+
+        self.id == query
+            || query.parse() == Ok(self.addr)
+            || self.name.as_deref().is_some_and(name_matches)
+            || name_matches(&self.advertised_name)
+    }
+
+    /// Refreshes the information advertised in discovery replies, returns
+    /// true if any of it changed.
+    fn update_discovery_info(&mut self, info: &discovery_reply::DiscoveryReply) -> bool {
+        let updated = Device::new(info);
+
+        let changed = self.addr != updated.addr
+            || self.advertised_name != updated.advertised_name
+            || self.firmware_version != updated.firmware_version
+            || self.color_code != updated.color_code
+            || self.zone_id != updated.zone_id
+            || self.creator != updated.creator
+            || self.device_state != updated.device_state
+            || self.stereo_pair_id != updated.stereo_pair_id;
+
+        self.addr = updated.addr;
+        self.advertised_name = updated.advertised_name;
+        self.firmware_version = updated.firmware_version;
+        self.color_code = updated.color_code;
+        self.zone_id = updated.zone_id;
+        self.creator = updated.creator;
+        self.device_state = updated.device_state;
+        self.stereo_pair_id = updated.stereo_pair_id;
+
+        changed
+    }
+}
+
+/// A part of the device state that is fetched from, or notified by, the
+/// device itself.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq, Serialize)]
+pub enum DeviceProperty {
+    Name,
+    Volume,
+    PlayStatus,
+    PlayInfo,
+    PreChannels,
+    ChargingState,
+    BatteryLevel,
+    PowerState,
+    PowerMode,
+    Capabilities,
+    FirmwareUpdate,
+}
+
+/// The value of a [`DeviceProperty`]
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(untagged)]
+pub enum PropertyValue {
+    Name(String),
+    Volume(u8),
+    PlayStatus(PlayControlCommand),
+    PlayInfo(Box<PlayInfoData>),
+    PreChannels(Vec<ChannelObject>),
+    ChargingState(ChargingStateData),
+    BatteryLevel(u8),
+    PowerState(PowerState),
+    PowerMode(String),
+    Capabilities(Vec<String>),
+    FirmwareUpdate(String),
+}
+
+impl PropertyValue {
+    pub fn property(&self) -> DeviceProperty {
+        match self {
+            PropertyValue::Name(_) => DeviceProperty::Name,
+            PropertyValue::Volume(_) => DeviceProperty::Volume,
+            PropertyValue::PlayStatus(_) => DeviceProperty::PlayStatus,
+            PropertyValue::PlayInfo(_) => DeviceProperty::PlayInfo,
+            PropertyValue::PreChannels(_) => DeviceProperty::PreChannels,
+            PropertyValue::ChargingState(_) => DeviceProperty::ChargingState,
+            PropertyValue::BatteryLevel(_) => DeviceProperty::BatteryLevel,
+            PropertyValue::PowerState(_) => DeviceProperty::PowerState,
+            PropertyValue::PowerMode(_) => DeviceProperty::PowerMode,
+            PropertyValue::Capabilities(_) => DeviceProperty::Capabilities,
+            PropertyValue::FirmwareUpdate(_) => DeviceProperty::FirmwareUpdate,
+        }
+    }
 }
 
-#[derive(Clone, Debug)]
+/// A change of device state, see [`Command::update_device`]
+#[derive(Clone, Debug, PartialEq)]
+pub struct PropertyChange {
+    /// `None` when the value was not known yet
+    pub old: Option<PropertyValue>,
+    pub new: PropertyValue,
+}
+
+impl PropertyChange {
+    /// Stores `value` in `field`, returning the change unless `field` already
+    /// held that value. `wrap` turns values of the field into a
+    /// [`PropertyValue`].
+    pub(crate) fn update<T: Clone + PartialEq>(
+        field: &mut Option<T>,
+        value: T,
+        wrap: fn(T) -> PropertyValue,
+    ) -> Option<PropertyChange> {
+        if field.as_ref() == Some(&value) {
+            return None;
+        }
+
+        Some(PropertyChange {
+            old: field.replace(value.clone()).map(wrap),
+            new: wrap(value),
+        })
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "event", content = "data")]
 pub enum DeviceManagerEvent {
     DeviceDiscovered(Device),
     DeviceUpdated(Device),
+    /// The device was not heard from for longer than the expiry delay.
+    DeviceLost(Device),
+    /// A device previously reported as lost is talking again.
+    DeviceReappeared(Device),
+    /// A reply or notification changed a value of the device state. Receiving
+    /// a value the device already had does not trigger any event.
+    DevicePropertyChanged {
+        device_id: String,
+        property: DeviceProperty,
+        old: Option<PropertyValue>,
+        new: PropertyValue,
+    },
+}
+
+/// The kinds of [`DeviceManagerEvent`], see [`EventFilter::with_kinds`]
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq, Serialize)]
+pub enum EventKind {
+    DeviceDiscovered,
+    DeviceUpdated,
+    DeviceLost,
+    DeviceReappeared,
+    DevicePropertyChanged,
+}
+
+impl DeviceManagerEvent {
+    pub fn kind(&self) -> EventKind {
+        match self {
+            DeviceManagerEvent::DeviceDiscovered(_) => EventKind::DeviceDiscovered,
+            DeviceManagerEvent::DeviceUpdated(_) => EventKind::DeviceUpdated,
+            DeviceManagerEvent::DeviceLost(_) => EventKind::DeviceLost,
+            DeviceManagerEvent::DeviceReappeared(_) => EventKind::DeviceReappeared,
+            DeviceManagerEvent::DevicePropertyChanged { .. } => EventKind::DevicePropertyChanged,
+        }
+    }
+
+    /// ID of the device the event is about
+    pub fn device_id(&self) -> &str {
+        match self {
+            DeviceManagerEvent::DeviceDiscovered(device)
+            | DeviceManagerEvent::DeviceUpdated(device)
+            | DeviceManagerEvent::DeviceLost(device)
+            | DeviceManagerEvent::DeviceReappeared(device) => &device.id,
+            DeviceManagerEvent::DevicePropertyChanged { device_id, .. } => device_id,
+        }
+    }
+}
+
+pub(crate) fn check_status(reply: &protocol::Packet) -> Result<()> {
+    match reply.status {
+        protocol::PacketStatus::Error(status) => Err(Error::DeviceStatus {
+            command: reply.command,
+            status,
+        }),
+        _ => Ok(()),
+    }
+}
+
+/// Outcome of a command sent with [`DeviceManager::set`].
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(tag = "outcome")]
+pub enum DeliveryOutcome {
+    /// The device confirmed the command, after the given number of attempts.
+    Delivered { attempts: u32 },
+    /// The device never confirmed the command.
+    Failed { attempts: u32 },
+}
+
+/// How often, and how patiently, commands are retransmitted when the device
+/// does not confirm them.
+#[derive(Clone, Copy, Debug)]
+pub struct RetryPolicy {
+    /// Total number of times a command is sent, including the first one.
+    pub attempts: u32,
+    /// How long to wait for a confirmation after the first attempt.
+    pub timeout: Duration,
+    /// Factor applied to the wait time after each failed attempt.
+    pub backoff: u32,
+}
+
+impl RetryPolicy {
+    /// How long to wait for a confirmation after the given attempt, counting
+    /// from 1. Saturates rather than overflowing with large backoffs.
+    pub(crate) fn attempt_timeout(&self, attempt: u32) -> Duration {
+        self.timeout
+            .checked_mul(self.backoff.saturating_pow(attempt.saturating_sub(1)))
+            .unwrap_or(Duration::MAX)
+    }
+
+    pub(crate) fn validate(&self) -> Result<()> {
+        if self.backoff == 0 {
+            return Err(Error::InvalidRetryPolicy(
+                "backoff must be at least 1".to_owned(),
+            ));
+        }
+
+        Ok(())
+    }
 }
 
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            attempts: 3,
+            timeout: Duration::from_millis(500),
+            backoff: 2,
+        }
+    }
+}
+
+pub(crate) type PacketMatcher = Box<dyn Fn(&protocol::Packet) -> bool + Send>;
+
 struct DeviceManagerData {
-    event_listeners: Vec<std::sync::mpsc::Sender<DeviceManagerEvent>>,
-    sock_send: Box<dyn PacketSender + Send>,
-    devices: std::collections::HashMap<String, Device>,
+    event_listeners: Vec<Subscriber>,
+    search_requests: std::sync::mpsc::Sender<()>,
+    /// `None` once the device manager is shut down
+    sock_send: Option<Box<dyn PacketSender + Send>>,
+    registry: DeviceRegistry,
 }
 
 pub struct DeviceManager {
+    shared: DeviceManagerShared,
+    shutdown: Arc<ShutdownSignal>,
+    threads: std::sync::Mutex<Vec<std::thread::JoinHandle<()>>>,
+}
+
+/// What a device manager shares with the [`DeviceHandle`]s it gives out:
+/// everything needed to send requests to devices. Requests fail with
+/// [`Error::Shutdown`] once the device manager is shut down.
+#[derive(Clone)]
+pub(crate) struct DeviceManagerShared {
     data: Arc<std::sync::Mutex<DeviceManagerData>>,
+    retry_policy: RetryPolicy,
+}
+
+/// Tells the threads of a device manager, or of a
+/// [`crate::monitor::Monitor`], when to stop
+pub(crate) struct ShutdownSignal {
+    triggered: std::sync::Mutex<bool>,
+    cond: std::sync::Condvar,
+}
+
+impl ShutdownSignal {
+    pub(crate) fn new() -> ShutdownSignal {
+        ShutdownSignal {
+            triggered: std::sync::Mutex::new(false),
+            cond: std::sync::Condvar::new(),
+        }
+    }
+
+    pub(crate) fn trigger(&self) {
+        *self.triggered.lock().unwrap() = true;
+        self.cond.notify_all();
+    }
+
+    pub(crate) fn is_triggered(&self) -> bool {
+        *self.triggered.lock().unwrap()
+    }
+
+    /// Sleeps for `duration`, or less if shutdown gets triggered meanwhile.
+    /// Returns whether it was.
+    pub(crate) fn sleep(&self, duration: Duration) -> bool {
+        let triggered = self.triggered.lock().unwrap();
+        let (triggered, _) = self
+            .cond
+            .wait_timeout_while(triggered, duration, |triggered| !*triggered)
+            .unwrap();
+        *triggered
+    }
 }
 
 const ADDR_ANY: Ipv4Addr = Ipv4Addr::new(0, 0, 0, 0);
@@ -92,26 +501,77 @@ const ADDR_ANY: Ipv4Addr = Ipv4Addr::new(0, 0, 0, 0);
 pub trait NetworkImpl {
     fn packet_sender(&self) -> Result<Box<dyn PacketSender + Send>>;
     fn packet_receiver(&self, port: u16) -> Result<Box<dyn PacketReceiver + Send>>;
+
+    /// Returns the address of the local interface used to reach
+    /// `device_addr`, which is where the device should send notifications.
+    fn local_addr_for(&self, device_addr: IpAddr) -> Result<IpAddr> {
+        route_local_addr(device_addr)
+    }
+}
+
+pub(crate) fn route_local_addr(device_addr: IpAddr) -> Result<IpAddr> {
+    // connecting a UDP socket sends nothing, but picks a route
+    let sock = UdpSocket::bind((ADDR_ANY, 0))
+        .and_then(|sock| {
+            sock.connect((device_addr, protocol::CMD_SEND_PORT))?;
+            Ok(sock)
+        })
+        .context("error finding a route to the device")?;
+    Ok(sock
+        .local_addr()
+        .context("error finding a route to the device")?
+        .ip())
 }
 
 pub trait DeviceDiscoveryImpl {
     fn discover(&self) -> Result<()>;
-    fn poll(&self) -> Result<discovery_reply::DiscoveryReply>;
+    /// Waits at most `timeout` for a discovery reply, returns `None` if
+    /// none came.
+    fn poll(&self, timeout: Duration) -> Result<Option<discovery_reply::DiscoveryReply>>;
 }
 
-type ThreadsafeNetworkImpl = Box<dyn NetworkImpl + Send + Sync + 'static>;
-type ThreadsafeDeviceDiscoveryImpl = Box<dyn DeviceDiscoveryImpl + Send + Sync + 'static>;
+pub(crate) type ThreadsafeNetworkImpl = Box<dyn NetworkImpl + Send + Sync + 'static>;
+pub(crate) type ThreadsafeDeviceDiscoveryImpl =
+    Box<dyn DeviceDiscoveryImpl + Send + Sync + 'static>;
 
-pub struct DeviceManagerConfig {
-    network_impl: Arc<ThreadsafeNetworkImpl>,
+struct SearchThreadData {
     device_discovery_impl: Arc<ThreadsafeDeviceDiscoveryImpl>,
+    requests: std::sync::Mutex<std::sync::mpsc::Receiver<()>>,
+    interval: Option<Duration>,
+}
+
+struct SubscriptionThreadData {
+    network_impl: Arc<ThreadsafeNetworkImpl>,
+    retry_policy: RetryPolicy,
+}
+
+pub struct DeviceManagerConfig {
+    pub(crate) network_impl: Arc<ThreadsafeNetworkImpl>,
+    pub(crate) device_discovery_impl: Arc<ThreadsafeDeviceDiscoveryImpl>,
+    pub(crate) retry_policy: RetryPolicy,
+    pub(crate) device_expiry: Duration,
+    pub(crate) discovery_interval: Option<Duration>,
+    pub(crate) subscription_interval: Duration,
 }
 
+pub(crate) const DEFAULT_DEVICE_EXPIRY: Duration = Duration::from_secs(120);
+pub(crate) const DEFAULT_DISCOVERY_INTERVAL: Duration = Duration::from_secs(30);
+pub(crate) const DEFAULT_SUBSCRIPTION_INTERVAL: Duration = Duration::from_secs(60);
+pub(crate) const SUBSCRIPTION_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+/// How long receiving threads block at most, and therefore how long they
+/// take to notice a shutdown
+pub(crate) const RECEIVE_TIMEOUT: Duration = Duration::from_millis(200);
+
 impl DeviceManagerConfig {
+    #[allow(clippy::should_implement_trait)]
     pub fn default() -> Result<Self> {
         Ok(DeviceManagerConfig {
             network_impl: Arc::new(Box::new(RealNetworkImpl {})),
             device_discovery_impl: Arc::new(Box::new(SSDPDiscovery::new()?)),
+            retry_policy: RetryPolicy::default(),
+            device_expiry: DEFAULT_DEVICE_EXPIRY,
+            discovery_interval: Some(DEFAULT_DISCOVERY_INTERVAL),
+            subscription_interval: DEFAULT_SUBSCRIPTION_INTERVAL,
         })
     }
 
@@ -122,149 +582,273 @@ impl DeviceManagerConfig {
         DeviceManagerConfig {
             network_impl: Arc::new(network_impl),
             device_discovery_impl: Arc::new(device_discovery_impl),
+            retry_policy: RetryPolicy::default(),
+            device_expiry: DEFAULT_DEVICE_EXPIRY,
+            discovery_interval: Some(DEFAULT_DISCOVERY_INTERVAL),
+            subscription_interval: DEFAULT_SUBSCRIPTION_INTERVAL,
         }
     }
+
+    /// Sets how commands are retransmitted. Fails if the backoff is 0, which
+    /// would leave no time for the device to confirm retransmissions.
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Result<Self> {
+        retry_policy.validate()?;
+        self.retry_policy = retry_policy;
+        Ok(self)
+    }
+
+    /// Sets how long a device can stay silent before it is reported as lost.
+    pub fn with_device_expiry(mut self, device_expiry: Duration) -> Self {
+        self.device_expiry = device_expiry;
+        self
+    }
+
+    /// Sets how often discovery requests are sent. With `None`, discovery
+    /// requests are only sent at startup and when calling
+    /// [`DeviceManager::rediscover`].
+    pub fn with_discovery_interval(mut self, discovery_interval: Option<Duration>) -> Self {
+        self.discovery_interval = discovery_interval;
+        self
+    }
+
+    /// Sets how often we register again with devices for notifications, and
+    /// how long to wait before trying again when a device did not confirm
+    /// the registration.
+    pub fn with_subscription_interval(mut self, subscription_interval: Duration) -> Self {
+        self.subscription_interval = subscription_interval;
+        self
+    }
 }
 
 impl DeviceManager {
     pub fn new(config: DeviceManagerConfig) -> Result<DeviceManager> {
         let sock_send = config.network_impl.packet_sender()?;
+        let (search_requests_tx, search_requests_rx) = std::sync::mpsc::channel();
         let data = Arc::new(std::sync::Mutex::new(DeviceManagerData {
             event_listeners: vec![],
-            sock_send,
-            devices: std::collections::HashMap::new(),
+            search_requests: search_requests_tx,
+            sock_send: Some(sock_send),
+            registry: DeviceRegistry::new(config.subscription_interval),
         }));
-
-        {
-            let data = Arc::clone(&data);
-            let device_discovery_impl = Arc::clone(&config.device_discovery_impl);
-
-            std::thread::spawn(|| {
-                Self::thread_manager(
-                    "discovery",
-                    Self::discovery_thread,
-                    data,
-                    device_discovery_impl,
-                );
-            });
-        }
-
-        {
-            let data = Arc::clone(&data);
-            let network_impl = Arc::clone(&config.network_impl);
-
-            std::thread::spawn(|| {
-                Self::thread_manager(
-                    "notification",
-                    Self::notification_thread,
-                    data,
-                    network_impl,
-                );
-            });
-        }
-
-        {
-            let data = Arc::clone(&data);
-            let network_impl = Arc::clone(&config.network_impl);
-
-            std::thread::spawn(|| {
-                Self::thread_manager(
-                    "command reply",
-                    Self::command_reply_thread,
-                    data,
-                    network_impl,
-                );
-            });
-        }
-
-        Ok(DeviceManager { data })
+        let shutdown = Arc::new(ShutdownSignal::new());
+
+        let threads = vec![
+            Self::spawn_thread(
+                "discovery",
+                Self::discovery_thread,
+                Arc::clone(&data),
+                Arc::clone(&config.device_discovery_impl),
+                Arc::clone(&shutdown),
+            ),
+            Self::spawn_thread(
+                "search",
+                Self::search_thread,
+                Arc::clone(&data),
+                Arc::new(SearchThreadData {
+                    device_discovery_impl: Arc::clone(&config.device_discovery_impl),
+                    requests: std::sync::Mutex::new(search_requests_rx),
+                    interval: config.discovery_interval,
+                }),
+                Arc::clone(&shutdown),
+            ),
+            Self::spawn_thread(
+                "notification",
+                Self::notification_thread,
+                Arc::clone(&data),
+                Arc::clone(&config.network_impl),
+                Arc::clone(&shutdown),
+            ),
+            Self::spawn_thread(
+                "command reply",
+                Self::command_reply_thread,
+                Arc::clone(&data),
+                Arc::clone(&config.network_impl),
+                Arc::clone(&shutdown),
+            ),
+            Self::spawn_thread(
+                "liveness",
+                Self::liveness_thread,
+                Arc::clone(&data),
+                Arc::new(config.device_expiry),
+                Arc::clone(&shutdown),
+            ),
+            Self::spawn_thread(
+                "subscription",
+                Self::subscription_thread,
+                Arc::clone(&data),
+                Arc::new(SubscriptionThreadData {
+                    network_impl: Arc::clone(&config.network_impl),
+                    retry_policy: config.retry_policy,
+                }),
+                Arc::clone(&shutdown),
+            ),
+        ];
+
+        Ok(DeviceManager {
+            shared: DeviceManagerShared {
+                data,
+                retry_policy: config.retry_policy,
+            },
+            shutdown,
+            threads: std::sync::Mutex::new(threads),
+        })
     }
 
-    fn thread_manager<F, T>(
+    /// Runs `thread_func` on a new thread, restarting it when it fails,
+    /// until shutdown.
+    fn spawn_thread<F, T>(
         thread_name: &'static str,
         thread_func: F,
         data: Arc<std::sync::Mutex<DeviceManagerData>>,
         extra: Arc<T>,
-    ) where
-        F: Fn(Arc<std::sync::Mutex<DeviceManagerData>>, Arc<T>) -> Result<()>,
-        T: Send + 'static,
+        shutdown: Arc<ShutdownSignal>,
+    ) -> std::thread::JoinHandle<()>
+    where
+        F: Fn(Arc<std::sync::Mutex<DeviceManagerData>>, Arc<T>, &ShutdownSignal) -> Result<()>
+            + Send
+            + 'static,
+        T: Send + Sync + 'static,
     {
-        loop {
+        std::thread::spawn(move || loop {
             let data = Arc::clone(&data);
             let extra = Arc::clone(&extra);
 
-            match thread_func(data, extra) {
+            match thread_func(data, extra, &shutdown) {
                 Err(err) => {
-                    println!("error in thread {}: {}", thread_name, err);
-                    std::thread::sleep(std::time::Duration::from_secs(5));
-                }
-                _ => {
-                    unreachable!("thread should never exit");
+                    tracing::error!(thread = thread_name, error = %err, "thread failed, restarting it");
+
+                    if shutdown.sleep(Duration::from_secs(5)) {
+                        return;
+                    }
                 }
+                // threads only return successfully on shutdown
+                Ok(()) => return,
             }
-        }
+        })
     }
 
     fn discovery_thread(
         data: Arc<std::sync::Mutex<DeviceManagerData>>,
         device_discovery_impl: Arc<ThreadsafeDeviceDiscoveryImpl>,
+        shutdown: &ShutdownSignal,
     ) -> Result<()> {
-        device_discovery_impl
-            .discover()
-            .context("error sending discovery packet")?;
-
-        loop {
-            let device_discovery_impl = Arc::clone(&device_discovery_impl);
-
-            match device_discovery_impl.poll() {
-                Ok(x) => {
-                    let data = Arc::clone(&data);
+        while !shutdown.is_triggered() {
+            match device_discovery_impl.poll(RECEIVE_TIMEOUT) {
+                Ok(Some(x)) => {
                     let mut data = data.lock().unwrap();
-                    data.register_device(&x);
+                    data.with_registry(|registry| registry.register_device(&x));
                 }
+                Ok(None) => {}
                 Err(err) => {
-                    println!("Error processing discovery reply: {}", err);
-                    continue;
+                    tracing::warn!(error = %err, "error processing discovery reply");
                 }
             };
         }
+
+        Ok(())
+    }
+
+    fn search_thread(
+        _data: Arc<std::sync::Mutex<DeviceManagerData>>,
+        search_thread_data: Arc<SearchThreadData>,
+        shutdown: &ShutdownSignal,
+    ) -> Result<()> {
+        let requests = search_thread_data.requests.lock().unwrap();
+
+        loop {
+            search_thread_data.device_discovery_impl.discover()?;
+
+            // Wait for the next periodic search, or for someone to call
+            // rediscover(). The sender lives in DeviceManagerData, so the
+            // channel never gets disconnected while this thread runs;
+            // shutdown() sends a request to wake us up.
+            let disconnected = match search_thread_data.interval {
+                Some(interval) => matches!(
+                    requests.recv_timeout(interval),
+                    Err(std::sync::mpsc::RecvTimeoutError::Disconnected)
+                ),
+                None => requests.recv().is_err(),
+            };
+
+            if shutdown.is_triggered() {
+                return Ok(());
+            }
+
+            if disconnected {
+                return Err(Error::Shutdown);
+            }
+        }
+    }
+
+    fn liveness_thread(
+        data: Arc<std::sync::Mutex<DeviceManagerData>>,
+        device_expiry: Arc<Duration>,
+        shutdown: &ShutdownSignal,
+    ) -> Result<()> {
+        let check_interval = (*device_expiry / 4).min(Duration::from_secs(5));
+
+        while !shutdown.sleep(check_interval) {
+            let mut data = data.lock().unwrap();
+            data.with_registry(|registry| registry.expire_devices(*device_expiry));
+        }
+
+        Ok(())
+    }
+
+    fn subscription_thread(
+        data: Arc<std::sync::Mutex<DeviceManagerData>>,
+        subscription_thread_data: Arc<SubscriptionThreadData>,
+        shutdown: &ShutdownSignal,
+    ) -> Result<()> {
+        while !shutdown.sleep(SUBSCRIPTION_CHECK_INTERVAL) {
+            let mut data = data.lock().unwrap();
+            data.send_hellos(
+                &subscription_thread_data.network_impl,
+                &subscription_thread_data.retry_policy,
+            );
+        }
+
+        Ok(())
     }
 
     fn packet_receiver_thread<F>(
         network_impl: Arc<ThreadsafeNetworkImpl>,
         port: u16,
+        shutdown: &ShutdownSignal,
         packet_func: F,
     ) -> Result<()>
     where
         F: Fn(SocketAddr, &protocol::Packet) -> Result<()>,
     {
-        let packet_receiver = network_impl
-            .packet_receiver(port)
-            .context("error creating packet receiver")?;
+        let packet_receiver = network_impl.packet_receiver(port)?;
 
-        loop {
-            match packet_receiver.receive_packet() {
-                Ok((from_addr, packet)) => {
+        while !shutdown.is_triggered() {
+            match packet_receiver.receive_packet(RECEIVE_TIMEOUT) {
+                Ok(Some((from_addr, packet))) => {
                     if let Err(err) = packet_func(from_addr, &packet) {
-                        println!("error handling packet from device {}: {}", from_addr, err);
+                        tracing::warn!(addr = %from_addr, port, error = %err, "error handling packet");
                     }
                 }
+                Ok(None) => {}
                 Err(err) => {
-                    println!("invalid packet: {}", err)
+                    tracing::warn!(port, error = %err, "invalid packet");
                 }
             };
         }
+
+        Ok(())
     }
 
     fn notification_thread(
         data: Arc<std::sync::Mutex<DeviceManagerData>>,
         network_impl: Arc<ThreadsafeNetworkImpl>,
+        shutdown: &ShutdownSignal,
     ) -> Result<()> {
         Self::packet_receiver_thread(
             network_impl,
             protocol::NOTIF_RECV_PORT,
+            shutdown,
             |from_addr, packet| {
-                let data = Arc::clone(&data);
                 let mut data = data.lock().unwrap();
                 data.handle_notification(from_addr, packet)
             },
@@ -274,139 +858,803 @@ impl DeviceManager {
     fn command_reply_thread(
         data: Arc<std::sync::Mutex<DeviceManagerData>>,
         network_impl: Arc<ThreadsafeNetworkImpl>,
+        shutdown: &ShutdownSignal,
     ) -> Result<()> {
         Self::packet_receiver_thread(
             network_impl,
             protocol::CMD_RESP_PORT,
+            shutdown,
             |from_addr, packet| {
-                let data = Arc::clone(&data);
                 let mut data = data.lock().unwrap();
                 data.handle_command_response(from_addr, packet)
             },
         )
     }
 
+    /// Stops the threads of the device manager and closes its sockets. The
+    /// channels returned by [`DeviceManager::listen`] get disconnected,
+    /// pending and later commands fail with [`Error::Shutdown`].
+    ///
+    /// Dropping the device manager shuts it down too.
+    pub fn shutdown(&self) {
+        self.shutdown.trigger();
+
+        {
+            let data = self.shared.data.lock().unwrap();
+            // wakes up the search thread
+            let _ = data.search_requests.send(());
+        }
+
+        let threads = std::mem::take(&mut *self.threads.lock().unwrap());
+
+        for thread in threads {
+            // a thread that panicked has stopped too
+            let _ = thread.join();
+        }
+
+        let mut data = self.shared.data.lock().unwrap();
+        data.event_listeners.clear();
+        data.sock_send = None;
+        data.registry.cancel_pending_replies();
+    }
+
+    /// Returns all the events of the device manager, see
+    /// [`DeviceManager::subscribe`] to only get some of them.
     pub fn listen(&self) -> std::sync::mpsc::Receiver<DeviceManagerEvent> {
         let (tx, rx) = std::sync::mpsc::channel();
 
-        let data = Arc::clone(&self.data);
+        let data = Arc::clone(&self.shared.data);
         let mut data = data.lock().unwrap();
-        data.event_listeners.push(tx);
+        data.event_listeners.push(Subscriber::channel(tx));
 
         rx
     }
 
-    pub fn fetch_info(&self, device_id: &str) -> Result<()> {
-        let data = Arc::clone(&self.data);
-        let data = data.lock().unwrap();
-        data.send_packet(device_id, &commands::DeviceName::fetch())?;
-        data.send_packet(device_id, &commands::Volume::fetch())?;
-        data.send_packet(device_id, &commands::PlayControl::fetch())?;
-        data.send_packet(device_id, &commands::PlayInfo::fetch())?;
-        data.send_packet(device_id, &commands::ChargingState::fetch())?;
-        data.send_packet(device_id, &commands::BatteryLevel::fetch())?;
-        data.send_packet(device_id, &commands::PreChannel::fetch())?;
+    /// Returns the events `filter` lets through. Unlike with
+    /// [`DeviceManager::listen`], the number of queued events can be bounded,
+    /// and the subscription can be cancelled from any thread.
+    pub fn subscribe(&self, filter: EventFilter) -> Subscription {
+        self.shared.subscribe(filter)
+    }
 
-        Ok(())
+    /// Returns the devices discovered so far.
+    pub fn devices(&self) -> Vec<Device> {
+        self.shared.devices()
     }
 
-    pub fn set_volume(&self, device_id: &str, volume: u8) -> Result<()> {
-        let data = Arc::clone(&self.data);
-        let data = data.lock().unwrap();
-        data.send_packet(device_id, &commands::Volume::set(volume.clamp(0, 100)))
+    pub fn device(&self, device_id: &str) -> Option<Device> {
+        self.shared.device(device_id)
     }
 
-    pub fn send_packet(&self, device_id: &str, packet: &protocol::Packet) -> Result<()> {
-        let data = Arc::clone(&self.data);
-        let data = data.lock().unwrap();
-        data.send_packet(device_id, packet)
+    pub fn fetch_info(&self, device_id: &str) -> Result<()> {
+        self.shared.fetch_info(device_id)
     }
-}
 
-impl DeviceManagerData {
-    fn send_event(&mut self, event: DeviceManagerEvent) {
-        let mut failed_send_indices = vec![];
+    pub fn set_volume(&self, device_id: &str, volume: u8) -> Result<DeliveryOutcome> {
+        self.shared.set_volume(device_id, volume)
+    }
 
-        for (i, tx) in self.event_listeners.iter().enumerate() {
-            if tx.send(event.clone()).is_err() {
-                failed_send_indices.push(i);
-            }
-        }
+    pub fn send_packet(&self, device_id: &str, packet: &protocol::Packet) -> Result<()> {
+        self.shared.send_packet(device_id, packet)
+    }
 
-        for idx in failed_send_indices.iter().rev() {
-            self.event_listeners.remove(*idx);
-        }
+    /// Sends a packet to a device and waits for the reply carrying
+    /// `reply_command_id`. Fails with [`Error::Timeout`] if no such reply
+    /// arrives within `timeout`, or with [`Error::DeviceStatus`] if the
+    /// device rejects the packet.
+    ///
+    /// The packet is sent with a sequence of its own, so that its reply can
+    /// be told apart from the replies to other requests.
+    pub fn request(
+        &self,
+        device_id: &str,
+        packet: &protocol::Packet,
+        reply_command_id: u16,
+        timeout: Duration,
+    ) -> Result<protocol::Packet> {
+        self.shared
+            .request(device_id, packet, reply_command_id, timeout)
     }
 
-    fn register_device(&mut self, info: &discovery_reply::DiscoveryReply) {
-        if self.devices.contains_key(&info.device_id) {
-            return;
-        }
+    /// Fetches the current value of `C` from a device, waiting at most
+    /// `timeout` for the reply.
+    pub fn get<C: Command>(&self, device_id: &str, timeout: Duration) -> Result<C::ResponseData> {
+        self.shared.get::<C>(device_id, timeout)
+    }
 
-        let device = Device::new(info.device_id.clone(), info.ip_address);
-        self.devices.insert(device.id.clone(), device.clone());
+    /// Sets the value of `C` on a device, retransmitting the command
+    /// according to the configured [`RetryPolicy`] until the device confirms
+    /// it with a matching notification or reply.
+    ///
+    /// Commands that have no notification are followed by a fetch, whose
+    /// reply then serves as the confirmation. Fails with a
+    /// [`Error::DeviceStatus`] if the device rejects the command.
+    pub fn set<C: Command + 'static>(
+        &self,
+        device_id: &str,
+        value: C::RequestData,
+    ) -> Result<DeliveryOutcome> {
+        self.shared.set::<C>(device_id, value)
+    }
 
-        self.send_event(DeviceManagerEvent::DeviceDiscovered(device));
+    /// Returns a handle on the device that [`Device::matches`] `query`,
+    /// among the ones discovered so far.
+    pub fn handle(&self, query: &str) -> Result<DeviceHandle> {
+        self.devices()
+            .into_iter()
+            .find(|device| device.matches(query))
+            .map(|device| DeviceHandle::new(self.shared.clone(), device.id))
+            .ok_or_else(|| Error::UnknownDevice(query.to_owned()))
     }
 
-    fn send_packet(&self, device_id: &str, packet: &protocol::Packet) -> Result<()> {
-        match self.devices.get(device_id) {
-            Some(device) => {
-                self.sock_send.send_packet(
-                    packet,
-                    SocketAddr::new(device.addr, protocol::CMD_SEND_PORT),
-                )?;
-                Ok(())
-            }
-            None => Err(anyhow!("unknown device ID")),
-        }
+    /// Sends a new discovery request right away, instead of waiting for the
+    /// next periodic one.
+    pub fn rediscover(&self) -> Result<()> {
+        let data = Arc::clone(&self.shared.data);
+        let data = data.lock().unwrap();
+        data.search_requests.send(()).map_err(|_| Error::Shutdown)
     }
+}
 
-    fn handle_notification(&mut self, addr: SocketAddr, packet: &protocol::Packet) -> Result<()> {
-        let notif_ack_addr = SocketAddr::new(addr.ip(), protocol::NOTIF_ACK_PORT);
-        self.sock_send
-            .send_packet(
-                &protocol::Packet {
-                    command: 2,
-                    command_type: packet.command_type,
-                    command_data: None,
-                },
-                notif_ack_addr,
-            )
-            .context("error acknowledging notification")?;
+impl DeviceManagerShared {
+    pub(crate) fn subscribe(&self, filter: EventFilter) -> Subscription {
+        let (subscriber, subscription) = Subscriber::new(filter);
 
-        println!(
-            "handling notification packet for {}: {}",
-            addr,
-            commands::format_notification(packet)
-        );
+        let data = Arc::clone(&self.data);
+        let mut data = data.lock().unwrap();
 
-        self.handle_incoming_packet(addr, packet)
+        // once shut down, dropping the subscriber disconnects the
+        // subscription right away
+        if data.sock_send.is_some() {
+            data.event_listeners.retain(Subscriber::is_active);
+            data.event_listeners.push(subscriber);
+        }
+
+        subscription
     }
 
-    fn handle_command_response(
-        &mut self,
-        addr: SocketAddr,
-        packet: &protocol::Packet,
-    ) -> Result<()> {
-        println!(
-            "handling reply packet for {}: {}",
-            addr,
-            commands::format_reply(packet)
-        );
+    pub(crate) fn devices(&self) -> Vec<Device> {
+        let data = Arc::clone(&self.data);
+        let data = data.lock().unwrap();
+        data.registry.devices()
+    }
 
-        self.handle_incoming_packet(addr, packet)
+    pub(crate) fn device(&self, device_id: &str) -> Option<Device> {
+        let data = Arc::clone(&self.data);
+        let data = data.lock().unwrap();
+        data.registry.device(device_id)
     }
 
-    fn handle_incoming_packet(
-        &mut self,
-        addr: SocketAddr,
-        packet: &protocol::Packet,
+    pub(crate) fn fetch_info(&self, device_id: &str) -> Result<()> {
+        let data = Arc::clone(&self.data);
+        let data = data.lock().unwrap();
+
+        for packet in info_fetches() {
+            data.send_packet(device_id, &packet)?;
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn set_volume(&self, device_id: &str, volume: u8) -> Result<DeliveryOutcome> {
+        self.set::<commands::Volume>(device_id, volume.clamp(0, 100))
+    }
+
+    pub(crate) fn send_packet(&self, device_id: &str, packet: &protocol::Packet) -> Result<()> {
+        let data = Arc::clone(&self.data);
+        let data = data.lock().unwrap();
+        data.send_packet(device_id, packet)
+    }
+
+    pub(crate) fn request(
+        &self,
+        device_id: &str,
+        packet: &protocol::Packet,
+        reply_command_id: u16,
+        timeout: Duration,
+    ) -> Result<protocol::Packet> {
+        let (tx, rx) = std::sync::mpsc::channel();
+
+        let pending_reply_id = {
+            let data = Arc::clone(&self.data);
+            let mut data = data.lock().unwrap();
+            let sequence = data.registry.next_sequence();
+            let pending_reply_id = data.registry.add_pending_reply(
+                device_id,
+                sequence,
+                Box::new(move |reply| reply.command == reply_command_id),
+                Box::new(move |reply| {
+                    let _ = tx.send(reply);
+                }),
+            );
+
+            let packet = protocol::Packet {
+                sequence,
+                ..packet.clone()
+            };
+
+            if let Err(err) = data.send_packet(device_id, &packet) {
+                data.registry.remove_pending_reply(pending_reply_id);
+                return Err(err);
+            }
+
+            pending_reply_id
+        };
+
+        let reply = rx.recv_timeout(timeout).map_err(|err| {
+            let data = Arc::clone(&self.data);
+            let mut data = data.lock().unwrap();
+            data.registry.remove_pending_reply(pending_reply_id);
+
+            match err {
+                std::sync::mpsc::RecvTimeoutError::Timeout => Error::Timeout,
+                std::sync::mpsc::RecvTimeoutError::Disconnected => Error::Shutdown,
+            }
+        })?;
+
+        check_status(&reply)?;
+        Ok(reply)
+    }
+
+    pub(crate) fn get<C: Command>(
+        &self,
+        device_id: &str,
+        timeout: Duration,
+    ) -> Result<C::ResponseData> {
+        let reply = self.request(device_id, &C::fetch(), C::GET_REPLY_COMMAND_ID, timeout)?;
+        C::unmarshal_data(reply.command_data.as_deref().unwrap_or_default())
+    }
+
+    pub(crate) fn set<C: Command + 'static>(
+        &self,
+        device_id: &str,
+        value: C::RequestData,
+    ) -> Result<DeliveryOutcome> {
+        let (packets, matches) = set_request::<C>(value)?;
+        self.send_with_retries(device_id, &packets, matches)
+    }
+
+    fn send_with_retries(
+        &self,
+        device_id: &str,
+        packets: &[protocol::Packet],
+        matches: PacketMatcher,
+    ) -> Result<DeliveryOutcome> {
+        let (tx, rx) = std::sync::mpsc::channel();
+
+        // retransmissions keep the sequence, since they are the same request
+        let (pending_reply_id, packets) = {
+            let data = Arc::clone(&self.data);
+            let mut data = data.lock().unwrap();
+            let sequence = data.registry.next_sequence();
+            let packets: Vec<protocol::Packet> = packets
+                .iter()
+                .map(|packet| protocol::Packet {
+                    sequence,
+                    ..packet.clone()
+                })
+                .collect();
+
+            (
+                data.registry.add_pending_reply(
+                    device_id,
+                    sequence,
+                    matches,
+                    Box::new(move |reply| {
+                        let _ = tx.send(reply);
+                    }),
+                ),
+                packets,
+            )
+        };
+
+        let result = (|| {
+            for attempt in 1..=self.retry_policy.attempts.max(1) {
+                {
+                    let data = Arc::clone(&self.data);
+                    let data = data.lock().unwrap();
+
+                    for packet in &packets {
+                        data.send_packet(device_id, packet)?;
+                    }
+                }
+
+                match rx.recv_timeout(self.retry_policy.attempt_timeout(attempt)) {
+                    Ok(reply) => {
+                        check_status(&reply)?;
+                        return Ok(DeliveryOutcome::Delivered { attempts: attempt });
+                    }
+                    Err(std::sync::mpsc::RecvTimeoutError::Disconnected) => {
+                        return Err(Error::Shutdown)
+                    }
+                    Err(std::sync::mpsc::RecvTimeoutError::Timeout) => {}
+                }
+            }
+
+            Ok(DeliveryOutcome::Failed {
+                attempts: self.retry_policy.attempts.max(1),
+            })
+        })();
+
+        let data = Arc::clone(&self.data);
+        let mut data = data.lock().unwrap();
+        data.registry.remove_pending_reply(pending_reply_id);
+
+        result
+    }
+}
+
+impl Drop for DeviceManager {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+/// The fetches sent by `fetch_info`, whose replies fill in the device state
+pub(crate) fn info_fetches() -> [protocol::Packet; 7] {
+    [
+        commands::DeviceName::fetch(),
+        commands::Volume::fetch(),
+        commands::PlayControl::fetch(),
+        commands::PlayInfo::fetch(),
+        commands::ChargingState::fetch(),
+        commands::BatteryLevel::fetch(),
+        commands::PreChannel::fetch(),
+    ]
+}
+
+/// Builds the packets setting `C` to `value`, and the matcher recognizing
+/// the notification or reply that confirms it. Fails if `value` can't be
+/// sent to the device.
+///
+/// Commands that have no notification are followed by a fetch, whose reply
+/// then serves as the confirmation.
+pub(crate) fn set_request<C: Command + 'static>(
+    value: C::RequestData,
+) -> Result<(Vec<protocol::Packet>, PacketMatcher)> {
+    let set_packet = C::set(value)?;
+    let sent_data = set_packet.command_data.clone().unwrap_or_default();
+
+    let mut packets = vec![set_packet];
+
+    if C::NOTIFY_ID == 0 {
+        packets.push(C::fetch());
+    }
+
+    let matches: PacketMatcher = Box::new(move |packet| {
+        let is_confirmation = (C::NOTIFY_ID != 0 && packet.command == C::NOTIFY_ID)
+            || packet.command == C::GET_REPLY_COMMAND_ID;
+
+        is_confirmation
+            && C::confirms_set(
+                &sent_data,
+                packet.command_data.as_deref().unwrap_or_default(),
+            )
+    });
+
+    Ok((packets, matches))
+}
+
+impl DeviceManagerData {
+    /// Runs `f` on the registry, then delivers the events it queued.
+    fn with_registry<R>(&mut self, f: impl FnOnce(&mut DeviceRegistry) -> R) -> R {
+        let result = f(&mut self.registry);
+
+        for event in self.registry.take_events() {
+            self.event_listeners
+                .retain(|subscriber| subscriber.deliver(&event));
+        }
+
+        result
+    }
+
+    /// Registers for notifications with the devices that are due for it.
+    fn send_hellos(&mut self, network_impl: &ThreadsafeNetworkImpl, retry_policy: &RetryPolicy) {
+        for (device_id, addr) in self.with_registry(|registry| registry.hellos_due(retry_policy)) {
+            let result = network_impl.local_addr_for(addr).and_then(|local_addr| {
+                self.sock_send()?.send_packet(
+                    &commands::hello(&local_addr)?,
+                    SocketAddr::new(addr, protocol::CMD_SEND_PORT),
+                )
+            });
+
+            if let Err(err) = result {
+                tracing::warn!(
+                    device_id = device_id.as_str(),
+                    %addr,
+                    error = %err,
+                    "error registering for notifications"
+                );
+            }
+        }
+    }
+
+    fn sock_send(&self) -> Result<&(dyn PacketSender + Send)> {
+        self.sock_send.as_deref().ok_or(Error::Shutdown)
+    }
+
+    fn send_packet(&self, device_id: &str, packet: &protocol::Packet) -> Result<()> {
+        let addr = self.registry.device_addr(device_id)?;
+
+        log_packet(Some(device_id), addr, Direction::Command, packet);
+
+        self.sock_send()?
+            .send_packet(packet, SocketAddr::new(addr, protocol::CMD_SEND_PORT))?;
+        Ok(())
+    }
+
+    fn handle_notification(&mut self, addr: SocketAddr, packet: &protocol::Packet) -> Result<()> {
+        let ack = protocol::NotificationAck::from_packet(packet);
+        self.sock_send()?.send_packet(
+            &ack.packet(),
+            SocketAddr::new(addr.ip(), protocol::NOTIF_ACK_PORT),
+        )?;
+
+        self.with_registry(|registry| registry.handle_notification(addr, packet))
+    }
+
+    fn handle_command_response(
+        &mut self,
+        addr: SocketAddr,
+        packet: &protocol::Packet,
     ) -> Result<()> {
-        if let Some(event) = self
+        self.with_registry(|registry| registry.handle_reply(addr, packet))
+    }
+}
+
+/// Logs a packet going to or coming from a device
+pub(crate) fn log_packet(
+    device_id: Option<&str>,
+    addr: IpAddr,
+    direction: Direction,
+    packet: &protocol::Packet,
+) {
+    tracing::debug!(
+        device_id,
+        %addr,
+        ?direction,
+        command = commands::command_name(packet.command).unwrap_or("unknown"),
+        command_id = packet.command,
+        sequence = packet.sequence,
+        "{}",
+        match direction {
+            Direction::Reply => commands::format_reply(packet),
+            Direction::Notification => commands::format_notification(packet),
+            _ => commands::format_command(packet),
+        }
+    );
+}
+
+/// Sends a reply to whoever is waiting for it
+pub(crate) type ReplySender = Box<dyn Fn(protocol::Packet) + Send>;
+
+struct PendingReply {
+    id: u64,
+    device_id: String,
+    /// Sequence of the request, echoed by the replies to it
+    sequence: u16,
+    matches: PacketMatcher,
+    tx: ReplySender,
+}
+
+/// What a device manager knows about devices, and the requests waiting for
+/// their replies. It does no I/O: device managers feed it what they receive,
+/// send what it asks them to, and deliver the events it queues.
+pub(crate) struct DeviceRegistry {
+    devices: std::collections::HashMap<String, Device>,
+    pending_replies: Vec<PendingReply>,
+    next_pending_reply_id: u64,
+    next_sequence: u16,
+    subscription_interval: Duration,
+    events: Vec<DeviceManagerEvent>,
+}
+
+impl DeviceRegistry {
+    pub(crate) fn new(subscription_interval: Duration) -> DeviceRegistry {
+        DeviceRegistry {
+            devices: std::collections::HashMap::new(),
+            pending_replies: vec![],
+            next_pending_reply_id: 0,
+            next_sequence: 1,
+            subscription_interval,
+            events: vec![],
+        }
+    }
+
+    /// Returns the events queued since the last call
+    pub(crate) fn take_events(&mut self) -> Vec<DeviceManagerEvent> {
+        std::mem::take(&mut self.events)
+    }
+
+    fn send_event(&mut self, event: DeviceManagerEvent) {
+        self.events.push(event);
+    }
+
+    pub(crate) fn devices(&self) -> Vec<Device> {
+        self.devices.values().cloned().collect()
+    }
+
+    pub(crate) fn device(&self, device_id: &str) -> Option<Device> {
+        self.devices.get(device_id).cloned()
+    }
+
+    pub(crate) fn device_addr(&self, device_id: &str) -> Result<IpAddr> {
+        self.devices
+            .get(device_id)
+            .map(|device| device.addr)
+            .ok_or_else(|| Error::UnknownDevice(device_id.to_owned()))
+    }
+
+    pub(crate) fn register_device(&mut self, info: &discovery_reply::DiscoveryReply) {
+        if let Some(device) = self.devices.get_mut(&info.device_id) {
+            let changed = device.update_discovery_info(info);
+
+            self.mark_device_seen(&info.device_id);
+
+            if changed {
+                let device = self.devices[&info.device_id].clone();
+                self.send_event(DeviceManagerEvent::DeviceUpdated(device));
+            }
+
+            return;
+        }
+
+        let device = Device::new(info);
+        self.devices.insert(device.id.clone(), device.clone());
+
+        self.send_event(DeviceManagerEvent::DeviceDiscovered(device));
+    }
+
+    fn device_id_for_addr(&self, addr: IpAddr) -> Option<String> {
+        self.devices
+            .values()
+            .find(|device| device.addr == addr)
+            .map(|device| device.id.clone())
+    }
+
+    fn mark_device_seen(&mut self, device_id: &str) {
+        let device = match self.devices.get_mut(device_id) {
+            Some(device) => device,
+            None => return,
+        };
+
+        device.last_seen = Instant::now();
+
+        if !device.online {
+            // the device may have restarted and forgotten about us
+            device.online = true;
+            device.subscription = SubscriptionStatus::Pending;
+            device.hello_attempts = 0;
+            device.next_hello = Instant::now();
+
+            let device = device.clone();
+            self.send_event(DeviceManagerEvent::DeviceReappeared(device));
+        }
+    }
+
+    pub(crate) fn expire_devices(&mut self, device_expiry: Duration) {
+        let lost_devices: Vec<Device> = self
             .devices
             .values_mut()
+            .filter(|device| device.online && device.last_seen.elapsed() > device_expiry)
+            .map(|device| {
+                device.online = false;
+                device.clone()
+            })
+            .collect();
+
+        for device in lost_devices {
+            self.send_event(DeviceManagerEvent::DeviceLost(device));
+        }
+    }
+
+    /// Returns the ID and address of the devices that are due for a
+    /// [`commands::hello`], and gives up until the next periodic registration
+    /// on the ones that did not reply after the number of attempts allowed by
+    /// `retry_policy`. Devices known to send us notifications stay
+    /// subscribed.
+    pub(crate) fn hellos_due(&mut self, retry_policy: &RetryPolicy) -> Vec<(String, IpAddr)> {
+        let now = Instant::now();
+        let mut due = vec![];
+        let mut updated_devices = vec![];
+
+        for device in self
+            .devices
+            .values_mut()
+            .filter(|device| device.online && device.next_hello <= now)
+        {
+            if device.hello_attempts >= retry_policy.attempts.max(1) {
+                device.hello_attempts = 0;
+                device.next_hello = now + self.subscription_interval;
+
+                if device.subscription == SubscriptionStatus::Pending {
+                    device.subscription = SubscriptionStatus::Unknown;
+                    updated_devices.push(device.clone());
+                }
+
+                continue;
+            }
+
+            device.hello_attempts += 1;
+            device.next_hello = now
+                .checked_add(retry_policy.attempt_timeout(device.hello_attempts))
+                .unwrap_or(now + self.subscription_interval);
+
+            due.push((device.id.clone(), device.addr));
+        }
+
+        for device in updated_devices {
+            self.send_event(DeviceManagerEvent::DeviceUpdated(device));
+        }
+
+        due
+    }
+
+    /// Records that a device sends us notifications, until the next periodic
+    /// registration.
+    fn mark_device_subscribed(&mut self, device_id: &str) {
+        let device = match self.devices.get_mut(device_id) {
+            Some(device) => device,
+            None => return,
+        };
+
+        device.hello_attempts = 0;
+        device.next_hello = Instant::now() + self.subscription_interval;
+
+        if device.subscription != SubscriptionStatus::Subscribed {
+            device.subscription = SubscriptionStatus::Subscribed;
+            let device = device.clone();
+            self.send_event(DeviceManagerEvent::DeviceUpdated(device));
+        }
+    }
+
+    /// Returns the sequence to send the next request with
+    pub(crate) fn next_sequence(&mut self) -> u16 {
+        let sequence = self.next_sequence;
+        self.next_sequence = self.next_sequence.wrapping_add(1);
+        sequence
+    }
+
+    pub(crate) fn add_pending_reply(
+        &mut self,
+        device_id: &str,
+        sequence: u16,
+        matches: PacketMatcher,
+        tx: ReplySender,
+    ) -> u64 {
+        let id = self.next_pending_reply_id;
+        self.next_pending_reply_id += 1;
+
+        self.pending_replies.push(PendingReply {
+            id,
+            device_id: device_id.to_owned(),
+            sequence,
+            matches,
+            tx,
+        });
+
+        id
+    }
+
+    pub(crate) fn remove_pending_reply(&mut self, id: u64) {
+        self.pending_replies
+            .retain(|pending_reply| pending_reply.id != id);
+    }
+
+    /// Drops all pending replies, disconnecting whoever waits for them
+    pub(crate) fn cancel_pending_replies(&mut self) {
+        self.pending_replies.clear();
+    }
+
+    /// Hands `packet` over to the requests waiting for it. `is_reply` is
+    /// false for notifications, whose sequence has nothing to do with the
+    /// requests.
+    fn complete_pending_replies(
+        &mut self,
+        addr: SocketAddr,
+        packet: &protocol::Packet,
+        is_reply: bool,
+    ) {
+        let device_id = match self
+            .devices
+            .values()
             .find(|device| device.addr == addr.ip())
+        {
+            Some(device) => device.id.clone(),
+            None => return,
+        };
+
+        // A reply echoing the sequence of a request answers that request
+        // only, even when it reports an error. Replies that don't are
+        // matched on their content alone.
+        let correlated = is_reply
+            && self.pending_replies.iter().any(|pending_reply| {
+                pending_reply.device_id == device_id && pending_reply.sequence == packet.sequence
+            });
+
+        self.pending_replies.retain(|pending_reply| {
+            let matches = if correlated {
+                pending_reply.sequence == packet.sequence
+                    && (matches!(packet.status, protocol::PacketStatus::Error(_))
+                        || (pending_reply.matches)(packet))
+            } else {
+                (pending_reply.matches)(packet)
+            };
+
+            if pending_reply.device_id != device_id || !matches {
+                return true;
+            }
+
+            // the requester might have timed out already, in which case
+            // the reply goes nowhere
+            (pending_reply.tx)(packet.clone());
+            false
+        });
+    }
+
+    pub(crate) fn handle_notification(
+        &mut self,
+        addr: SocketAddr,
+        packet: &protocol::Packet,
+    ) -> Result<()> {
+        log_packet(
+            self.device_id_for_addr(addr.ip()).as_deref(),
+            addr.ip(),
+            Direction::Notification,
+            packet,
+        );
+
+        // receiving notifications is the proof that we are registered
+        if let Some(device_id) = self.device_id_for_addr(addr.ip()) {
+            self.mark_device_subscribed(&device_id);
+        }
+
+        self.complete_pending_replies(addr, packet, false);
+        self.handle_incoming_packet(addr, packet)
+    }
+
+    pub(crate) fn handle_reply(
+        &mut self,
+        addr: SocketAddr,
+        packet: &protocol::Packet,
+    ) -> Result<()> {
+        log_packet(
+            self.device_id_for_addr(addr.ip()).as_deref(),
+            addr.ip(),
+            Direction::Reply,
+            packet,
+        );
+
+        if packet.command == commands::HELLO_COMMAND_ID {
+            if let Some(device_id) = self.device_id_for_addr(addr.ip()) {
+                self.mark_device_subscribed(&device_id);
+            }
+        }
+
+        self.complete_pending_replies(addr, packet, true);
+        self.handle_incoming_packet(addr, packet)
+    }
+
+    fn handle_incoming_packet(
+        &mut self,
+        addr: SocketAddr,
+        packet: &protocol::Packet,
+    ) -> Result<()> {
+        let device_id = match self.device_id_for_addr(addr.ip()) {
+            Some(device_id) => device_id,
+            None => return Ok(()),
+        };
+
+        self.mark_device_seen(&device_id);
+
+        // error replies carry nothing to update the device with
+        if let protocol::PacketStatus::Error(_) = packet.status {
+            return Ok(());
+        }
+
+        if let Some(event) = self
+            .devices
+            .get_mut(&device_id)
             .map(|device| Self::handle_device_update(device, packet))
             .unwrap_or(Ok(None))?
         {
@@ -420,113 +1668,585 @@ impl DeviceManagerData {
         device: &mut Device,
         packet: &protocol::Packet,
     ) -> Result<Option<DeviceManagerEvent>> {
-        match packet.command {
-            commands::DeviceName::GET_REPLY_COMMAND_ID => {
-                device.name = Some(commands::DeviceName::unmarshal_data(
-                    packet.command_data.as_ref().unwrap_or(&vec![]),
-                )?);
-                Ok(Some(DeviceManagerEvent::DeviceUpdated(device.clone())))
-            }
-            commands::Volume::GET_REPLY_COMMAND_ID => {
-                device.volume = Some(commands::Volume::unmarshal_data(
-                    packet.command_data.as_ref().unwrap_or(&vec![]),
-                )?);
-                Ok(Some(DeviceManagerEvent::DeviceUpdated(device.clone())))
-            }
-            commands::PlayControl::GET_REPLY_COMMAND_ID => {
-                device.play_status = Some(commands::PlayControl::unmarshal_data(
-                    packet.command_data.as_ref().unwrap_or(&vec![]),
-                )?);
-                Ok(Some(DeviceManagerEvent::DeviceUpdated(device.clone())))
-            }
-            commands::PlayInfo::GET_REPLY_COMMAND_ID => {
-                device.play_info = Some(commands::PlayInfo::unmarshal_data(
-                    packet.command_data.as_ref().unwrap_or(&vec![]),
-                )?);
-                Ok(Some(DeviceManagerEvent::DeviceUpdated(device.clone())))
-            }
-            commands::ChargingState::GET_REPLY_COMMAND_ID => {
-                device.charging_state = Some(commands::ChargingState::unmarshal_data(
-                    packet.command_data.as_ref().unwrap_or(&vec![]),
-                )?);
-                Ok(Some(DeviceManagerEvent::DeviceUpdated(device.clone())))
-            }
-            commands::BatteryLevel::GET_REPLY_COMMAND_ID | commands::BatteryLevel::NOTIFY_ID => {
-                device.battery_level = Some(commands::BatteryLevel::unmarshal_data(
-                    packet.command_data.as_ref().unwrap_or(&vec![]),
-                )?);
-                Ok(Some(DeviceManagerEvent::DeviceUpdated(device.clone())))
-            }
-            commands::PreChannel::GET_COMMAND_ID => {
-                device.pre_channels = Some(commands::PreChannel::unmarshal_data(
-                    packet.command_data.as_ref().unwrap_or(&vec![]),
-                )?);
-                Ok(Some(DeviceManagerEvent::DeviceUpdated(device.clone())))
+        Ok(commands::update_device(device, packet)?.map(|change| {
+            DeviceManagerEvent::DevicePropertyChanged {
+                device_id: device.id.clone(),
+                property: change.new.property(),
+                old: change.old,
+                new: change.new,
             }
-            _ => Ok(None),
-        }
+        }))
     }
 }
 
+/// Binds a socket to `addr` and `port` that other sockets, in this process
+/// or in others, can bind to as well.
+pub fn reusable_socket(addr: Ipv4Addr, port: u16) -> Result<UdpSocket> {
+    net2::UdpBuilder::new_v4()
+        .and_then(|builder| {
+            builder
+                .reuse_address(true)?
+                .reuse_port(true)?
+                .bind((addr, port))
+        })
+        .with_context(|| format!("error creating socket on {}:{}", addr, port))
+}
+
 struct RealNetworkImpl;
 
 impl NetworkImpl for RealNetworkImpl {
     fn packet_sender(&self) -> Result<Box<dyn PacketSender + Send>> {
-        let sock = net2::UdpBuilder::new_v4()?
-            .reuse_address(true)?
-            .reuse_port(true)?
-            .bind((ADDR_ANY, 0))?;
-        Ok(Box::new(sock))
+        Ok(Box::new(reusable_socket(ADDR_ANY, 0)?))
     }
 
     fn packet_receiver(&self, port: u16) -> Result<Box<dyn PacketReceiver + Send>> {
-        let sock = net2::UdpBuilder::new_v4()?
-            .reuse_address(true)?
-            .reuse_port(true)?
-            .bind((ADDR_ANY, port))
-            .context("error creating socket")?;
-
-        Ok(Box::new(sock))
+        Ok(Box::new(reusable_socket(ADDR_ANY, port)?))
     }
 }
 
+pub(crate) const SSDP_SEARCH_REQUEST: &str = "M-SEARCH * HTTP/1.1";
+
 struct SSDPDiscovery {
     sock: UdpSocket,
 }
 
-const SSDP_MULTICAST_PORT: u16 = 1800; // for some reason not the standard one
-
 impl SSDPDiscovery {
     fn new() -> Result<SSDPDiscovery> {
-        let sock = net2::UdpBuilder::new_v4()?
-            .reuse_address(true)?
-            .reuse_port(true)?
-            .bind((ADDR_ANY, SSDP_MULTICAST_PORT))
-            .context("error creating socket")?;
-
-        Ok(SSDPDiscovery { sock })
+        Ok(SSDPDiscovery {
+            sock: reusable_socket(ADDR_ANY, protocol::SSDP_MULTICAST_PORT)?,
+        })
     }
 }
 
 impl DeviceDiscoveryImpl for SSDPDiscovery {
     fn discover(&self) -> Result<()> {
-        const SSDP_MULTICAST_ADDR: Ipv4Addr = Ipv4Addr::new(239, 255, 255, 250);
-        const SEARCH_REQUEST_BODY: &str = "M-SEARCH * HTTP/1.1";
-
-        self.sock.send_to(
-            SEARCH_REQUEST_BODY.as_bytes(),
-            SocketAddr::new(IpAddr::V4(SSDP_MULTICAST_ADDR), SSDP_MULTICAST_PORT),
-        )?;
+        self.sock
+            .send_to(
+                SSDP_SEARCH_REQUEST.as_bytes(),
+                SocketAddr::new(
+                    IpAddr::V4(protocol::SSDP_MULTICAST_ADDR),
+                    protocol::SSDP_MULTICAST_PORT,
+                ),
+            )
+            .context("error sending discovery packet")?;
         Ok(())
     }
 
-    fn poll(&self) -> Result<discovery_reply::DiscoveryReply> {
+    fn poll(&self, timeout: Duration) -> Result<Option<discovery_reply::DiscoveryReply>> {
+        // a zero timeout would mean blocking forever
+        self.sock
+            .set_read_timeout(Some(timeout.max(Duration::from_millis(1))))
+            .context("error setting discovery receive timeout")?;
+
         let mut recv_buffer = vec![0; 4096];
-        let (count, _) = self
-            .sock
-            .recv_from(&mut recv_buffer)
-            .context("error receiving discovery packet")?;
 
-        discovery_reply::DiscoveryReply::parse(&recv_buffer[..count])
+        loop {
+            let (count, _) = match self.sock.recv_from(&mut recv_buffer) {
+                Ok(x) => x,
+                Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                    return Ok(None)
+                }
+                Err(err) => return Err(err).context("error receiving discovery packet"),
+            };
+
+            // Search requests, ours or other clients', show up here as soon as
+            // something on this machine joined the SSDP group
+            if recv_buffer[..count].starts_with(b"M-SEARCH") {
+                continue;
+            }
+
+            return discovery_reply::DiscoveryReply::parse(&recv_buffer[..count]).map(Some);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fake;
+    use crate::subscription::LagPolicy;
+
+    fn discover_device(device_manager: &DeviceManager) -> Device {
+        let events = device_manager.listen();
+
+        match events.recv_timeout(Duration::from_secs(5)).unwrap() {
+            DeviceManagerEvent::DeviceDiscovered(device) => device,
+            event => panic!("unexpected event: {:?}", event),
+        }
+    }
+
+    fn discovery_reply() -> discovery_reply::DiscoveryReply {
+        discovery_reply::DiscoveryReply {
+            device_name: "Kitchen".to_owned(),
+            device_id: "0123456789ab".to_owned(),
+            device_state: "F,S,P".to_owned(),
+            port: protocol::CMD_SEND_PORT,
+            zone_id: String::new(),
+            creator: String::new(),
+            ip_address: IpAddr::V4(Ipv4Addr::new(192, 168, 1, 10)),
+            color_code: "2003".to_owned(),
+            firmware_version: "809;1,1;1,1".to_owned(),
+            stereo_pair_id: String::new(),
+        }
+    }
+
+    #[test]
+    fn update_discovery_info_test() {
+        let mut info = discovery_reply();
+
+        let mut device = Device::new(&info);
+        assert_eq!(device.advertised_name(), "Kitchen");
+        assert_eq!(device.firmware_version(), "809;1,1;1,1");
+        assert_eq!(device.color_code(), "2003");
+        assert_eq!(device.device_state(), "F,S,P");
+
+        assert!(!device.update_discovery_info(&info));
+
+        info.firmware_version = "812;1,1;1,1".to_owned();
+        info.stereo_pair_id = "pair".to_owned();
+        assert!(device.update_discovery_info(&info));
+        assert_eq!(device.firmware_version(), "812;1,1;1,1");
+        assert_eq!(device.stereo_pair_id(), "pair");
+    }
+
+    #[test]
+    fn event_json_test() {
+        let device = Device::new(&discovery_reply());
+
+        let json = serde_json::to_value(DeviceManagerEvent::DeviceDiscovered(device)).unwrap();
+
+        assert_eq!(json["event"], "DeviceDiscovered");
+        assert_eq!(json["data"]["id"], "0123456789ab");
+        assert_eq!(json["data"]["addr"], "192.168.1.10");
+        assert_eq!(json["data"]["volume"], serde_json::Value::Null);
+        assert_eq!(json["data"]["online"], true);
+
+        let json = serde_json::to_value(DeviceManagerEvent::DevicePropertyChanged {
+            device_id: "0123456789ab".to_owned(),
+            property: DeviceProperty::Volume,
+            old: None,
+            new: PropertyValue::Volume(40),
+        })
+        .unwrap();
+
+        assert_eq!(json["event"], "DevicePropertyChanged");
+        assert_eq!(json["data"]["property"], "Volume");
+        assert_eq!(json["data"]["old"], serde_json::Value::Null);
+        assert_eq!(json["data"]["new"], 40);
+    }
+
+    #[test]
+    fn device_update_test() {
+        let mut device = Device::new(&discovery_reply());
+        let mut update = |command: u16, data: &str| {
+            let packet = protocol::Packet {
+                command_type: commands::COMMAND_TYPE_SET,
+                command,
+                status: protocol::PacketStatus::Ok,
+                sequence: protocol::DEFAULT_SEQUENCE,
+                command_data: Some(data.as_bytes().to_vec()),
+            };
+
+            match DeviceRegistry::handle_device_update(&mut device, &packet).unwrap() {
+                Some(DeviceManagerEvent::DevicePropertyChanged {
+                    device_id,
+                    property,
+                    old,
+                    new,
+                }) => {
+                    assert_eq!(device_id, "0123456789ab");
+                    assert_eq!(property, new.property());
+                    Some((old, new))
+                }
+                Some(event) => panic!("unexpected event: {:?}", event),
+                None => None,
+            }
+        };
+
+        // replies and notifications use different IDs for the battery level
+        assert_eq!(
+            update(commands::BatteryLevel::GET_REPLY_COMMAND_ID, "40"),
+            Some((None, PropertyValue::BatteryLevel(40)))
+        );
+        assert_eq!(
+            update(commands::BatteryLevel::NOTIFY_ID, "35"),
+            Some((
+                Some(PropertyValue::BatteryLevel(40)),
+                PropertyValue::BatteryLevel(35)
+            ))
+        );
+        // an unchanged value is not an update
+        assert_eq!(update(commands::BatteryLevel::NOTIFY_ID, "35"), None);
+
+        assert!(update(commands::Power::NOTIFY_ID, "02").is_some());
+        assert!(update(commands::PowerMode::GET_REPLY_COMMAND_ID, "1").is_some());
+        assert!(update(commands::FirmwareUpdate::NOTIFY_ID, "0").is_some());
+        assert!(update(
+            commands::Capabilities::GET_REPLY_COMMAND_ID,
+            r#"{"capabilities": [{"name": "volume"}, {"name": "battery"}]}"#
+        )
+        .is_some());
+        assert_eq!(update(commands::HELLO_COMMAND_ID, "10.0.0.1,3333"), None);
+        assert_eq!(update(4242, ""), None);
+
+        assert_eq!(device.battery_level(), Some(35));
+        assert_eq!(device.power_state(), Some(PowerState::Sleep));
+        assert_eq!(device.power_mode().as_deref(), Some("1"));
+        assert_eq!(device.firmware_update().as_deref(), Some("0"));
+        assert_eq!(
+            device.capabilities(),
+            Some(vec!["volume".to_owned(), "battery".to_owned()])
+        );
+    }
+
+    #[test]
+    fn get_test() {
+        let device_manager = DeviceManager::new(fake::device_manager_config().unwrap()).unwrap();
+        let device = discover_device(&device_manager);
+
+        assert_eq!(
+            device_manager
+                .get::<commands::Volume>(&device.id(), Duration::from_secs(1))
+                .unwrap(),
+            35
+        );
+        assert_eq!(
+            device_manager
+                .get::<commands::DeviceName>(&device.id(), Duration::from_secs(1))
+                .unwrap(),
+            "Pretty name"
+        );
+    }
+
+    #[test]
+    fn get_timeout_test() {
+        let device_manager = DeviceManager::new(fake::device_manager_config().unwrap()).unwrap();
+        let device = discover_device(&device_manager);
+
+        // the fake device ignores commands it does not know about
+        let err = device_manager
+            .request(
+                &device.id(),
+                &protocol::Packet {
+                    command_type: commands::COMMAND_TYPE_FETCH,
+                    command: 4242,
+                    status: protocol::PacketStatus::Ok,
+                    sequence: protocol::DEFAULT_SEQUENCE,
+                    command_data: None,
+                },
+                4242,
+                Duration::from_millis(100),
+            )
+            .unwrap_err();
+        assert!(matches!(err, Error::Timeout));
+
+        assert!(matches!(
+            device_manager.get::<commands::Volume>("no-such-device", Duration::from_millis(100)),
+            Err(Error::UnknownDevice(_))
+        ));
+    }
+
+    #[test]
+    fn retry_policy_test() {
+        let policy = RetryPolicy {
+            attempts: 3,
+            timeout: Duration::from_millis(100),
+            backoff: 2,
+        };
+        assert_eq!(policy.attempt_timeout(1), Duration::from_millis(100));
+        assert_eq!(policy.attempt_timeout(3), Duration::from_millis(400));
+
+        let policy = RetryPolicy {
+            attempts: 100,
+            timeout: Duration::from_secs(u64::MAX / 2),
+            backoff: u32::MAX,
+        };
+        assert_eq!(policy.attempt_timeout(1), Duration::from_secs(u64::MAX / 2));
+        assert_eq!(policy.attempt_timeout(100), Duration::MAX);
+
+        let config = fake::device_manager_config().unwrap();
+        assert!(matches!(
+            config.with_retry_policy(RetryPolicy {
+                backoff: 0,
+                ..RetryPolicy::default()
+            }),
+            Err(Error::InvalidRetryPolicy(_))
+        ));
+    }
+
+    #[test]
+    fn set_test() {
+        let device_manager = DeviceManager::new(
+            fake::device_manager_config()
+                .unwrap()
+                .with_retry_policy(RetryPolicy {
+                    attempts: 2,
+                    timeout: Duration::from_millis(100),
+                    backoff: 2,
+                })
+                .unwrap(),
+        )
+        .unwrap();
+        let device = discover_device(&device_manager);
+
+        assert_eq!(
+            device_manager.set_volume(&device.id(), 42).unwrap(),
+            DeliveryOutcome::Delivered { attempts: 1 }
+        );
+
+        assert_eq!(
+            device_manager
+                .set::<commands::Power>(&device.id(), commands::PowerState::Sleep)
+                .unwrap(),
+            DeliveryOutcome::Delivered { attempts: 1 }
+        );
+
+        // the fake device ignores volume changes while asleep
+        assert_eq!(
+            device_manager.set_volume(&device.id(), 10).unwrap(),
+            DeliveryOutcome::Failed { attempts: 2 }
+        );
+    }
+
+    #[test]
+    fn rejected_request_test() {
+        let device_manager = DeviceManager::new(fake::device_manager_config().unwrap()).unwrap();
+        let device = discover_device(&device_manager);
+
+        let err = device_manager
+            .request(
+                &device.id(),
+                &protocol::Packet {
+                    command_data: Some(b"150".to_vec()),
+                    ..commands::Volume::set(0).unwrap()
+                },
+                commands::Volume::GET_REPLY_COMMAND_ID,
+                Duration::from_secs(1),
+            )
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            Error::DeviceStatus {
+                command: commands::Volume::SET_COMMAND_ID,
+                status: fake::INVALID_VALUE_STATUS,
+            }
+        ));
+
+        // the error reply leaves the device alone
+        assert_eq!(
+            device_manager
+                .get::<commands::Volume>(&device.id(), Duration::from_secs(1))
+                .unwrap(),
+            35
+        );
+    }
+
+    #[test]
+    fn shutdown_test() {
+        let device_manager = DeviceManager::new(fake::device_manager_config().unwrap()).unwrap();
+        let device = discover_device(&device_manager);
+        let events = device_manager.listen();
+
+        let start = Instant::now();
+        device_manager.shutdown();
+        assert!(start.elapsed() < Duration::from_secs(2));
+
+        assert!(matches!(
+            events.recv_timeout(Duration::from_secs(1)),
+            Err(std::sync::mpsc::RecvTimeoutError::Disconnected)
+        ));
+        assert!(matches!(
+            device_manager.get::<commands::Volume>(&device.id(), Duration::from_secs(1)),
+            Err(Error::Shutdown)
+        ));
+
+        // shutting down again, or dropping, is harmless
+        device_manager.shutdown();
+    }
+
+    #[test]
+    fn subscribe_test() {
+        let device_manager = DeviceManager::new(fake::device_manager_config().unwrap()).unwrap();
+        let device = discover_device(&device_manager);
+
+        let volume_changes = device_manager.subscribe(
+            EventFilter::all()
+                .with_devices([device.id()])
+                .with_kinds([EventKind::DevicePropertyChanged])
+                .with_capacity(8, LagPolicy::CoalescePerDevice),
+        );
+        let other_device = device_manager.subscribe(EventFilter::all().with_devices(["other"]));
+
+        device_manager.set_volume(&device.id(), 42).unwrap();
+
+        match volume_changes.recv_timeout(Duration::from_secs(5)).unwrap() {
+            DeviceManagerEvent::DevicePropertyChanged { new, .. } => {
+                assert_eq!(new, PropertyValue::Volume(42))
+            }
+            event => panic!("unexpected event: {:?}", event),
+        }
+        assert!(other_device.try_recv().is_err());
+
+        device_manager.shutdown();
+
+        assert_eq!(
+            volume_changes.recv_timeout(Duration::from_secs(1)).err(),
+            Some(std::sync::mpsc::RecvTimeoutError::Disconnected)
+        );
+    }
+
+    #[test]
+    fn liveness_test() {
+        let device_manager = DeviceManager::new(
+            fake::device_manager_config()
+                .unwrap()
+                .with_device_expiry(Duration::from_millis(200))
+                .with_discovery_interval(None),
+        )
+        .unwrap();
+        let events = device_manager.listen();
+        let device = discover_device(&device_manager);
+
+        // the fake device only announces itself once, and stays silent
+        // unless spoken to
+        loop {
+            match events.recv_timeout(Duration::from_secs(5)).unwrap() {
+                DeviceManagerEvent::DeviceLost(lost) => {
+                    assert_eq!(lost.id(), device.id());
+                    assert!(!lost.is_online());
+                    break;
+                }
+                DeviceManagerEvent::DeviceDiscovered(_) | DeviceManagerEvent::DeviceUpdated(_) => {}
+                event => panic!("unexpected event: {:?}", event),
+            }
+        }
+
+        device_manager
+            .get::<commands::Volume>(&device.id(), Duration::from_secs(1))
+            .unwrap();
+
+        match events.recv_timeout(Duration::from_secs(5)).unwrap() {
+            DeviceManagerEvent::DeviceReappeared(reappeared) => {
+                assert_eq!(reappeared.id(), device.id());
+                assert!(reappeared.is_online());
+            }
+            event => panic!("unexpected event: {:?}", event),
+        }
+    }
+
+    #[test]
+    fn rediscover_test() {
+        let device_manager = DeviceManager::new(
+            fake::device_manager_config()
+                .unwrap()
+                .with_device_expiry(Duration::from_millis(200))
+                .with_discovery_interval(None),
+        )
+        .unwrap();
+        let events = device_manager.listen();
+        let device = discover_device(&device_manager);
+
+        loop {
+            if let DeviceManagerEvent::DeviceLost(_) =
+                events.recv_timeout(Duration::from_secs(5)).unwrap()
+            {
+                break;
+            }
+        }
+
+        device_manager.rediscover().unwrap();
+
+        match events.recv_timeout(Duration::from_secs(5)).unwrap() {
+            DeviceManagerEvent::DeviceReappeared(reappeared) => {
+                assert_eq!(reappeared.id(), device.id());
+            }
+            event => panic!("unexpected event: {:?}", event),
+        }
+    }
+
+    #[test]
+    fn subscription_test() {
+        let wait_for_subscription = |config: DeviceManagerConfig, expected| {
+            let device_manager = DeviceManager::new(
+                config
+                    .with_retry_policy(RetryPolicy {
+                        attempts: 2,
+                        timeout: Duration::from_millis(100),
+                        backoff: 2,
+                    })
+                    .unwrap(),
+            )
+            .unwrap();
+            let events = device_manager.listen();
+
+            loop {
+                match events.recv_timeout(Duration::from_secs(5)).unwrap() {
+                    DeviceManagerEvent::DeviceDiscovered(device) => {
+                        assert_eq!(device.subscription(), SubscriptionStatus::Pending);
+                    }
+                    DeviceManagerEvent::DeviceUpdated(device) => {
+                        if device.subscription() == expected {
+                            break;
+                        }
+                    }
+                    event => panic!("unexpected event: {:?}", event),
+                }
+            }
+        };
+
+        wait_for_subscription(
+            fake::device_manager_config().unwrap(),
+            SubscriptionStatus::Subscribed,
+        );
+
+        // the device never gets our registration
+        wait_for_subscription(
+            fake::FakeEnvironment::new()
+                .with_speaker(fake::FakeSpeakerState::new(
+                    "test-device",
+                    "Pretty name",
+                    "192.168.10.10".parse().unwrap(),
+                ))
+                .with_packet_loss(1.0)
+                .device_manager_config()
+                .unwrap(),
+            SubscriptionStatus::Unknown,
+        );
+    }
+
+    #[test]
+    fn unanswered_hello_test() {
+        let speaker = fake::FakeSpeaker::new(fake::FakeSpeakerState::new(
+            "test-device",
+            "Pretty name",
+            "192.168.10.10".parse().unwrap(),
+        ));
+        let retry_policy = RetryPolicy {
+            attempts: 1,
+            timeout: Duration::ZERO,
+            backoff: 1,
+        };
+        let subscription =
+            |registry: &DeviceRegistry| registry.device("test-device").unwrap().subscription();
+
+        let mut registry = DeviceRegistry::new(Duration::ZERO);
+        registry.register_device(&speaker.discovery_reply());
+        registry.take_events();
+
+        assert_eq!(registry.hellos_due(&retry_policy).len(), 1);
+        assert!(registry.hellos_due(&retry_policy).is_empty());
+        assert_eq!(subscription(&registry), SubscriptionStatus::Unknown);
+        assert_eq!(registry.take_events().len(), 1);
+
+        registry.mark_device_subscribed("test-device");
+        assert_eq!(subscription(&registry), SubscriptionStatus::Subscribed);
+        assert_eq!(registry.take_events().len(), 1);
+
+        // a device sending notifications does not need to reply to hellos
+        for _ in 0..4 {
+            registry.hellos_due(&retry_policy);
+        }
+        assert_eq!(subscription(&registry), SubscriptionStatus::Subscribed);
+        assert!(registry.take_events().is_empty());
     }
 }
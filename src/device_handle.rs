@@ -0,0 +1,240 @@
+use std::time::Duration;
+
+use crate::commands;
+use crate::commands::{ChannelObject, ChargingStateData, Command, PlayControlCommand, PowerState};
+use crate::device::{DeliveryOutcome, Device, DeviceManagerShared};
+use crate::subscription::{EventFilter, Subscription};
+use crate::{Error, Result};
+
+/// How long to wait for replies, unless told otherwise with
+/// [`DeviceHandle::with_timeout`]
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// A device known to a [`DeviceManager`], see [`DeviceManager::handle`].
+///
+/// Getters fetch the current value from the device, and setters wait for the
+/// device to confirm the change, as [`DeviceManager::get`] and
+/// [`DeviceManager::set`] do.
+///
+/// Handles can be kept around and sent to other threads. Once the device
+/// manager is shut down, their requests fail with [`Error::Shutdown`].
+///
+/// [`DeviceManager`]: crate::device::DeviceManager
+/// [`DeviceManager::handle`]: crate::device::DeviceManager::handle
+/// [`DeviceManager::get`]: crate::device::DeviceManager::get
+/// [`DeviceManager::set`]: crate::device::DeviceManager::set
+#[derive(Clone)]
+pub struct DeviceHandle {
+    device_manager: DeviceManagerShared,
+    device_id: String,
+    timeout: Duration,
+}
+
+impl DeviceHandle {
+    pub(crate) fn new(device_manager: DeviceManagerShared, device_id: String) -> DeviceHandle {
+        DeviceHandle {
+            device_manager,
+            device_id,
+            timeout: DEFAULT_TIMEOUT,
+        }
+    }
+
+    /// Waits at most `timeout` for each reply from the device
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn id(&self) -> &str {
+        &self.device_id
+    }
+
+    /// Returns the latest known state of the device, without fetching
+    /// anything from it.
+    pub fn device(&self) -> Result<Device> {
+        self.device_manager
+            .device(&self.device_id)
+            .ok_or_else(|| Error::UnknownDevice(self.device_id.clone()))
+    }
+
+    pub fn get<C: Command>(&self) -> Result<C::ResponseData> {
+        self.device_manager.get::<C>(&self.device_id, self.timeout)
+    }
+
+    pub fn set<C: Command + 'static>(&self, value: C::RequestData) -> Result<DeliveryOutcome> {
+        self.device_manager.set::<C>(&self.device_id, value)
+    }
+
+    /// Fetches all the information the device state holds, without waiting
+    /// for the replies.
+    pub fn fetch_info(&self) -> Result<()> {
+        self.device_manager.fetch_info(&self.device_id)
+    }
+
+    /// Returns the events about this device that `filter` lets through
+    pub fn subscribe(&self, filter: EventFilter) -> Subscription {
+        self.device_manager
+            .subscribe(filter.with_devices([self.device_id.as_str()]))
+    }
+
+    pub fn name(&self) -> Result<String> {
+        self.get::<commands::DeviceName>()
+    }
+
+    pub fn rename(&self, name: &str) -> Result<DeliveryOutcome> {
+        self.set::<commands::DeviceName>(name.to_owned())
+    }
+
+    pub fn volume(&self) -> Result<u8> {
+        self.get::<commands::Volume>()
+    }
+
+    /// Sets the volume, capped to 100
+    pub fn set_volume(&self, volume: u8) -> Result<DeliveryOutcome> {
+        self.device_manager.set_volume(&self.device_id, volume)
+    }
+
+    pub fn play_status(&self) -> Result<PlayControlCommand> {
+        self.get::<commands::PlayControl>()
+    }
+
+    pub fn play_control(&self, command: PlayControlCommand) -> Result<DeliveryOutcome> {
+        self.set::<commands::PlayControl>(command)
+    }
+
+    pub fn play(&self) -> Result<DeliveryOutcome> {
+        self.play_control(PlayControlCommand::Play)
+    }
+
+    pub fn pause(&self) -> Result<DeliveryOutcome> {
+        self.play_control(PlayControlCommand::Pause)
+    }
+
+    pub fn stop(&self) -> Result<DeliveryOutcome> {
+        self.play_control(PlayControlCommand::Stop)
+    }
+
+    pub fn next(&self) -> Result<DeliveryOutcome> {
+        self.play_control(PlayControlCommand::Next)
+    }
+
+    pub fn previous(&self) -> Result<DeliveryOutcome> {
+        self.play_control(PlayControlCommand::Previous)
+    }
+
+    /// The channels saved as favorites on the device
+    pub fn favorites(&self) -> Result<Vec<ChannelObject>> {
+        self.get::<commands::PreChannel>()
+    }
+
+    /// Plays the favorite at `index` in [`DeviceHandle::favorites`]
+    pub fn play_favorite(&self, index: usize) -> Result<DeliveryOutcome> {
+        let favorites = self.favorites()?;
+        let channel = favorites.get(index).ok_or(Error::NoSuchFavorite {
+            index,
+            count: favorites.len(),
+        })?;
+
+        self.set::<commands::PlayInfo>(channel.play_info_data())
+    }
+
+    pub fn sleep(&self) -> Result<DeliveryOutcome> {
+        self.set::<commands::Power>(PowerState::Sleep)
+    }
+
+    pub fn wake_up(&self) -> Result<DeliveryOutcome> {
+        self.set::<commands::Power>(PowerState::WakeUp)
+    }
+
+    /// Battery level, in percent
+    pub fn battery(&self) -> Result<u8> {
+        self.get::<commands::BatteryLevel>()
+    }
+
+    pub fn charging_state(&self) -> Result<ChargingStateData> {
+        self.get::<commands::ChargingState>()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::{DeviceManager, DeviceManagerEvent, EventKind, PropertyValue};
+    use crate::fake;
+
+    fn discover_device(device_manager: &DeviceManager) -> String {
+        let events = device_manager.listen();
+
+        match events.recv_timeout(Duration::from_secs(5)).unwrap() {
+            DeviceManagerEvent::DeviceDiscovered(device) => device.id(),
+            event => panic!("unexpected event: {:?}", event),
+        }
+    }
+
+    #[test]
+    fn handle_test() {
+        let device_manager = DeviceManager::new(fake::device_manager_config().unwrap()).unwrap();
+        let events = device_manager.listen();
+
+        let device_id = match events.recv_timeout(Duration::from_secs(5)).unwrap() {
+            DeviceManagerEvent::DeviceDiscovered(device) => device.id(),
+            event => panic!("unexpected event: {:?}", event),
+        };
+
+        assert!(matches!(
+            device_manager.handle("nowhere"),
+            Err(Error::UnknownDevice(_))
+        ));
+
+        let device = device_manager.handle(&device_id).unwrap();
+        let name = device.name().unwrap();
+
+        // names are looked up regardless of case
+        let device = device_manager.handle(&name.to_uppercase()).unwrap();
+        assert_eq!(device.id(), device_id);
+
+        let changes =
+            device.subscribe(EventFilter::all().with_kinds([EventKind::DevicePropertyChanged]));
+
+        assert_eq!(
+            device.set_volume(42).unwrap(),
+            DeliveryOutcome::Delivered { attempts: 1 }
+        );
+        assert_eq!(device.volume().unwrap(), 42);
+
+        match changes.recv_timeout(Duration::from_secs(5)).unwrap() {
+            DeviceManagerEvent::DevicePropertyChanged { new, .. } => {
+                assert_eq!(new, PropertyValue::Volume(42))
+            }
+            event => panic!("unexpected event: {:?}", event),
+        }
+
+        let favorites = device.favorites().unwrap();
+        assert!(matches!(
+            device.play_favorite(favorites.len()),
+            Err(Error::NoSuchFavorite { .. })
+        ));
+    }
+
+    #[test]
+    fn owned_handle_test() {
+        fn assert_send<T: Send + 'static>(_: &T) {}
+
+        let device_manager = DeviceManager::new(fake::device_manager_config().unwrap()).unwrap();
+        let device = device_manager
+            .handle(&discover_device(&device_manager))
+            .unwrap();
+        assert_send(&device);
+
+        let other = device.clone();
+        let outcome = std::thread::spawn(move || other.set_volume(30))
+            .join()
+            .unwrap();
+        assert_eq!(outcome.unwrap(), DeliveryOutcome::Delivered { attempts: 1 });
+
+        // the handle outlives the device manager
+        drop(device_manager);
+        assert!(matches!(device.volume(), Err(Error::Shutdown)));
+        assert!(device.subscribe(EventFilter::all()).recv().is_err());
+    }
+}
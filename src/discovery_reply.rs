@@ -1,9 +1,17 @@
 use std::borrow::Cow;
+use std::collections::BTreeSet;
 use std::net::IpAddr;
+use std::str::FromStr;
 
-use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
 
-#[derive(Debug, PartialEq)]
+use crate::{Error, Result};
+
+fn invalid(reason: impl std::fmt::Display) -> Error {
+    Error::InvalidDiscoveryReply(reason.to_string())
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
 pub struct DiscoveryReply {
     pub device_name: String,
     pub device_id: String,
@@ -39,14 +47,15 @@ impl DiscoveryReply {
 
         let mut headers = [httparse::EMPTY_HEADER; 16];
         let mut req = httparse::Request::new(&mut headers);
-        req.parse(&input).context("error parsing HTTP data")?;
+        req.parse(&input)
+            .map_err(|err| invalid(format!("error parsing HTTP data: {}", err)))?;
 
         if req.method != Some("NOTIFY") {
-            return Err(anyhow!("unexpected method: {:?}", req.method));
+            return Err(invalid(format!("unexpected method: {:?}", req.method)));
         }
 
         if req.path != Some("*") {
-            return Err(anyhow!("unexpected path: {:?}", req.path));
+            return Err(invalid(format!("unexpected path: {:?}", req.path)));
         }
 
         let mut device_name: Option<String> = None;
@@ -76,7 +85,7 @@ impl DiscoveryReply {
                         String::from_utf8_lossy(header.value)
                             .as_ref()
                             .parse::<u16>()
-                            .context("invalid port number")?,
+                            .map_err(|_| invalid("invalid port number"))?,
                     );
                 }
                 "ZoneID" => {
@@ -89,7 +98,7 @@ impl DiscoveryReply {
                     ip_address = Some(
                         String::from_utf8_lossy(header.value)
                             .parse()
-                            .context("invalid IP address")?,
+                            .map_err(|_| invalid("invalid IP address"))?,
                     );
                 }
                 "ColorCode" => {
@@ -108,26 +117,174 @@ impl DiscoveryReply {
         }
 
         Ok(DiscoveryReply {
-            device_name: device_name.ok_or_else(|| anyhow!("missing DeviceName header"))?,
-            device_id: device_id.ok_or_else(|| anyhow!("missing DeviceID header"))?,
-            device_state: device_state.ok_or_else(|| anyhow!("missing DeviceState header"))?,
-            port: port.ok_or_else(|| anyhow!("missing PORT header"))?,
-            zone_id: zone_id.ok_or_else(|| anyhow!("missing ZoneID header"))?,
-            creator: creator.ok_or_else(|| anyhow!("missing Creator header"))?,
-            ip_address: ip_address.ok_or_else(|| anyhow!("missing IPAddr header"))?,
-            color_code: color_code.ok_or_else(|| anyhow!("missing ColorCode header"))?,
+            device_name: device_name.ok_or_else(|| invalid("missing DeviceName header"))?,
+            device_id: device_id.ok_or_else(|| invalid("missing DeviceID header"))?,
+            device_state: device_state.ok_or_else(|| invalid("missing DeviceState header"))?,
+            port: port.ok_or_else(|| invalid("missing PORT header"))?,
+            zone_id: zone_id.ok_or_else(|| invalid("missing ZoneID header"))?,
+            creator: creator.ok_or_else(|| invalid("missing Creator header"))?,
+            ip_address: ip_address.ok_or_else(|| invalid("missing IPAddr header"))?,
+            color_code: color_code.ok_or_else(|| invalid("missing ColorCode header"))?,
             firmware_version: firmware_version
-                .ok_or_else(|| anyhow!("missing FWVersion header"))?,
+                .ok_or_else(|| invalid("missing FWVersion header"))?,
             stereo_pair_id: stereo_pair_id.unwrap_or_default(),
         })
     }
+
+    /// Encodes the reply the way devices send it, quirks included, so that
+    /// [`DiscoveryReply::parse`] accepts it.
+    pub fn encode(&self) -> Vec<u8> {
+        format!(
+            "NOTIFY * HTTP/1.1 \r\n\
+HOST: 239.255.255.250:1800\r\n\
+PROTOCOL: Version 1.0\r\n\
+NTS: ssdp-alive\r\n\
+DeviceName: {}\r\n\
+DeviceID: {}\r\n\
+DeviceState: {}\r\n\
+PORT: {}\r\n\
+ZoneID: {}\r\n\
+Creator: {}\r\n\
+IPAddr: {}\r\n\
+ColorCode: {}\r\n\
+FWVersion: {}\r\n\
+StereoPairID: {}",
+            self.device_name,
+            self.device_id,
+            self.device_state,
+            self.port,
+            self.zone_id,
+            self.creator,
+            self.ip_address,
+            self.color_code,
+            self.firmware_version,
+            self.stereo_pair_id,
+        )
+        .into_bytes()
+    }
+
+    pub fn parse_firmware_version(&self) -> Result<FirmwareVersion> {
+        self.firmware_version.parse()
+    }
+
+    pub fn parse_device_state(&self) -> DeviceState {
+        DeviceState::parse(&self.device_state)
+    }
+
+    pub fn parse_color_code(&self) -> ColorCode {
+        ColorCode::parse(&self.color_code)
+    }
+}
+
+/// Decoded FWVersion header, which looks like "809;1,1;1,1": a build number
+/// followed by a list of comma separated component versions. Versions compare
+/// by build number first, then component by component.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct FirmwareVersion {
+    pub build: u32,
+    pub components: Vec<Vec<u32>>,
+}
+
+impl FromStr for FirmwareVersion {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut parts = s.split(';');
+
+        let build = parts
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .parse()
+            .map_err(|_| invalid(format!("invalid firmware build number in {:?}", s)))?;
+
+        let components = parts
+            .map(|part| {
+                part.split(',')
+                    .map(|x| x.trim().parse::<u32>())
+                    .collect::<std::result::Result<Vec<_>, _>>()
+                    .map_err(|_| invalid(format!("invalid firmware component {:?}", part)))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(FirmwareVersion { build, components })
+    }
+}
+
+impl std::fmt::Display for FirmwareVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.build)?;
+
+        for component in &self.components {
+            let component: Vec<String> = component.iter().map(|x| x.to_string()).collect();
+            write!(f, ";{}", component.join(","))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Decoded DeviceState header, a comma separated list of single letter flags
+/// such as "F,S,P". The meaning of the individual flags is not known yet.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DeviceState {
+    flags: BTreeSet<String>,
+}
+
+impl DeviceState {
+    /// Parses the header. Any flag is accepted, unknown ones included, so
+    /// this can't fail.
+    pub fn parse(s: &str) -> DeviceState {
+        DeviceState {
+            flags: s
+                .split(',')
+                .map(|x| x.trim())
+                .filter(|x| !x.is_empty())
+                .map(|x| x.to_owned())
+                .collect(),
+        }
+    }
+
+    pub fn contains(&self, flag: &str) -> bool {
+        self.flags.contains(flag)
+    }
+
+    pub fn flags(&self) -> impl Iterator<Item = &str> {
+        self.flags.iter().map(|x| x.as_str())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.flags.is_empty()
+    }
+}
+
+/// Decoded ColorCode header, such as "2003". The codes presumably tell the
+/// product model and colour apart, but which code stands for what has yet to
+/// be worked out from real devices, so for now they are all kept as is.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ColorCode {
+    Unknown(String),
+}
+
+impl ColorCode {
+    pub fn parse(s: &str) -> ColorCode {
+        ColorCode::Unknown(s.to_owned())
+    }
+
+    /// The code as sent by the device
+    pub fn as_str(&self) -> &str {
+        match self {
+            ColorCode::Unknown(code) => code,
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
-    const DATA: &'static str = "NOTIFY * HTTP/1.1 \r\n\
+    const DATA: &str = "NOTIFY * HTTP/1.1 \r\n\
 HOST: 239.255.255.250:1800\r\n\
 PROTOCOL: Version 1.0\r\n\
 NTS: ssdp-alive\r\n\
@@ -162,4 +319,62 @@ StereoPairID: ";
             },
         );
     }
+
+    #[test]
+    fn encode_test() {
+        let reply = DiscoveryReply::parse(DATA.as_bytes()).unwrap();
+
+        assert_eq!(String::from_utf8(reply.encode()).unwrap(), DATA);
+        assert_eq!(DiscoveryReply::parse(&reply.encode()).unwrap(), reply);
+    }
+
+    #[test]
+    fn firmware_version_test() {
+        let reply = DiscoveryReply::parse(DATA.as_bytes()).unwrap();
+        let version = reply.parse_firmware_version().unwrap();
+
+        assert_eq!(
+            version,
+            FirmwareVersion {
+                build: 809,
+                components: vec![vec![1, 1], vec![1, 1]],
+            }
+        );
+        assert_eq!(version.to_string(), "809;1,1;1,1");
+
+        assert!(version < "812;1,1;1,1".parse().unwrap());
+        assert!(version < "809;1,2;1,1".parse().unwrap());
+        assert!(version > "808;9,9;9,9".parse().unwrap());
+        assert_eq!(
+            "809".parse::<FirmwareVersion>().unwrap().components.len(),
+            0
+        );
+
+        assert!("".parse::<FirmwareVersion>().is_err());
+        assert!("809;1,x".parse::<FirmwareVersion>().is_err());
+    }
+
+    #[test]
+    fn device_state_test() {
+        let reply = DiscoveryReply::parse(DATA.as_bytes()).unwrap();
+        let state = reply.parse_device_state();
+
+        assert!(state.contains("F"));
+        assert!(state.contains("S"));
+        assert!(state.contains("P"));
+        assert!(!state.contains("X"));
+        assert_eq!(state.flags().collect::<Vec<_>>(), vec!["F", "P", "S"]);
+
+        assert!(DeviceState::parse("").is_empty());
+        assert_eq!(DeviceState::parse(" P , F ,"), DeviceState::parse("F,P"));
+    }
+
+    #[test]
+    fn color_code_test() {
+        let reply = DiscoveryReply::parse(DATA.as_bytes()).unwrap();
+        let color_code = reply.parse_color_code();
+
+        assert_eq!(color_code, ColorCode::Unknown("2003".to_owned()));
+        assert_eq!(color_code.as_str(), "2003");
+    }
 }
@@ -0,0 +1,80 @@
+use thiserror::Error;
+
+/// Errors returned by the library
+#[derive(Debug, Error)]
+pub enum Error {
+    /// A packet is shorter than the fixed size header
+    #[error("packet is too short")]
+    PacketTooShort,
+    /// The length announced in a packet header does not match its payload
+    #[error("incorrect packet length: header says {expected} bytes, got {actual}")]
+    PacketLength { expected: usize, actual: usize },
+    /// A packet does not start with [`crate::protocol::MAGIC`]
+    #[error("bad packet magic {:02x}{:02x}", .0[0], .0[1])]
+    BadMagic([u8; 2]),
+    /// The payload of a packet could not be decoded as the data of `command`
+    #[error("invalid {command} data: {reason}")]
+    Decode {
+        command: &'static str,
+        reason: String,
+    },
+    /// A value can't be sent as the data of `command`
+    #[error("invalid {command} value: {reason}")]
+    InvalidValue {
+        command: &'static str,
+        reason: String,
+    },
+    #[error("invalid discovery reply: {0}")]
+    InvalidDiscoveryReply(String),
+    #[error("invalid retry policy: {0}")]
+    InvalidRetryPolicy(String),
+    #[error("invalid capture: {0}")]
+    InvalidCapture(String),
+    /// No known command uses this command ID
+    #[error("unknown command ID {0}")]
+    UnknownCommand(u16),
+    #[error("unknown device ID {0:?}")]
+    UnknownDevice(String),
+    /// A device has no favorite channel at the requested index
+    #[error("no favorite at index {index}, the device has {count}")]
+    NoSuchFavorite { index: usize, count: usize },
+    /// A device did not reply to a request in time
+    #[error("timed out waiting for reply")]
+    Timeout,
+    /// A device replied to a request with an error status
+    #[error(
+        "device rejected {} with status {status}",
+        crate::commands::command_name(*.command).unwrap_or("command")
+    )]
+    DeviceStatus { command: u16, status: u8 },
+    #[error("{context}")]
+    Io {
+        context: String,
+        #[source]
+        source: std::io::Error,
+    },
+    /// The device manager went away while the operation was in progress
+    #[error("the device manager was shut down")]
+    Shutdown,
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// Adds context to I/O errors, turning them into [`Error::Io`]
+pub(crate) trait IoContext<T> {
+    fn context(self, context: &str) -> Result<T>;
+    fn with_context<F: FnOnce() -> String>(self, context: F) -> Result<T>;
+}
+
+impl<T> IoContext<T> for std::io::Result<T> {
+    fn context(self, context: &str) -> Result<T> {
+        self.with_context(|| context.to_owned())
+    }
+
+    fn with_context<F: FnOnce() -> String>(self, context: F) -> Result<T> {
+        self.map_err(|source| Error::Io {
+            context: context(),
+            source,
+        })
+    }
+}
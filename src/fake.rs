@@ -1,15 +1,19 @@
-use std::cell::Cell;
 use std::collections::HashMap;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
-use std::sync::{mpsc, Arc, Mutex};
+use std::sync::{mpsc, Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
 
-use anyhow::Result;
+use serde::Serialize;
 
 use crate::commands;
-use crate::commands::Command;
+use crate::commands::{ChannelObject, ChargingStateData, Command, PlayControlCommand};
 use crate::device::{DeviceDiscoveryImpl, DeviceManagerConfig, NetworkImpl};
 use crate::discovery_reply::DiscoveryReply;
-use crate::protocol::{Packet, PacketReceiver, PacketSender, CMD_RESP_PORT, NOTIF_RECV_PORT};
+use crate::protocol::{
+    NotificationAck, Packet, PacketReceiver, PacketSender, PacketStatus, CMD_RESP_PORT,
+    CMD_SEND_PORT, NOTIF_ACK_PORT, NOTIF_RECV_PORT,
+};
+use crate::{Error, Result};
 
 type AddressAndPacket = (SocketAddr, Packet);
 type FakeSocket = (
@@ -17,6 +21,692 @@ type FakeSocket = (
     Arc<Mutex<mpsc::Receiver<AddressAndPacket>>>,
 );
 
+/// A packet sent by a [`FakeSpeaker`], along with the port it is sent to:
+/// [`CMD_RESP_PORT`] for replies, [`NOTIF_RECV_PORT`] for notifications.
+pub type PortAndPacket = (u16, Packet);
+
+/// State of a simulated speaker. Fields can be tweaked before handing the
+/// state over to [`FakeSpeaker::new`].
+#[derive(Clone, Debug)]
+pub struct FakeSpeakerState {
+    pub id: String,
+    pub addr: IpAddr,
+    pub name: String,
+    pub volume: u8,
+    /// One of `Play`, `Pause` or `Stop`
+    pub play_status: PlayControlCommand,
+    pub muted: bool,
+    pub play_info: commands::PlayInfoData,
+    pub pre_channels: Vec<ChannelObject>,
+    /// Index of the favorite being played in `pre_channels`, if any
+    pub current_channel: Option<usize>,
+    pub battery_level: u8,
+    pub charging_state: ChargingStateData,
+    /// Time it takes for the battery level to change by one percent
+    pub battery_interval: Duration,
+    pub sleeping: bool,
+    /// Whether the speaker answers packets and announces itself
+    pub online: bool,
+    /// Addresses registered for notifications with the hello command
+    pub subscribers: Vec<SocketAddr>,
+    pub power_mode: String,
+    pub capabilities: Vec<String>,
+    pub firmware_update: String,
+    pub firmware_version: String,
+    pub color_code: String,
+}
+
+impl FakeSpeakerState {
+    pub fn new(id: &str, name: &str, addr: IpAddr) -> FakeSpeakerState {
+        FakeSpeakerState {
+            id: id.to_owned(),
+            addr,
+            name: name.to_owned(),
+            volume: 35,
+            play_status: PlayControlCommand::Stop,
+            muted: false,
+            play_info: commands::PlayInfoData::default(),
+            pre_channels: vec![
+                fake_channel(1, "Radio Paradise"),
+                fake_channel(2, "FIP"),
+                fake_channel(3, "KEXP"),
+            ],
+            current_channel: None,
+            battery_level: 80,
+            charging_state: ChargingStateData::Discharging,
+            battery_interval: Duration::from_secs(60),
+            sleeping: false,
+            online: true,
+            subscribers: vec![],
+            power_mode: "0".to_owned(),
+            capabilities: ["volume", "play_control", "favorites", "battery"]
+                .iter()
+                .map(|&x| x.to_owned())
+                .collect(),
+            firmware_update: "0".to_owned(),
+            firmware_version: "809;1,1;1,1".to_owned(),
+            color_code: "1001".to_owned(),
+        }
+    }
+}
+
+fn fake_channel(id: i64, name: &str) -> ChannelObject {
+    ChannelObject {
+        is_playing: Some(false),
+        channel_id: id,
+        channel_type: commands::ChannelType::VTuner,
+        channel_name: name.to_owned(),
+        channel_identity: Some(format!("vtuner-{}", id)),
+        station_url: None,
+        picture_url: None,
+        username: None,
+        password: None,
+        play_token: None,
+    }
+}
+
+/// A simulated speaker, independent of any transport. It answers every
+/// command from [`commands`] the way a real device does: fetches get a
+/// reply, sets are applied and announced with the matching notification.
+///
+/// A speaker that is asleep still answers fetches and power commands, but
+/// ignores playback and volume changes.
+///
+/// Like a real device, the speaker expects its notifications to be
+/// acknowledged, and sends them again until they are: transports report
+/// the notifications they deliver with [`FakeSpeaker::notification_sent`],
+/// and the acks they receive with [`FakeSpeaker::handle_ack`].
+pub struct FakeSpeaker {
+    state: Mutex<FakeSpeakerState>,
+    battery_clock: Mutex<Instant>,
+    next_sequence: Mutex<u16>,
+    unacked_notifications: Mutex<Vec<UnackedNotification>>,
+}
+
+struct UnackedNotification {
+    client: SocketAddr,
+    packet: Packet,
+    attempts: u32,
+    sent_at: Instant,
+}
+
+/// How long a speaker waits for a notification to be acknowledged before
+/// sending it again
+pub const ACK_TIMEOUT: Duration = Duration::from_secs(1);
+/// How many times a speaker sends a notification before giving up on it
+pub const NOTIFICATION_ATTEMPTS: u32 = 3;
+/// Status a speaker replies with to sets carrying an invalid value
+pub const INVALID_VALUE_STATUS: u8 = 2;
+
+impl FakeSpeaker {
+    pub fn new(state: FakeSpeakerState) -> FakeSpeaker {
+        FakeSpeaker {
+            state: Mutex::new(state),
+            battery_clock: Mutex::new(Instant::now()),
+            next_sequence: Mutex::new(0),
+            unacked_notifications: Mutex::new(vec![]),
+        }
+    }
+
+    pub fn state(&self) -> FakeSpeakerState {
+        self.state.lock().unwrap().clone()
+    }
+
+    pub fn addr(&self) -> IpAddr {
+        self.state.lock().unwrap().addr
+    }
+
+    pub fn is_online(&self) -> bool {
+        self.state.lock().unwrap().online
+    }
+
+    /// Applies a scripted event, and returns the resulting notifications.
+    pub fn apply(&self, event: &FakeEvent) -> Vec<PortAndPacket> {
+        let packets = self.apply_event(event);
+        self.sequenced(packets, None)
+    }
+
+    fn apply_event(&self, event: &FakeEvent) -> Vec<PortAndPacket> {
+        let mut state = self.state.lock().unwrap();
+
+        match event {
+            FakeEvent::Disappear => {
+                state.online = false;
+                vec![]
+            }
+            FakeEvent::Reappear => {
+                state.online = true;
+                vec![]
+            }
+            FakeEvent::ChangeAddress(addr) => {
+                state.addr = *addr;
+                vec![]
+            }
+            FakeEvent::BatteryLevel(level) => {
+                state.battery_level = (*level).min(100);
+                vec![notification(
+                    commands::BatteryLevel::NOTIFY_ID,
+                    state.battery_level.to_string(),
+                )]
+            }
+            FakeEvent::ChargingState(charging_state) => {
+                state.charging_state = *charging_state;
+                vec![Self::charging_state_notification(&state)]
+            }
+            FakeEvent::PlayInfo(play_info) => {
+                state.play_info = play_info.as_ref().clone();
+                Self::play(&mut state, None)
+            }
+        }
+    }
+
+    pub fn discovery_reply(&self) -> DiscoveryReply {
+        let state = self.state.lock().unwrap();
+
+        DiscoveryReply {
+            device_name: state.name.clone(),
+            device_id: state.id.clone(),
+            device_state: "F,S,P".to_owned(),
+            port: CMD_SEND_PORT,
+            zone_id: String::new(),
+            creator: String::new(),
+            ip_address: state.addr,
+            color_code: state.color_code.clone(),
+            firmware_version: state.firmware_version.clone(),
+            stereo_pair_id: String::new(),
+        }
+    }
+
+    /// Handles a packet received on the command port, and returns the
+    /// replies and notifications it triggers.
+    pub fn handle_packet(&self, packet: &Packet) -> Vec<PortAndPacket> {
+        let packets = {
+            let mut state = self.state.lock().unwrap();
+            let data = packet.command_data.as_deref().unwrap_or_default();
+
+            match packet.command_type {
+                commands::COMMAND_TYPE_FETCH => Self::handle_fetch(&state, packet.command)
+                    .into_iter()
+                    .collect(),
+                commands::COMMAND_TYPE_SET => Self::handle_set(&mut state, packet.command, data),
+                _ => vec![],
+            }
+        };
+
+        self.sequenced(packets, Some(packet))
+    }
+
+    /// Records that `packet`, a notification returned by this speaker, was
+    /// sent to `client`.
+    pub fn notification_sent(&self, client: SocketAddr, packet: &Packet) {
+        self.unacked_notifications
+            .lock()
+            .unwrap()
+            .push(UnackedNotification {
+                client,
+                packet: packet.clone(),
+                attempts: 1,
+                sent_at: Instant::now(),
+            });
+    }
+
+    /// Handles an ack received from `from`, returns false if it is not a
+    /// well-formed ack, or does not acknowledge any notification sent to that
+    /// client. Acks don't tell notifications of the same command type apart,
+    /// so the oldest one is taken as acknowledged.
+    pub fn handle_ack(&self, from: IpAddr, packet: &Packet) -> bool {
+        let ack = NotificationAck::from_packet(packet);
+
+        if ack.packet() != *packet {
+            return false;
+        }
+
+        let mut unacked_notifications = self.unacked_notifications.lock().unwrap();
+
+        match unacked_notifications
+            .iter()
+            .position(|x| x.client.ip() == from && ack.acknowledges(&x.packet))
+        {
+            Some(index) => {
+                unacked_notifications.remove(index);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Returns the notifications that were not acknowledged in time, along
+    /// with the client to send them to again. Notifications sent too many
+    /// times already are given up on.
+    pub fn notifications_to_resend(&self) -> Vec<(SocketAddr, Packet)> {
+        self.notifications_to_resend_at(Instant::now())
+    }
+
+    fn notifications_to_resend_at(&self, now: Instant) -> Vec<(SocketAddr, Packet)> {
+        let mut unacked_notifications = self.unacked_notifications.lock().unwrap();
+        let timed_out =
+            |x: &UnackedNotification| now.saturating_duration_since(x.sent_at) >= ACK_TIMEOUT;
+
+        unacked_notifications.retain(|x| x.attempts < NOTIFICATION_ATTEMPTS || !timed_out(x));
+
+        unacked_notifications
+            .iter_mut()
+            .filter(|x| timed_out(x))
+            .map(|x| {
+                x.attempts += 1;
+                x.sent_at = now;
+                (x.client, x.packet.clone())
+            })
+            .collect()
+    }
+
+    /// Number of notifications waiting for an ack
+    pub fn unacked_notifications(&self) -> usize {
+        self.unacked_notifications.lock().unwrap().len()
+    }
+
+    /// Fills in the header of outgoing packets: replies echo the sequence
+    /// of the request, notifications get their own.
+    fn sequenced(
+        &self,
+        packets: Vec<PortAndPacket>,
+        request: Option<&Packet>,
+    ) -> Vec<PortAndPacket> {
+        packets
+            .into_iter()
+            .map(|(port, mut packet)| {
+                if port == NOTIF_RECV_PORT {
+                    let mut next_sequence = self.next_sequence.lock().unwrap();
+                    packet.status = PacketStatus::Notification;
+                    packet.sequence = *next_sequence;
+                    *next_sequence = next_sequence.wrapping_add(1);
+                } else if let Some(request) = request {
+                    packet.sequence = request.sequence;
+                }
+
+                (port, packet)
+            })
+            .collect()
+    }
+
+    /// Drains (or charges) the battery according to the time elapsed since
+    /// the last call, and returns the resulting notifications.
+    pub fn tick(&self) -> Vec<PortAndPacket> {
+        let packets = self.tick_at(Instant::now());
+        self.sequenced(packets, None)
+    }
+
+    fn tick_at(&self, now: Instant) -> Vec<PortAndPacket> {
+        let mut state = self.state.lock().unwrap();
+        let mut battery_clock = self.battery_clock.lock().unwrap();
+
+        let interval = state.battery_interval.max(Duration::from_millis(1));
+        let steps = (now.saturating_duration_since(*battery_clock).as_millis()
+            / interval.as_millis()) as u32;
+
+        if steps == 0 {
+            return vec![];
+        }
+
+        *battery_clock += interval * steps;
+
+        let steps = steps.min(100) as u8;
+        let previous_level = state.battery_level;
+        let mut notifications = vec![];
+
+        match state.charging_state {
+            ChargingStateData::Discharging => {
+                state.battery_level = state.battery_level.saturating_sub(steps);
+            }
+            ChargingStateData::PluggedInCharging => {
+                state.battery_level = state.battery_level.saturating_add(steps).min(100);
+
+                if state.battery_level == 100 {
+                    state.charging_state = ChargingStateData::PluggedInCharged;
+                    notifications.push(Self::charging_state_notification(&state));
+                }
+            }
+            _ => {}
+        }
+
+        if state.battery_level != previous_level {
+            notifications.insert(
+                0,
+                notification(
+                    commands::BatteryLevel::NOTIFY_ID,
+                    state.battery_level.to_string(),
+                ),
+            );
+        }
+
+        notifications
+    }
+
+    fn handle_fetch(state: &FakeSpeakerState, command: u16) -> Option<PortAndPacket> {
+        let (reply_command, data): (u16, Vec<u8>) = match command {
+            commands::BatteryLevel::GET_COMMAND_ID => (
+                commands::BatteryLevel::GET_REPLY_COMMAND_ID,
+                state.battery_level.to_string().into_bytes(),
+            ),
+            commands::Capabilities::GET_COMMAND_ID => (
+                commands::Capabilities::GET_REPLY_COMMAND_ID,
+                serde_json::json!({
+                    "capabilities": state
+                        .capabilities
+                        .iter()
+                        .map(|name| serde_json::json!({ "name": name }))
+                        .collect::<Vec<_>>(),
+                })
+                .to_string()
+                .into_bytes(),
+            ),
+            commands::ChargingState::GET_COMMAND_ID => (
+                commands::ChargingState::GET_REPLY_COMMAND_ID,
+                charging_state_data(state.charging_state),
+            ),
+            commands::DeviceName::GET_COMMAND_ID => (
+                commands::DeviceName::GET_REPLY_COMMAND_ID,
+                state.name.clone().into_bytes(),
+            ),
+            commands::FirmwareUpdate::GET_COMMAND_ID => (
+                commands::FirmwareUpdate::GET_REPLY_COMMAND_ID,
+                state.firmware_update.clone().into_bytes(),
+            ),
+            commands::PlayControl::GET_COMMAND_ID => (
+                commands::PlayControl::GET_REPLY_COMMAND_ID,
+                play_status_data(state.play_status),
+            ),
+            commands::PlayInfo::GET_COMMAND_ID => (
+                commands::PlayInfo::GET_REPLY_COMMAND_ID,
+                device_json(&state.play_info, "is_from_channel", "isFromChannel"),
+            ),
+            commands::Power::GET_COMMAND_ID => (
+                commands::Power::GET_REPLY_COMMAND_ID,
+                power_data(state.sleeping),
+            ),
+            commands::PowerMode::GET_COMMAND_ID => (
+                commands::PowerMode::GET_REPLY_COMMAND_ID,
+                state.power_mode.clone().into_bytes(),
+            ),
+            commands::PreChannel::GET_COMMAND_ID => (
+                commands::PreChannel::GET_REPLY_COMMAND_ID,
+                device_json(&state.pre_channels, "is_playing", "isPlaying"),
+            ),
+            commands::Volume::GET_COMMAND_ID => (
+                commands::Volume::GET_REPLY_COMMAND_ID,
+                state.volume.to_string().into_bytes(),
+            ),
+            _ => return None,
+        };
+
+        Some((
+            CMD_RESP_PORT,
+            Packet {
+                command_type: commands::COMMAND_TYPE_SET,
+                command: reply_command,
+                status: PacketStatus::Ok,
+                sequence: 0,
+                command_data: Some(data),
+            },
+        ))
+    }
+
+    fn handle_set(state: &mut FakeSpeakerState, command: u16, data: &[u8]) -> Vec<PortAndPacket> {
+        match command {
+            commands::HELLO_COMMAND_ID => {
+                let subscriber =
+                    String::from_utf8_lossy(data)
+                        .split_once(',')
+                        .and_then(|(ip, port)| {
+                            Some(SocketAddr::new(ip.parse().ok()?, port.parse().ok()?))
+                        });
+
+                match subscriber {
+                    Some(subscriber) => {
+                        if !state.subscribers.contains(&subscriber) {
+                            state.subscribers.push(subscriber);
+                        }
+
+                        // assumed, like in commands::Hello, rather than
+                        // checked against a real device
+                        vec![(
+                            CMD_RESP_PORT,
+                            Packet {
+                                command_type: commands::COMMAND_TYPE_SET,
+                                command: commands::HELLO_COMMAND_ID,
+                                status: PacketStatus::Ok,
+                                sequence: 0,
+                                command_data: Some(data.to_vec()),
+                            },
+                        )]
+                    }
+                    None => vec![],
+                }
+            }
+            commands::DeviceName::SET_COMMAND_ID => {
+                state.name = String::from_utf8_lossy(data).to_string();
+                vec![]
+            }
+            commands::FirmwareUpdate::SET_COMMAND_ID => vec![notification(
+                commands::FirmwareUpdate::NOTIFY_ID,
+                state.firmware_update.clone(),
+            )],
+            commands::Power::SET_COMMAND_ID => {
+                state.sleeping = match data {
+                    b"02" => true,
+                    b"00" => false,
+                    _ => return vec![rejected(command)],
+                };
+
+                let mut notifications = vec![notification(
+                    commands::Power::NOTIFY_ID,
+                    power_data(state.sleeping),
+                )];
+
+                if state.sleeping && state.play_status == PlayControlCommand::Play {
+                    state.play_status = PlayControlCommand::Pause;
+                    notifications.push(Self::play_status_notification(state));
+                }
+
+                notifications
+            }
+            commands::PreChannel::SET_COMMAND_ID => {
+                if let Ok(channel) = serde_json::from_slice::<ChannelObject>(data) {
+                    match state
+                        .pre_channels
+                        .iter_mut()
+                        .find(|x| x.channel_id == channel.channel_id)
+                    {
+                        Some(existing) => *existing = channel,
+                        None => state.pre_channels.push(channel),
+                    }
+                }
+
+                vec![]
+            }
+            _ if state.sleeping => vec![],
+            commands::PlayControl::SET_COMMAND_ID => Self::handle_play_control(state, data),
+            commands::PlayInfo::SET_COMMAND_ID => {
+                let play_info = match serde_json::from_slice::<serde_json::Value>(data)
+                    .map(|x| rename_key(x, "is_from_channel", "isFromChannel"))
+                    .and_then(serde_json::from_value::<commands::PlayInfoData>)
+                {
+                    Ok(play_info) => play_info,
+                    Err(_) => return vec![rejected(command)],
+                };
+
+                let channel = state.pre_channels.iter().position(|x| {
+                    x.channel_identity.is_some() && x.channel_identity == play_info.play_identity
+                });
+
+                state.play_info = play_info;
+                Self::play(state, channel)
+            }
+            commands::Volume::SET_COMMAND_ID => {
+                match String::from_utf8_lossy(data).parse::<u8>() {
+                    Ok(volume) if volume <= 100 => state.volume = volume,
+                    _ => return vec![rejected(command)],
+                }
+
+                vec![notification(
+                    commands::Volume::NOTIFY_ID,
+                    state.volume.to_string(),
+                )]
+            }
+            _ => vec![],
+        }
+    }
+
+    fn handle_play_control(state: &mut FakeSpeakerState, data: &[u8]) -> Vec<PortAndPacket> {
+        let channel_count = state.pre_channels.len();
+
+        match data {
+            b"PLAY" => state.play_status = PlayControlCommand::Play,
+            b"PAUSE" => state.play_status = PlayControlCommand::Pause,
+            b"STOP" => state.play_status = PlayControlCommand::Stop,
+            b"TOGGL" => {
+                state.play_status = match state.play_status {
+                    PlayControlCommand::Play => PlayControlCommand::Pause,
+                    _ => PlayControlCommand::Play,
+                }
+            }
+            b"MUTE" => state.muted = true,
+            b"UNMUTE" => state.muted = false,
+            b"NEXT" | b"PREV" if channel_count > 0 => {
+                let next = match (data, state.current_channel) {
+                    (b"NEXT", Some(current)) => (current + 1) % channel_count,
+                    (b"NEXT", None) => 0,
+                    (_, Some(current)) => (current + channel_count - 1) % channel_count,
+                    (_, None) => channel_count - 1,
+                };
+
+                let mut play_info = state.pre_channels[next].play_info_data();
+                play_info.is_from_channel = true;
+                state.play_info = play_info;
+
+                return Self::play(state, Some(next));
+            }
+            _ => return vec![],
+        }
+
+        vec![Self::play_status_notification(state)]
+    }
+
+    /// Starts playing whatever is in `state.play_info`, `channel` being the
+    /// matching favorite if any.
+    fn play(state: &mut FakeSpeakerState, channel: Option<usize>) -> Vec<PortAndPacket> {
+        state.current_channel = channel;
+        state.play_status = PlayControlCommand::Play;
+
+        for (index, channel) in state.pre_channels.iter_mut().enumerate() {
+            channel.is_playing = Some(Some(index) == state.current_channel);
+        }
+
+        vec![
+            notification(
+                commands::PlayInfo::NOTIFY_ID,
+                device_json(&state.play_info, "is_from_channel", "isFromChannel"),
+            ),
+            Self::play_status_notification(state),
+        ]
+    }
+
+    fn play_status_notification(state: &FakeSpeakerState) -> PortAndPacket {
+        notification(
+            commands::PlayControl::NOTIFY_ID,
+            play_status_data(state.play_status),
+        )
+    }
+
+    fn charging_state_notification(state: &FakeSpeakerState) -> PortAndPacket {
+        notification(
+            commands::ChargingState::NOTIFY_ID,
+            charging_state_data(state.charging_state),
+        )
+    }
+}
+
+fn rejected(command: u16) -> PortAndPacket {
+    (
+        CMD_RESP_PORT,
+        Packet {
+            command_type: commands::COMMAND_TYPE_SET,
+            command,
+            status: PacketStatus::Error(INVALID_VALUE_STATUS),
+            sequence: 0,
+            command_data: None,
+        },
+    )
+}
+
+fn notification(command: u16, data: impl Into<Vec<u8>>) -> PortAndPacket {
+    (
+        NOTIF_RECV_PORT,
+        Packet {
+            command_type: commands::COMMAND_TYPE_SET,
+            command,
+            status: PacketStatus::Ok,
+            sequence: 0,
+            command_data: Some(data.into()),
+        },
+    )
+}
+
+fn play_status_data(status: PlayControlCommand) -> Vec<u8> {
+    let index = match status {
+        PlayControlCommand::Play => 0,
+        PlayControlCommand::Stop => 1,
+        PlayControlCommand::Pause => 2,
+        PlayControlCommand::Next => 3,
+        PlayControlCommand::Previous => 4,
+        PlayControlCommand::Toggle => 5,
+        PlayControlCommand::Mute => 6,
+        PlayControlCommand::Unmute => 7,
+    };
+
+    vec![b'0' + index]
+}
+
+fn charging_state_data(charging_state: ChargingStateData) -> Vec<u8> {
+    let index = match charging_state {
+        ChargingStateData::Discharging => 0,
+        ChargingStateData::PluggedInCharging => 1,
+        ChargingStateData::PluggedInCharged => 2,
+        ChargingStateData::PluggedInNotCharging => 3,
+    };
+
+    vec![b'0' + index]
+}
+
+fn power_data(sleeping: bool) -> Vec<u8> {
+    if sleeping { "02" } else { "00" }.as_bytes().to_vec()
+}
+
+/// Serializes `value` to JSON the way a device does, the device using
+/// `device_key` instead of the `key` field name in objects.
+fn device_json<T: Serialize>(value: &T, key: &str, device_key: &str) -> Vec<u8> {
+    serde_json::to_value(value)
+        .map(|x| rename_key(x, key, device_key))
+        .map(|x| x.to_string().into_bytes())
+        .unwrap_or_default()
+}
+
+fn rename_key(value: serde_json::Value, from: &str, to: &str) -> serde_json::Value {
+    match value {
+        serde_json::Value::Array(values) => values
+            .into_iter()
+            .map(|x| rename_key(x, from, to))
+            .collect(),
+        serde_json::Value::Object(mut map) => {
+            if let Some(x) = map.remove(from) {
+                map.insert(to.to_owned(), x);
+            }
+            serde_json::Value::Object(map)
+        }
+        other => other,
+    }
+}
+
 trait FakeSocketMap {
     fn sender(&mut self, port: u16) -> mpsc::Sender<AddressAndPacket>;
     fn receiver(&mut self, port: u16) -> Arc<Mutex<mpsc::Receiver<AddressAndPacket>>>;
@@ -46,14 +736,247 @@ impl FakeSocketMap for HashMap<u16, FakeSocket> {
     }
 }
 
+type FakeSpeakers = Arc<Vec<Arc<FakeSpeaker>>>;
+
+const TICK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Something happening to a fake speaker behind the client's back, see
+/// [`FakeEnvironment::with_event`].
+#[derive(Clone, Debug)]
+pub enum FakeEvent {
+    /// The speaker stops answering packets and announcing itself
+    Disappear,
+    /// The speaker comes back and announces itself
+    Reappear,
+    /// The speaker moves to another address, and announces itself there
+    ChangeAddress(IpAddr),
+    BatteryLevel(u8),
+    ChargingState(ChargingStateData),
+    /// Something else starts playing on the speaker
+    PlayInfo(Box<commands::PlayInfoData>),
+}
+
+/// Builds a fake network with any number of speakers, along with a script of
+/// events happening to them and imperfect network conditions.
+pub struct FakeEnvironment {
+    speakers: Vec<FakeSpeakerState>,
+    events: Vec<(Duration, String, FakeEvent)>,
+    packet_loss: f64,
+    delay: Duration,
+    seed: u64,
+}
+
+impl Default for FakeEnvironment {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FakeEnvironment {
+    pub fn new() -> FakeEnvironment {
+        FakeEnvironment {
+            speakers: vec![],
+            events: vec![],
+            packet_loss: 0.0,
+            delay: Duration::ZERO,
+            seed: 0x2545_f491_4f6c_dd1d,
+        }
+    }
+
+    pub fn with_speaker(mut self, speaker: FakeSpeakerState) -> Self {
+        self.speakers.push(speaker);
+        self
+    }
+
+    /// Schedules `event` to happen to the speaker with ID `device_id`, `at`
+    /// being counted from the moment the environment is built.
+    pub fn with_event(mut self, at: Duration, device_id: &str, event: FakeEvent) -> Self {
+        self.events.push((at, device_id.to_owned(), event));
+        self
+    }
+
+    /// Drops packets, in both directions, with probability `ratio`
+    pub fn with_packet_loss(mut self, ratio: f64) -> Self {
+        self.packet_loss = ratio.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Delays packets sent by the speakers
+    pub fn with_delay(mut self, delay: Duration) -> Self {
+        self.delay = delay;
+        self
+    }
+
+    /// Seeds the generator deciding which packets get lost, so that flaky
+    /// runs can be reproduced
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = seed.max(1);
+        self
+    }
+
+    pub fn device_manager_config(mut self) -> Result<DeviceManagerConfig> {
+        let speakers: FakeSpeakers = Arc::new(
+            self.speakers
+                .into_iter()
+                .map(|x| Arc::new(FakeSpeaker::new(x)))
+                .collect(),
+        );
+
+        let link = Arc::new(FakeLink {
+            sockets: Mutex::new(HashMap::new()),
+            packet_loss: self.packet_loss,
+            delay: self.delay,
+            rng: Mutex::new(self.seed),
+        });
+
+        let network = FakeNetwork::new(link, Arc::clone(&speakers));
+        let discovery = FakeDeviceDiscovery::new(Arc::clone(&speakers));
+
+        if !self.events.is_empty() {
+            self.events.sort_by_key(|(at, _, _)| *at);
+
+            let link = Arc::downgrade(&network.link);
+            let speakers = Arc::downgrade(&speakers);
+            let announcements = discovery.sender.clone();
+            let events = self.events;
+
+            std::thread::spawn(move || run_script(events, link, speakers, announcements));
+        }
+
+        Ok(DeviceManagerConfig::new(
+            Box::new(network),
+            Box::new(discovery),
+        ))
+    }
+}
+
+/// Plays scripted events at their scheduled time, until the network goes
+/// away
+fn run_script(
+    events: Vec<(Duration, String, FakeEvent)>,
+    link: Weak<FakeLink>,
+    speakers: Weak<Vec<Arc<FakeSpeaker>>>,
+    announcements: mpsc::Sender<DiscoveryReply>,
+) {
+    let start = Instant::now();
+
+    for (at, device_id, event) in events {
+        std::thread::sleep((start + at).saturating_duration_since(Instant::now()));
+
+        let (link, speakers) = match (link.upgrade(), speakers.upgrade()) {
+            (Some(link), Some(speakers)) => (link, speakers),
+            _ => return,
+        };
+
+        let speaker = match speakers.iter().find(|x| x.state().id == device_id) {
+            Some(speaker) => speaker,
+            None => continue,
+        };
+
+        link.send_from_speaker(speaker, speaker.apply(&event));
+
+        if matches!(event, FakeEvent::Reappear | FakeEvent::ChangeAddress(_))
+            && announcements.send(speaker.discovery_reply()).is_err()
+        {
+            return;
+        }
+    }
+}
+
+/// The medium shared by the client side sockets and the speakers. There is a
+/// single client, which gets notifications whether it registered for them
+/// or not.
+struct FakeLink {
+    sockets: Mutex<HashMap<u16, FakeSocket>>,
+    packet_loss: f64,
+    delay: Duration,
+    rng: Mutex<u64>,
+}
+
+impl FakeLink {
+    fn is_lost(&self) -> bool {
+        if self.packet_loss <= 0.0 {
+            return false;
+        }
+
+        // xorshift64, good enough to pick packets to drop
+        let mut rng = self.rng.lock().unwrap();
+        *rng ^= *rng << 13;
+        *rng ^= *rng >> 7;
+        *rng ^= *rng << 17;
+
+        ((*rng >> 11) as f64 / (1u64 << 53) as f64) < self.packet_loss
+    }
+
+    fn send_from_speaker(&self, speaker: &FakeSpeaker, packets: Vec<PortAndPacket>) {
+        if !speaker.is_online() {
+            return;
+        }
+
+        for (port, packet) in packets {
+            if port == NOTIF_RECV_PORT {
+                speaker.notification_sent(SocketAddr::new(FAKE_CLIENT_ADDR, port), &packet);
+            }
+
+            self.deliver(speaker, port, packet);
+        }
+    }
+
+    fn deliver(&self, speaker: &FakeSpeaker, port: u16, packet: Packet) {
+        if !speaker.is_online() || self.is_lost() {
+            return;
+        }
+
+        let sender = self.sockets.lock().unwrap().sender(port);
+        let message = (SocketAddr::new(speaker.addr(), port), packet);
+
+        if self.delay.is_zero() {
+            sender.send(message).expect("error sending packet");
+        } else {
+            let delay = self.delay;
+
+            std::thread::spawn(move || {
+                std::thread::sleep(delay);
+                // the receiving side may be gone by then
+                let _ = sender.send(message);
+            });
+        }
+    }
+}
+
 struct FakeNetwork {
-    reply_senders: Arc<Mutex<HashMap<u16, FakeSocket>>>,
+    link: Arc<FakeLink>,
+    speakers: FakeSpeakers,
 }
 
 impl FakeNetwork {
-    fn new() -> Self {
-        FakeNetwork {
-            reply_senders: Arc::new(Mutex::new(HashMap::new())),
+    fn new(link: Arc<FakeLink>, speakers: FakeSpeakers) -> Self {
+        let network = FakeNetwork { link, speakers };
+
+        let link = Arc::downgrade(&network.link);
+        let speakers = Arc::downgrade(&network.speakers);
+        std::thread::spawn(move || Self::tick_speakers(link, speakers));
+
+        network
+    }
+
+    /// Lets time pass for all speakers, until the network goes away
+    fn tick_speakers(link: Weak<FakeLink>, speakers: Weak<Vec<Arc<FakeSpeaker>>>) {
+        loop {
+            std::thread::sleep(TICK_INTERVAL);
+
+            let (link, speakers) = match (link.upgrade(), speakers.upgrade()) {
+                (Some(link), Some(speakers)) => (link, speakers),
+                _ => return,
+            };
+
+            for speaker in speakers.iter() {
+                link.send_from_speaker(speaker, speaker.tick());
+
+                for (client, packet) in speaker.notifications_to_resend() {
+                    link.deliver(speaker, client.port(), packet);
+                }
+            }
         }
     }
 }
@@ -61,116 +984,55 @@ impl FakeNetwork {
 impl NetworkImpl for FakeNetwork {
     fn packet_sender(&self) -> Result<Box<dyn PacketSender + Send>> {
         Ok(Box::new(FakePacketSender {
-            reply_senders: Arc::clone(&self.reply_senders),
-            playing: Arc::new(Mutex::new(Cell::new(false))),
+            link: Arc::clone(&self.link),
+            speakers: Arc::clone(&self.speakers),
         }))
     }
 
     fn packet_receiver(&self, port: u16) -> Result<Box<dyn PacketReceiver + Send>> {
         Ok(Box::new(FakePacketReceiver {
-            reply_senders: Arc::clone(&self.reply_senders),
+            link: Arc::clone(&self.link),
             port,
         }))
     }
-}
 
-struct FakePacketSender {
-    reply_senders: Arc<Mutex<HashMap<u16, FakeSocket>>>,
-    playing: Arc<Mutex<Cell<bool>>>,
+    fn local_addr_for(&self, _device_addr: IpAddr) -> Result<IpAddr> {
+        Ok(FAKE_CLIENT_ADDR)
+    }
 }
 
-impl FakePacketSender {
-    fn reply(&self, to_addr: SocketAddr, packet: Packet) {
-        let reply_senders = Arc::clone(&self.reply_senders);
-        let mut reply_senders = reply_senders.lock().unwrap();
-        reply_senders
-            .sender(to_addr.port())
-            .send((to_addr, packet))
-            .expect("error sending packet");
-    }
+struct FakePacketSender {
+    link: Arc<FakeLink>,
+    speakers: FakeSpeakers,
 }
 
 impl PacketSender for FakePacketSender {
     fn send_packet(&self, packet: &Packet, to: SocketAddr) -> Result<usize> {
-        println!("Sending {:?} to {}", packet, to);
-
-        match packet.command_type {
-            commands::COMMAND_TYPE_FETCH => match packet.command {
-                commands::DeviceName::GET_COMMAND_ID => {
-                    println!("faking DeviceName reply");
-                    self.reply(
-                        SocketAddr::new(to.ip(), CMD_RESP_PORT),
-                        Packet {
-                            command_type: commands::COMMAND_TYPE_SET,
-                            command: commands::DeviceName::SET_COMMAND_ID,
-                            command_data: Some("Pretty name".as_bytes().to_vec()),
-                        },
-                    )
-                }
-                commands::Volume::GET_COMMAND_ID => {
-                    println!("faking Volume reply");
-                    self.reply(
-                        SocketAddr::new(to.ip(), CMD_RESP_PORT),
-                        Packet {
-                            command_type: commands::COMMAND_TYPE_SET,
-                            command: commands::Volume::SET_COMMAND_ID,
-                            command_data: Some("35".as_bytes().to_vec()),
-                        },
-                    )
-                }
-                _ => {}
-            },
+        tracing::trace!(
+            addr = %to.ip(),
+            port = to.port(),
+            command = commands::command_name(packet.command).unwrap_or("unknown"),
+            "fake network carrying {:?}",
+            packet
+        );
 
-            commands::COMMAND_TYPE_SET => match packet.command {
-                commands::Volume::SET_COMMAND_ID => {
-                    println!("faking Volume notification");
-                    self.reply(
-                        SocketAddr::new(to.ip(), NOTIF_RECV_PORT),
-                        Packet {
-                            command_type: commands::COMMAND_TYPE_SET,
-                            command: commands::Volume::NOTIFY_ID,
-                            command_data: packet.command_data.clone(),
-                        },
-                    )
-                }
-                commands::PlayControl::SET_COMMAND_ID => {
-                    println!("faking PlayControl notification");
-                    let now_playing = {
-                        let playing = Arc::clone(&self.playing);
-                        let playing = playing.lock().unwrap();
-                        let command_data =
-                            String::from_utf8_lossy(packet.command_data.as_ref().unwrap());
-
-                        match command_data.as_ref() {
-                            "PLAY" => {
-                                playing.set(true);
-                            }
-                            "PAUSE" | "STOP" => {
-                                playing.set(false);
-                            }
-                            "TOGGL" => {
-                                playing.set(!playing.get());
-                            }
-                            _ => {}
-                        };
-
-                        playing.get()
-                    };
-                    let notification_data: Vec<u8> = vec![if now_playing { 48 } else { 49 }];
-
-                    self.reply(
-                        SocketAddr::new(to.ip(), NOTIF_RECV_PORT),
-                        Packet {
-                            command_type: commands::COMMAND_TYPE_SET,
-                            command: commands::PlayControl::NOTIFY_ID,
-                            command_data: Some(notification_data),
-                        },
-                    )
-                }
-                _ => {}
-            },
+        if to.port() == CMD_SEND_PORT && !self.link.is_lost() {
+            if let Some(speaker) = self
+                .speakers
+                .iter()
+                .find(|x| x.is_online() && x.addr() == to.ip())
+            {
+                self.link
+                    .send_from_speaker(speaker, speaker.handle_packet(packet));
+            }
+        }
 
-            _ => {}
+        if to.port() == NOTIF_ACK_PORT && !self.link.is_lost() {
+            if let Some(speaker) = self.speakers.iter().find(|x| x.addr() == to.ip()) {
+                if !speaker.handle_ack(FAKE_CLIENT_ADDR, packet) {
+                    tracing::warn!(addr = %to.ip(), "unexpected notification ack: {:?}", packet);
+                }
+            }
         }
 
         Ok(packet.data().len())
@@ -179,35 +1041,38 @@ impl PacketSender for FakePacketSender {
 
 struct FakePacketReceiver {
     port: u16,
-    reply_senders: Arc<Mutex<HashMap<u16, FakeSocket>>>,
+    link: Arc<FakeLink>,
 }
 
 impl PacketReceiver for FakePacketReceiver {
-    fn receive_packet(&self) -> Result<(SocketAddr, Packet)> {
-        let receiver = {
-            let reply_senders = Arc::clone(&self.reply_senders);
-            let mut reply_senders = reply_senders.lock().unwrap();
-            Arc::clone(&reply_senders.receiver(self.port))
-        };
+    fn receive_packet(&self, timeout: Duration) -> Result<Option<(SocketAddr, Packet)>> {
+        let receiver = self.link.sockets.lock().unwrap().receiver(self.port);
         let receiver = receiver.lock().unwrap();
-        receiver.recv().map_err(|err| err.into())
+
+        match receiver.recv_timeout(timeout) {
+            Ok(x) => Ok(Some(x)),
+            Err(mpsc::RecvTimeoutError::Timeout) => Ok(None),
+            Err(mpsc::RecvTimeoutError::Disconnected) => Err(Error::Shutdown),
+        }
     }
 }
 
 const FAKE_DEVICE_ADDR: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 168, 10, 10));
-const FAKE_DEVICE_PORT: u16 = 7777;
+const FAKE_CLIENT_ADDR: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 168, 10, 1));
 
 struct FakeDeviceDiscovery {
     sender: mpsc::Sender<DiscoveryReply>,
-    receiver: mpsc::Receiver<DiscoveryReply>,
+    receiver: Mutex<mpsc::Receiver<DiscoveryReply>>,
+    speakers: FakeSpeakers,
 }
 
 impl FakeDeviceDiscovery {
-    fn new() -> Self {
+    fn new(speakers: FakeSpeakers) -> Self {
         let (tx, rx) = mpsc::channel();
         FakeDeviceDiscovery {
             sender: tx,
-            receiver: rx,
+            receiver: Mutex::new(rx),
+            speakers,
         }
     }
 }
@@ -215,51 +1080,445 @@ impl FakeDeviceDiscovery {
 impl DeviceDiscoveryImpl for FakeDeviceDiscovery {
     fn discover(&self) -> Result<()> {
         let sender = self.sender.clone();
+        let speakers = Arc::clone(&self.speakers);
 
         std::thread::spawn(move || {
             std::thread::sleep(std::time::Duration::from_secs(1));
 
-            sender
-                .send(DiscoveryReply {
-                    device_name: "Test device".to_owned(),
-                    device_id: "test-device".to_owned(),
-                    device_state: String::new(),
-                    port: FAKE_DEVICE_PORT,
-                    zone_id: String::new(),
-                    creator: String::new(),
-                    ip_address: FAKE_DEVICE_ADDR,
-                    color_code: String::new(),
-                    firmware_version: String::new(),
-                    stereo_pair_id: String::new(),
-                })
-                .expect("error sending discovery packet");
+            for speaker in speakers.iter().filter(|x| x.is_online()) {
+                // the device manager may have been shut down meanwhile
+                if sender.send(speaker.discovery_reply()).is_err() {
+                    return;
+                }
+            }
         });
 
         Ok(())
     }
 
-    fn poll(&self) -> Result<DiscoveryReply> {
-        let packet = self
-            .receiver
-            .recv()
-            .expect("error receiving discovery packet");
-        Ok(packet)
+    fn poll(&self, timeout: Duration) -> Result<Option<DiscoveryReply>> {
+        // we hold a sender ourselves, so the channel never gets disconnected
+        Ok(self.receiver.lock().unwrap().recv_timeout(timeout).ok())
     }
 }
 
-impl DeviceDiscoveryImpl for std::sync::Mutex<FakeDeviceDiscovery> {
-    fn discover(&self) -> Result<()> {
-        self.lock().unwrap().discover()
+/// Returns a configuration for a fake network with a single speaker, with
+/// ID "test-device".
+pub fn device_manager_config() -> Result<DeviceManagerConfig> {
+    FakeEnvironment::new()
+        .with_speaker(FakeSpeakerState::new(
+            "test-device",
+            "Pretty name",
+            FAKE_DEVICE_ADDR,
+        ))
+        .device_manager_config()
+}
+
+/// An environment with a few speakers and some things happening to them,
+/// handy to exercise a client by hand.
+pub fn demo_environment() -> FakeEnvironment {
+    let addr = |x| IpAddr::V4(Ipv4Addr::new(192, 168, 10, x));
+
+    let mut bathroom = FakeSpeakerState::new("demo-bathroom", "Bathroom", addr(12));
+    bathroom.battery_level = 12;
+    bathroom.battery_interval = Duration::from_secs(10);
+    bathroom.color_code = "2003".to_owned();
+
+    FakeEnvironment::new()
+        .with_speaker(FakeSpeakerState::new("demo-kitchen", "Kitchen", addr(10)))
+        .with_speaker(FakeSpeakerState::new("demo-bedroom", "Bedroom", addr(11)))
+        .with_speaker(bathroom)
+        .with_event(
+            Duration::from_secs(15),
+            "demo-kitchen",
+            FakeEvent::PlayInfo(Box::new(commands::PlayInfoData {
+                play_title: Some("Track from another app".to_owned()),
+                play_artist: Some("Someone else".to_owned()),
+                ..commands::PlayInfoData::default()
+            })),
+        )
+        .with_event(
+            Duration::from_secs(30),
+            "demo-bedroom",
+            FakeEvent::Disappear,
+        )
+        .with_event(
+            Duration::from_secs(60),
+            "demo-bathroom",
+            FakeEvent::ChargingState(ChargingStateData::PluggedInCharging),
+        )
+        .with_event(
+            Duration::from_secs(90),
+            "demo-bedroom",
+            FakeEvent::ChangeAddress(addr(21)),
+        )
+        .with_event(Duration::from_secs(90), "demo-bedroom", FakeEvent::Reappear)
+        .with_packet_loss(0.05)
+        .with_delay(Duration::from_millis(50))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::{DeviceManager, DeviceManagerEvent, PropertyValue};
+
+    fn speaker() -> FakeSpeaker {
+        FakeSpeaker::new(FakeSpeakerState::new(
+            "test-device",
+            "Pretty name",
+            FAKE_DEVICE_ADDR,
+        ))
     }
 
-    fn poll(&self) -> Result<DiscoveryReply> {
-        self.lock().unwrap().poll()
+    fn fetch<C: Command>(speaker: &FakeSpeaker) -> C::ResponseData {
+        let replies = speaker.handle_packet(&C::fetch());
+        assert_eq!(replies.len(), 1, "expected one reply to {}", C::NAME);
+
+        let (port, reply) = &replies[0];
+        assert_eq!(*port, CMD_RESP_PORT);
+        assert_eq!(reply.command, C::GET_REPLY_COMMAND_ID);
+
+        C::unmarshal_data(reply.command_data.as_deref().unwrap()).unwrap()
     }
-}
 
-pub fn device_manager_config() -> Result<DeviceManagerConfig> {
-    Ok(DeviceManagerConfig::new(
-        Box::new(FakeNetwork::new()),
-        Box::new(std::sync::Mutex::new(FakeDeviceDiscovery::new())),
-    ))
+    fn notified_commands(packets: &[PortAndPacket]) -> Vec<u16> {
+        packets
+            .iter()
+            .map(|(port, packet)| {
+                assert_eq!(*port, NOTIF_RECV_PORT);
+                packet.command
+            })
+            .collect()
+    }
+
+    #[test]
+    fn fetch_test() {
+        let speaker = speaker();
+
+        assert_eq!(fetch::<commands::BatteryLevel>(&speaker), 80);
+        assert_eq!(
+            fetch::<commands::Capabilities>(&speaker).capabilities.len(),
+            4
+        );
+        assert_eq!(
+            fetch::<commands::ChargingState>(&speaker),
+            ChargingStateData::Discharging
+        );
+        assert_eq!(fetch::<commands::DeviceName>(&speaker), "Pretty name");
+        assert_eq!(fetch::<commands::FirmwareUpdate>(&speaker), "0");
+        assert_eq!(
+            fetch::<commands::PlayControl>(&speaker),
+            PlayControlCommand::Stop
+        );
+        assert!(!fetch::<commands::PlayInfo>(&speaker).is_from_channel);
+        fetch::<commands::Power>(&speaker);
+        assert_eq!(fetch::<commands::PowerMode>(&speaker), "0");
+        assert_eq!(fetch::<commands::PreChannel>(&speaker).len(), 3);
+        assert_eq!(fetch::<commands::Volume>(&speaker), 35);
+    }
+
+    #[test]
+    fn set_test() {
+        let speaker = speaker();
+
+        let notifications = speaker.handle_packet(&commands::Volume::set(42).unwrap());
+        assert_eq!(
+            notified_commands(&notifications),
+            [commands::Volume::NOTIFY_ID]
+        );
+        assert_eq!(fetch::<commands::Volume>(&speaker), 42);
+
+        let replies = speaker.handle_packet(&Packet {
+            sequence: 7,
+            command_data: Some(b"150".to_vec()),
+            ..commands::Volume::set(0).unwrap()
+        });
+        assert_eq!(replies.len(), 1);
+        let (port, reply) = &replies[0];
+        assert_eq!(*port, CMD_RESP_PORT);
+        assert_eq!(reply.status, PacketStatus::Error(INVALID_VALUE_STATUS));
+        assert_eq!(reply.sequence, 7);
+        assert_eq!(fetch::<commands::Volume>(&speaker), 42);
+
+        assert!(speaker
+            .handle_packet(&commands::DeviceName::set("Kitchen".to_owned()).unwrap())
+            .is_empty());
+        assert_eq!(fetch::<commands::DeviceName>(&speaker), "Kitchen");
+
+        speaker.handle_packet(&commands::PlayControl::set(PlayControlCommand::Toggle).unwrap());
+        assert_eq!(
+            fetch::<commands::PlayControl>(&speaker),
+            PlayControlCommand::Play
+        );
+
+        let notifications =
+            speaker.handle_packet(&commands::Power::set(commands::PowerState::Sleep).unwrap());
+        assert_eq!(
+            notified_commands(&notifications),
+            [commands::Power::NOTIFY_ID, commands::PlayControl::NOTIFY_ID]
+        );
+        assert_eq!(
+            fetch::<commands::PlayControl>(&speaker),
+            PlayControlCommand::Pause
+        );
+
+        // playback and volume changes are ignored while asleep
+        assert!(speaker
+            .handle_packet(&commands::Volume::set(10).unwrap())
+            .is_empty());
+        assert_eq!(fetch::<commands::Volume>(&speaker), 42);
+    }
+
+    #[test]
+    fn favorites_test() {
+        let speaker = speaker();
+
+        let notifications = speaker
+            .handle_packet(&commands::PlayControl::set(PlayControlCommand::Previous).unwrap());
+        assert_eq!(
+            notified_commands(&notifications),
+            [
+                commands::PlayInfo::NOTIFY_ID,
+                commands::PlayControl::NOTIFY_ID
+            ]
+        );
+        assert_eq!(
+            fetch::<commands::PlayInfo>(&speaker).play_title.as_deref(),
+            Some("KEXP")
+        );
+
+        speaker.handle_packet(&commands::PlayControl::set(PlayControlCommand::Next).unwrap());
+        let play_info = fetch::<commands::PlayInfo>(&speaker);
+        assert!(play_info.is_from_channel);
+        assert_eq!(play_info.play_title.as_deref(), Some("Radio Paradise"));
+
+        let channels = fetch::<commands::PreChannel>(&speaker);
+        assert_eq!(channels[0].is_playing, Some(true));
+        assert_eq!(channels[2].is_playing, Some(false));
+
+        speaker.handle_packet(&commands::PlayInfo::set(channels[1].play_info_data()).unwrap());
+        assert_eq!(speaker.state().current_channel, Some(1));
+    }
+
+    #[test]
+    fn ack_test() {
+        let speaker = speaker();
+        let client = SocketAddr::new(FAKE_CLIENT_ADDR, NOTIF_RECV_PORT);
+
+        let notifications = speaker.handle_packet(&commands::Volume::set(42).unwrap());
+        let (_, first) = &notifications[0];
+        speaker.notification_sent(client, first);
+
+        let notifications = speaker.handle_packet(&commands::Volume::set(43).unwrap());
+        let (_, second) = &notifications[0];
+        speaker.notification_sent(client, second);
+
+        assert_ne!(first.sequence, second.sequence);
+        assert_eq!(speaker.unacked_notifications(), 2);
+
+        let ack = NotificationAck::from_packet(second).packet();
+
+        // acks only count when they come from the client a notification was
+        // sent to
+        assert!(!speaker.handle_ack(FAKE_DEVICE_ADDR, &ack));
+        assert!(speaker.handle_ack(FAKE_CLIENT_ADDR, &ack));
+        assert_eq!(speaker.unacked_notifications(), 1);
+
+        // and when they are well formed
+        assert!(!speaker.handle_ack(FAKE_CLIENT_ADDR, first));
+
+        assert!(speaker.handle_ack(FAKE_CLIENT_ADDR, &ack));
+        assert_eq!(speaker.unacked_notifications(), 0);
+        assert!(!speaker.handle_ack(FAKE_CLIENT_ADDR, &ack));
+    }
+
+    #[test]
+    fn wrong_ack_test() {
+        let speaker = speaker();
+        let client = SocketAddr::new(FAKE_CLIENT_ADDR, NOTIF_RECV_PORT);
+
+        let notifications = speaker.handle_packet(&commands::Volume::set(42).unwrap());
+        let (_, notification) = &notifications[0];
+        speaker.notification_sent(client, notification);
+        let sent_at = Instant::now();
+
+        let ack = NotificationAck::from_packet(notification).packet();
+        let wrong_acks = [
+            // echoing the notification instead of sending an ack
+            notification.clone(),
+            Packet {
+                command_type: commands::COMMAND_TYPE_FETCH,
+                ..ack.clone()
+            },
+            Packet {
+                command: commands::Volume::NOTIFY_ID,
+                ..ack.clone()
+            },
+            Packet {
+                command_data: Some(vec![0x30]),
+                ..ack
+            },
+        ];
+
+        for ack in &wrong_acks {
+            assert!(!speaker.handle_ack(FAKE_CLIENT_ADDR, ack));
+        }
+
+        assert_eq!(speaker.unacked_notifications(), 1);
+        assert!(speaker.notifications_to_resend_at(sent_at).is_empty());
+        assert_eq!(
+            speaker.notifications_to_resend_at(sent_at + ACK_TIMEOUT),
+            [(client, notification.clone())]
+        );
+    }
+
+    #[test]
+    fn device_manager_ack_test() {
+        let speakers: FakeSpeakers = Arc::new(vec![Arc::new(speaker())]);
+        let link = Arc::new(FakeLink {
+            sockets: Mutex::new(HashMap::new()),
+            packet_loss: 0.0,
+            delay: Duration::ZERO,
+            rng: Mutex::new(1),
+        });
+
+        let config = DeviceManagerConfig::new(
+            Box::new(FakeNetwork::new(link, Arc::clone(&speakers))),
+            Box::new(FakeDeviceDiscovery::new(Arc::clone(&speakers))),
+        );
+
+        let device_manager = DeviceManager::new(config.with_discovery_interval(None)).unwrap();
+        let events = device_manager.listen();
+
+        loop {
+            if let DeviceManagerEvent::DeviceDiscovered(_) =
+                events.recv_timeout(Duration::from_secs(5)).unwrap()
+            {
+                break;
+            }
+        }
+
+        device_manager.set_volume("test-device", 42).unwrap();
+
+        let deadline = Instant::now() + ACK_TIMEOUT;
+
+        while speakers[0].unacked_notifications() > 0 {
+            assert!(
+                Instant::now() < deadline,
+                "notifications were not acknowledged"
+            );
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn battery_test() {
+        let mut state = FakeSpeakerState::new("test-device", "Pretty name", FAKE_DEVICE_ADDR);
+        state.battery_level = 99;
+        state.battery_interval = Duration::from_millis(10);
+        let speaker = FakeSpeaker::new(state);
+        let start = *speaker.battery_clock.lock().unwrap();
+
+        let notifications = speaker.tick_at(start + Duration::from_millis(25));
+        assert_eq!(
+            notified_commands(&notifications),
+            [commands::BatteryLevel::NOTIFY_ID]
+        );
+        assert_eq!(fetch::<commands::BatteryLevel>(&speaker), 97);
+
+        speaker.state.lock().unwrap().charging_state = ChargingStateData::PluggedInCharging;
+        let notifications = speaker.tick_at(start + Duration::from_millis(75));
+        assert_eq!(
+            notified_commands(&notifications),
+            [
+                commands::BatteryLevel::NOTIFY_ID,
+                commands::ChargingState::NOTIFY_ID
+            ]
+        );
+        assert_eq!(fetch::<commands::BatteryLevel>(&speaker), 100);
+        assert_eq!(
+            fetch::<commands::ChargingState>(&speaker),
+            ChargingStateData::PluggedInCharged
+        );
+    }
+
+    #[test]
+    fn environment_test() {
+        let bedroom_addr: IpAddr = "192.168.10.11".parse().unwrap();
+        let moved_addr: IpAddr = "192.168.10.12".parse().unwrap();
+
+        let config = FakeEnvironment::new()
+            .with_speaker(FakeSpeakerState::new(
+                "kitchen",
+                "Kitchen",
+                FAKE_DEVICE_ADDR,
+            ))
+            .with_speaker(FakeSpeakerState::new("bedroom", "Bedroom", bedroom_addr))
+            .with_event(
+                Duration::from_millis(1500),
+                "bedroom",
+                FakeEvent::BatteryLevel(20),
+            )
+            .with_event(
+                Duration::from_millis(1500),
+                "kitchen",
+                FakeEvent::ChangeAddress(moved_addr),
+            )
+            .device_manager_config()
+            .unwrap();
+
+        let device_manager = DeviceManager::new(config.with_discovery_interval(None)).unwrap();
+        let events = device_manager.listen();
+
+        let mut discovered = vec![];
+        let mut battery_dropped = false;
+        let mut moved = false;
+
+        while !(battery_dropped && moved) {
+            match events.recv_timeout(Duration::from_secs(5)).unwrap() {
+                DeviceManagerEvent::DeviceDiscovered(device) => discovered.push(device.id()),
+                DeviceManagerEvent::DeviceUpdated(device) => {
+                    moved |= device.id() == "kitchen" && device.addr() == moved_addr;
+                }
+                DeviceManagerEvent::DevicePropertyChanged { device_id, new, .. } => {
+                    battery_dropped |=
+                        device_id == "bedroom" && new == PropertyValue::BatteryLevel(20);
+                }
+                event => panic!("unexpected event: {:?}", event),
+            }
+        }
+
+        discovered.sort();
+        assert_eq!(discovered, ["bedroom", "kitchen"]);
+    }
+
+    #[test]
+    fn flaky_network_test() {
+        let get_volume = |environment: FakeEnvironment, timeout: Duration| {
+            let config = environment
+                .with_speaker(FakeSpeakerState::new(
+                    "test-device",
+                    "Pretty name",
+                    FAKE_DEVICE_ADDR,
+                ))
+                .device_manager_config()
+                .unwrap();
+            let device_manager = DeviceManager::new(config.with_discovery_interval(None)).unwrap();
+            let events = device_manager.listen();
+            events.recv_timeout(Duration::from_secs(5)).unwrap();
+
+            device_manager.get::<commands::Volume>("test-device", timeout)
+        };
+
+        let err = get_volume(
+            FakeEnvironment::new().with_packet_loss(1.0),
+            Duration::from_millis(200),
+        )
+        .unwrap_err();
+        assert!(matches!(err, Error::Timeout));
+
+        let delayed = || FakeEnvironment::new().with_delay(Duration::from_millis(300));
+        assert!(get_volume(delayed(), Duration::from_millis(100)).is_err());
+        assert_eq!(get_volume(delayed(), Duration::from_secs(1)).unwrap(), 35);
+    }
 }
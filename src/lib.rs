@@ -1,5 +1,16 @@
+#[cfg(feature = "tokio")]
+pub mod async_device;
+pub mod capture;
+#[cfg(feature = "cli")]
+pub mod cli_util;
 pub mod commands;
 pub mod device;
+pub mod device_handle;
 pub mod discovery_reply;
+mod error;
 pub mod fake;
+pub mod monitor;
 pub mod protocol;
+pub mod subscription;
+
+pub use error::{Error, Result};
@@ -0,0 +1,333 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+
+use crate::commands;
+use crate::device::{
+    DeviceManagerConfig, ShutdownSignal, ThreadsafeDeviceDiscoveryImpl, ThreadsafeNetworkImpl,
+    RECEIVE_TIMEOUT,
+};
+use crate::protocol;
+use crate::protocol::Direction;
+use crate::Result;
+
+#[derive(Clone, Debug, Serialize)]
+pub struct MonitoredPacket {
+    /// Milliseconds since the Unix epoch
+    pub timestamp: u64,
+    pub direction: Direction,
+    /// Address the packet came from, `None` if it could not be received
+    /// properly
+    pub addr: Option<SocketAddr>,
+    /// ID of the device the packet came from or went to, if it was
+    /// discovered already
+    pub device_id: Option<String>,
+    pub command: Option<u16>,
+    /// Whether the command ID is one we know how to decode
+    pub known: bool,
+    pub description: String,
+}
+
+impl std::fmt::Display for MonitoredPacket {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let ms_of_day = self.timestamp % (24 * 3600 * 1000);
+
+        write!(
+            f,
+            "{:02}:{:02}:{:02}.{:03} {:<15} {:<12} {} {:?}",
+            ms_of_day / 3_600_000,
+            (ms_of_day / 60_000) % 60,
+            (ms_of_day / 1000) % 60,
+            ms_of_day % 1000,
+            self.addr
+                .map(|x| x.ip().to_string())
+                .unwrap_or_else(|| "?".to_owned()),
+            self.device_id.as_deref().unwrap_or("?"),
+            self.direction.arrow(),
+            self.direction,
+        )?;
+
+        if !self.known {
+            write!(f, " [UNKNOWN]")?;
+        }
+
+        write!(f, " {}", self.description)
+    }
+}
+
+/// Listens passively to all Libratone traffic reaching this host, without
+/// sending anything. The OS shares incoming packets between sockets bound to
+/// the same port, so a monitor should not run alongside a `DeviceManager`.
+///
+/// Discovery goes through the same [`crate::device::DeviceDiscoveryImpl`] as
+/// the device manager, which only reports device announcements: the M-SEARCH
+/// requests of clients are not shown.
+///
+/// Dropping the monitor stops its threads and closes its sockets.
+pub struct Monitor {
+    packets: mpsc::Receiver<MonitoredPacket>,
+    shutdown: Arc<ShutdownSignal>,
+    threads: Vec<std::thread::JoinHandle<()>>,
+}
+
+type DeviceIds = Arc<Mutex<HashMap<IpAddr, String>>>;
+
+impl Monitor {
+    pub fn new(config: DeviceManagerConfig) -> Result<Monitor> {
+        let (tx, rx) = mpsc::channel();
+        let device_ids: DeviceIds = Arc::new(Mutex::new(HashMap::new()));
+        let shutdown = Arc::new(ShutdownSignal::new());
+        let mut threads = vec![];
+
+        for (port, direction) in [
+            (protocol::CMD_SEND_PORT, Direction::Command),
+            (protocol::CMD_RESP_PORT, Direction::Reply),
+            (protocol::NOTIF_RECV_PORT, Direction::Notification),
+            (protocol::NOTIF_ACK_PORT, Direction::NotificationAck),
+        ] {
+            let thread = Self::spawn_packet_thread(
+                Arc::clone(&config.network_impl),
+                port,
+                direction,
+                Arc::clone(&device_ids),
+                tx.clone(),
+                Arc::clone(&shutdown),
+            );
+
+            match thread {
+                Ok(thread) => threads.push(thread),
+                Err(err) => {
+                    // stops the threads spawned so far
+                    drop(Monitor {
+                        packets: rx,
+                        shutdown,
+                        threads,
+                    });
+                    return Err(err);
+                }
+            }
+        }
+
+        threads.push(Self::spawn_discovery_thread(
+            Arc::clone(&config.device_discovery_impl),
+            Arc::clone(&device_ids),
+            tx,
+            Arc::clone(&shutdown),
+        ));
+
+        Ok(Monitor {
+            packets: rx,
+            shutdown,
+            threads,
+        })
+    }
+
+    pub fn recv(&self) -> Option<MonitoredPacket> {
+        self.packets.recv().ok()
+    }
+
+    fn spawn_packet_thread(
+        network_impl: Arc<ThreadsafeNetworkImpl>,
+        port: u16,
+        direction: Direction,
+        device_ids: DeviceIds,
+        tx: mpsc::Sender<MonitoredPacket>,
+        shutdown: Arc<ShutdownSignal>,
+    ) -> Result<std::thread::JoinHandle<()>> {
+        let packet_receiver = network_impl.packet_receiver(port)?;
+
+        Ok(std::thread::spawn(move || {
+            while !shutdown.is_triggered() {
+                let monitored = match packet_receiver.receive_packet(RECEIVE_TIMEOUT) {
+                    Ok(None) => continue,
+                    Ok(Some((addr, packet))) => {
+                        let device_id = device_ids.lock().unwrap().get(&addr.ip()).cloned();
+                        describe_packet(direction, addr, device_id, &packet)
+                    }
+                    Err(err) => MonitoredPacket {
+                        timestamp: now(),
+                        direction,
+                        addr: None,
+                        device_id: None,
+                        command: None,
+                        known: false,
+                        description: format!("invalid packet: {}", err),
+                    },
+                };
+
+                if tx.send(monitored).is_err() {
+                    return;
+                }
+            }
+        }))
+    }
+
+    fn spawn_discovery_thread(
+        device_discovery_impl: Arc<ThreadsafeDeviceDiscoveryImpl>,
+        device_ids: DeviceIds,
+        tx: mpsc::Sender<MonitoredPacket>,
+        shutdown: Arc<ShutdownSignal>,
+    ) -> std::thread::JoinHandle<()> {
+        std::thread::spawn(move || {
+            while !shutdown.is_triggered() {
+                let monitored = match device_discovery_impl.poll(RECEIVE_TIMEOUT) {
+                    Ok(None) => continue,
+                    Ok(Some(reply)) => {
+                        device_ids
+                            .lock()
+                            .unwrap()
+                            .insert(reply.ip_address, reply.device_id.clone());
+
+                        MonitoredPacket {
+                            timestamp: now(),
+                            direction: Direction::Discovery,
+                            addr: Some(SocketAddr::new(reply.ip_address, reply.port)),
+                            device_id: Some(reply.device_id.clone()),
+                            command: None,
+                            known: true,
+                            description: format!(
+                                "NOTIFY {:?} firmware {} state {:?}",
+                                reply.device_name, reply.firmware_version, reply.device_state
+                            ),
+                        }
+                    }
+                    Err(err) => MonitoredPacket {
+                        timestamp: now(),
+                        direction: Direction::Discovery,
+                        addr: None,
+                        device_id: None,
+                        command: None,
+                        known: false,
+                        description: format!("invalid discovery packet: {}", err),
+                    },
+                };
+
+                if tx.send(monitored).is_err() {
+                    return;
+                }
+            }
+        })
+    }
+}
+
+impl Drop for Monitor {
+    fn drop(&mut self) {
+        self.shutdown.trigger();
+
+        for thread in self.threads.drain(..) {
+            // a thread that panicked has stopped too
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Iterator for Monitor {
+    type Item = MonitoredPacket;
+
+    fn next(&mut self) -> Option<MonitoredPacket> {
+        self.recv()
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|x| x.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+fn describe_packet(
+    direction: Direction,
+    addr: SocketAddr,
+    device_id: Option<String>,
+    packet: &protocol::Packet,
+) -> MonitoredPacket {
+    let description = match direction {
+        Direction::Command => commands::format_command(packet),
+        Direction::Reply => commands::format_reply(packet),
+        Direction::Notification => commands::format_notification(packet),
+        Direction::NotificationAck => format!(
+            "ack {}",
+            commands::command_name(packet.command).unwrap_or("??")
+        ),
+        Direction::Discovery => format!("{:?}", packet),
+    };
+
+    MonitoredPacket {
+        timestamp: now(),
+        direction,
+        addr: Some(addr),
+        device_id,
+        command: Some(packet.command),
+        known: commands::command_name(packet.command).is_some(),
+        description,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr() -> SocketAddr {
+        "192.168.1.10:7778".parse().unwrap()
+    }
+
+    #[test]
+    fn describe_packet_test() {
+        let monitored = describe_packet(
+            Direction::Notification,
+            addr(),
+            Some("0123456789ab".to_owned()),
+            &protocol::Packet {
+                command_type: commands::COMMAND_TYPE_SET,
+                command: 64,
+                status: protocol::PacketStatus::Ok,
+                sequence: protocol::DEFAULT_SEQUENCE,
+                command_data: Some(b"35".to_vec()),
+            },
+        );
+
+        assert!(monitored.known);
+        assert_eq!(monitored.command, Some(64));
+        assert_eq!(monitored.description, "set Volume \"35\"");
+
+        let line = monitored.to_string();
+        assert!(line.contains("192.168.1.10"));
+        assert!(line.contains("0123456789ab"));
+        assert!(line.contains("device -> client Notification"));
+        assert!(!line.contains("[UNKNOWN]"));
+    }
+
+    #[test]
+    fn describe_unknown_packet_test() {
+        let monitored = describe_packet(
+            Direction::Command,
+            addr(),
+            None,
+            &protocol::Packet {
+                command_type: commands::COMMAND_TYPE_FETCH,
+                command: 4242,
+                status: protocol::PacketStatus::Ok,
+                sequence: protocol::DEFAULT_SEQUENCE,
+                command_data: None,
+            },
+        );
+
+        assert!(!monitored.known);
+        assert!(monitored.to_string().contains("[UNKNOWN] fetch ??"));
+    }
+
+    #[test]
+    fn drop_test() {
+        let monitor = Monitor::new(crate::fake::device_manager_config().unwrap()).unwrap();
+
+        // dropping waits for the threads, which notice within a receive
+        // timeout that they should stop
+        let start = std::time::Instant::now();
+        drop(monitor);
+        assert!(start.elapsed() < RECEIVE_TIMEOUT * 3);
+    }
+}
@@ -1,16 +1,90 @@
-use std::net::{SocketAddr, UdpSocket};
+use std::io::ErrorKind;
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+use std::time::Duration;
 
-use anyhow::{anyhow, Result};
+use serde::Serialize;
+
+use crate::error::IoContext;
+use crate::{Error, Result};
 
 pub const CMD_SEND_PORT: u16 = 7777;
 pub const CMD_RESP_PORT: u16 = 7778;
 pub const NOTIF_RECV_PORT: u16 = 3333;
 pub const NOTIF_ACK_PORT: u16 = 3334;
+pub const SSDP_MULTICAST_ADDR: Ipv4Addr = Ipv4Addr::new(239, 255, 255, 250);
+pub const SSDP_MULTICAST_PORT: u16 = 1800; // for some reason not the standard one
+
+/// The two bytes every packet starts with
+pub const MAGIC: [u8; 2] = [0xaa, 0xaa];
+
+/// Sequence of the packets built by [`crate::commands`]. The device manager
+/// gives each request a sequence of its own before sending it.
+pub const DEFAULT_SEQUENCE: u16 = 0x1234;
+
+/// Status byte of the packet header
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PacketStatus {
+    /// Commands, and replies to the commands the device accepted
+    Ok,
+    /// Notifications pushed by devices
+    Notification,
+    /// Reply to a command the device rejected, with the raw status code
+    Error(u8),
+}
+
+impl From<u8> for PacketStatus {
+    fn from(status: u8) -> Self {
+        match status {
+            0 => PacketStatus::Ok,
+            1 => PacketStatus::Notification,
+            _ => PacketStatus::Error(status),
+        }
+    }
+}
+
+impl From<PacketStatus> for u8 {
+    fn from(status: PacketStatus) -> Self {
+        match status {
+            PacketStatus::Ok => 0,
+            PacketStatus::Notification => 1,
+            PacketStatus::Error(status) => status,
+        }
+    }
+}
 
-#[derive(Debug, PartialEq)]
+/// Which way a packet was going, deduced from the port it was sent to or
+/// received on.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub enum Direction {
+    /// Client to device, on the command port
+    Command,
+    /// Device to client, on the command reply port
+    Reply,
+    /// Device to client, on the notification port
+    Notification,
+    /// Client to device, on the notification acknowledgement port
+    NotificationAck,
+    /// Device announcing itself over SSDP
+    Discovery,
+}
+
+impl Direction {
+    pub(crate) fn arrow(&self) -> &'static str {
+        match self {
+            Direction::Command | Direction::NotificationAck => "client -> device",
+            Direction::Reply | Direction::Notification | Direction::Discovery => "device -> client",
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
 pub struct Packet {
     pub command_type: u8,
     pub command: u16,
+    pub status: PacketStatus,
+    /// The two header bytes following the status. Replies echo the sequence
+    /// of the request they answer, notifications have their own.
+    pub sequence: u16,
     pub command_data: Option<Vec<u8>>,
 }
 
@@ -19,17 +93,26 @@ impl Packet {
         const HEADER_LEN: usize = 10;
 
         if data.len() < HEADER_LEN {
-            return Err(anyhow!("packet is too short"));
+            return Err(Error::PacketTooShort);
         }
 
         let data_len: u16 = ((data[8] as u16) << 8) | (data[9] as u16);
 
         if (HEADER_LEN + (data_len as usize)) != data.len() {
-            return Err(anyhow!("incorrect packet length"));
+            return Err(Error::PacketLength {
+                expected: data_len as usize,
+                actual: data.len() - HEADER_LEN,
+            });
+        }
+
+        if data[..2] != MAGIC {
+            return Err(Error::BadMagic([data[0], data[1]]));
         }
 
         let command_type: u8 = data[2];
         let command: u16 = ((data[3] as u16) << 8) | (data[4] as u16);
+        let status = PacketStatus::from(data[5]);
+        let sequence: u16 = ((data[6] as u16) << 8) | (data[7] as u16);
         let command_data = if data_len > 0 {
             Some(data[HEADER_LEN..].to_vec())
         } else {
@@ -39,6 +122,8 @@ impl Packet {
         Ok(Packet {
             command_type,
             command,
+            status,
+            sequence,
             command_data,
         })
     }
@@ -46,14 +131,14 @@ impl Packet {
     pub fn data(&self) -> Vec<u8> {
         let data_len = self.command_data.as_ref().map_or(0, |x| x.len());
         let mut packet_data = vec![
-            0xaa,
-            0xaa,
+            MAGIC[0],
+            MAGIC[1],
             self.command_type,
             ((self.command & 0xff00) >> 8) as u8,
             (self.command & 0xff) as u8,
-            0x00, // command status?
-            0x12, // random byte
-            0x34, // random byte
+            self.status.into(),
+            ((self.sequence & 0xff00) >> 8) as u8,
+            (self.sequence & 0xff) as u8,
             ((data_len & 0xff00) >> 8) as u8,
             (data_len & 0xff) as u8,
         ];
@@ -66,32 +151,87 @@ impl Packet {
     }
 }
 
+/// Command ID of notification acks
+pub const NOTIFICATION_ACK_COMMAND: u16 = 2;
+
+/// Acknowledgement of a notification, sent back to the device on
+/// [`NOTIF_ACK_PORT`]. Devices keep sending a notification until it is
+/// acknowledged.
+///
+/// An ack only echoes the command type of the notification, with
+/// [`NOTIFICATION_ACK_COMMAND`] as its command and no payload. This is the
+/// format the library has always sent. It has not been checked against a
+/// capture of a real device yet, so it is kept as is.
+#[derive(Clone, Debug, PartialEq)]
+pub struct NotificationAck {
+    pub command_type: u8,
+}
+
+impl NotificationAck {
+    /// Returns the ack for `packet`, be it the notification to acknowledge or
+    /// an ack received for it.
+    pub fn from_packet(packet: &Packet) -> NotificationAck {
+        NotificationAck {
+            command_type: packet.command_type,
+        }
+    }
+
+    pub fn packet(&self) -> Packet {
+        Packet {
+            command_type: self.command_type,
+            command: NOTIFICATION_ACK_COMMAND,
+            status: PacketStatus::Ok,
+            sequence: DEFAULT_SEQUENCE,
+            command_data: None,
+        }
+    }
+
+    pub fn acknowledges(&self, notification: &Packet) -> bool {
+        Self::from_packet(notification) == *self
+    }
+}
+
 pub trait PacketSender {
     fn send_packet(&self, packet: &Packet, to: SocketAddr) -> Result<usize>;
 }
 
 impl PacketSender for UdpSocket {
     fn send_packet(&self, packet: &Packet, to: SocketAddr) -> Result<usize> {
-        self.send_to(&packet.data(), to).map_err(|e| e.into())
+        self.send_to(&packet.data(), to)
+            .with_context(|| format!("error sending packet to {}", to))
     }
 }
 
 impl PacketSender for std::sync::Arc<UdpSocket> {
     fn send_packet(&self, packet: &Packet, to: SocketAddr) -> Result<usize> {
-        self.send_to(&packet.data(), to).map_err(|e| e.into())
+        self.send_to(&packet.data(), to)
+            .with_context(|| format!("error sending packet to {}", to))
     }
 }
 
 pub trait PacketReceiver {
-    fn receive_packet(&self) -> Result<(SocketAddr, Packet)>;
+    /// Waits at most `timeout` for a packet, returns `None` if none came.
+    /// Receiving threads rely on the timeout to notice when they should
+    /// stop.
+    fn receive_packet(&self, timeout: Duration) -> Result<Option<(SocketAddr, Packet)>>;
 }
 
 impl PacketReceiver for UdpSocket {
-    fn receive_packet(&self) -> Result<(SocketAddr, Packet)> {
+    fn receive_packet(&self, timeout: Duration) -> Result<Option<(SocketAddr, Packet)>> {
+        // a zero timeout would mean blocking forever
+        self.set_read_timeout(Some(timeout.max(Duration::from_millis(1))))
+            .context("error setting receive timeout")?;
+
         let mut recv_buffer = vec![0; 65536];
-        let (count, from_addr) = self.recv_from(&mut recv_buffer)?;
+        let (count, from_addr) = match self.recv_from(&mut recv_buffer) {
+            Ok(x) => x,
+            Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                return Ok(None)
+            }
+            Err(err) => return Err(err).context("error receiving packet"),
+        };
 
-        Ok((from_addr, Packet::parse(&recv_buffer[..count])?))
+        Ok(Some((from_addr, Packet::parse(&recv_buffer[..count])?)))
     }
 }
 
@@ -99,7 +239,7 @@ impl PacketReceiver for UdpSocket {
 mod tests {
     use super::*;
 
-    const PACKET: &'static [u8] = &[
+    const PACKET: &[u8] = &[
         0xaa, 0xaa, 0x02, 0x00, 0x0e, 0x01, 0x00, 0x00, 0x00, 0x01, 0x30,
     ];
 
@@ -110,8 +250,64 @@ mod tests {
             Packet {
                 command_type: 2,
                 command: 14,
+                status: PacketStatus::Notification,
+                sequence: 0,
                 command_data: Some(vec![0x30]),
             },
         )
     }
+
+    #[test]
+    fn parse_error_test() {
+        let mut data = PACKET.to_vec();
+        data[1] = 0xab;
+        assert!(matches!(
+            Packet::parse(&data),
+            Err(Error::BadMagic([0xaa, 0xab]))
+        ));
+
+        assert!(matches!(
+            Packet::parse(&PACKET[..5]),
+            Err(Error::PacketTooShort)
+        ));
+        assert!(matches!(
+            Packet::parse(&PACKET[..10]),
+            Err(Error::PacketLength {
+                expected: 1,
+                actual: 0
+            })
+        ));
+    }
+
+    #[test]
+    fn status_test() {
+        let mut data = PACKET.to_vec();
+        data[5] = 0x05;
+        let packet = Packet::parse(&data).unwrap();
+        assert_eq!(packet.status, PacketStatus::Error(5));
+        assert_eq!(packet.data(), data);
+
+        data[6] = 0xbe;
+        data[7] = 0xef;
+        assert_eq!(Packet::parse(&data).unwrap().sequence, 0xbeef);
+    }
+
+    #[test]
+    fn notification_ack_test() {
+        let notification = Packet::parse(PACKET).unwrap();
+        let ack = NotificationAck::from_packet(&notification);
+
+        assert_eq!(
+            ack.packet().data(),
+            [0xaa, 0xaa, 0x02, 0x00, 0x02, 0x00, 0x12, 0x34, 0x00, 0x00]
+        );
+        assert!(ack.acknowledges(&notification));
+        assert!(NotificationAck::from_packet(&ack.packet()).acknowledges(&notification));
+
+        let fetch = Packet {
+            command_type: 1,
+            ..notification
+        };
+        assert!(!ack.acknowledges(&fetch));
+    }
 }
@@ -0,0 +1,497 @@
+use std::collections::{HashSet, VecDeque};
+use std::sync::mpsc::{RecvError, RecvTimeoutError, TryRecvError};
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::time::{Duration, Instant};
+
+use crate::device::{DeviceManagerEvent, EventKind};
+
+/// What to do with new events when a bounded subscription is full, because
+/// its receiver does not keep up.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LagPolicy {
+    /// Discard the oldest queued event
+    DropOldest,
+    /// Replace the queued event that the new one supersedes: the same kind of
+    /// event for the same device, and the same property for property
+    /// changes. The oldest queued event is discarded if there is none.
+    CoalescePerDevice,
+}
+
+/// Selects the events a [`Subscription`] receives, and how many of them it
+/// queues. The default filter lets all events through, and queues as many
+/// of them as needed.
+#[derive(Clone, Debug, Default)]
+pub struct EventFilter {
+    device_ids: Option<HashSet<String>>,
+    kinds: Option<HashSet<EventKind>>,
+    bound: Option<(usize, LagPolicy)>,
+}
+
+impl EventFilter {
+    pub fn all() -> EventFilter {
+        EventFilter::default()
+    }
+
+    /// Only lets through events about the given devices
+    pub fn with_devices<I, S>(mut self, device_ids: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.device_ids = Some(device_ids.into_iter().map(Into::into).collect());
+        self
+    }
+
+    /// Only lets through events of the given kinds
+    pub fn with_kinds<I: IntoIterator<Item = EventKind>>(mut self, kinds: I) -> Self {
+        self.kinds = Some(kinds.into_iter().collect());
+        self
+    }
+
+    /// Queues at most `capacity` events, applying `lag_policy` to the ones
+    /// that don't fit. A capacity of 0 leaves the subscription unbounded.
+    pub fn with_capacity(mut self, capacity: usize, lag_policy: LagPolicy) -> Self {
+        self.bound = (capacity > 0).then_some((capacity, lag_policy));
+        self
+    }
+
+    pub fn matches(&self, event: &DeviceManagerEvent) -> bool {
+        self.device_ids
+            .as_ref()
+            .is_none_or(|ids| ids.contains(event.device_id()))
+            && self
+                .kinds
+                .as_ref()
+                .is_none_or(|kinds| kinds.contains(&event.kind()))
+    }
+}
+
+#[derive(Default)]
+struct Queue {
+    events: VecDeque<DeviceManagerEvent>,
+    /// Events that were discarded or merged because the queue was full
+    dropped: u64,
+    /// Set when the subscription is cancelled, or the device manager is gone
+    closed: bool,
+}
+
+#[derive(Default)]
+struct Shared {
+    queue: Mutex<Queue>,
+    ready: Condvar,
+}
+
+impl Shared {
+    fn close(&self) {
+        let mut queue = self.queue.lock().unwrap();
+        queue.closed = true;
+        queue.events.clear();
+        self.ready.notify_all();
+    }
+}
+
+/// Whether `event` makes the queued event `other` obsolete, see
+/// [`LagPolicy::CoalescePerDevice`]
+fn supersedes(event: &DeviceManagerEvent, other: &DeviceManagerEvent) -> bool {
+    match (event, other) {
+        (
+            DeviceManagerEvent::DevicePropertyChanged {
+                device_id,
+                property,
+                ..
+            },
+            DeviceManagerEvent::DevicePropertyChanged {
+                device_id: other_device_id,
+                property: other_property,
+                ..
+            },
+        ) => device_id == other_device_id && property == other_property,
+        _ => event.kind() == other.kind() && event.device_id() == other.device_id(),
+    }
+}
+
+/// Merges `event` into the event it supersedes, so that property changes
+/// still start from the value the receiver last saw. Returns `None` if the
+/// property went back to that value.
+fn coalesce(queued: DeviceManagerEvent, event: DeviceManagerEvent) -> Option<DeviceManagerEvent> {
+    match (queued, event) {
+        (
+            DeviceManagerEvent::DevicePropertyChanged { old, .. },
+            DeviceManagerEvent::DevicePropertyChanged {
+                device_id,
+                property,
+                new,
+                ..
+            },
+        ) => (old.as_ref() != Some(&new)).then_some(DeviceManagerEvent::DevicePropertyChanged {
+            device_id,
+            property,
+            old,
+            new,
+        }),
+        (_, event) => Some(event),
+    }
+}
+
+enum Sink {
+    Channel(std::sync::mpsc::Sender<DeviceManagerEvent>),
+    Queue(Arc<Shared>),
+}
+
+/// The device manager side of a subscription
+pub(crate) struct Subscriber {
+    filter: EventFilter,
+    sink: Sink,
+}
+
+impl Subscriber {
+    /// Forwards all events to `tx`, see [`crate::device::DeviceManager::listen`]
+    pub(crate) fn channel(tx: std::sync::mpsc::Sender<DeviceManagerEvent>) -> Subscriber {
+        Subscriber {
+            filter: EventFilter::all(),
+            sink: Sink::Channel(tx),
+        }
+    }
+
+    pub(crate) fn new(filter: EventFilter) -> (Subscriber, Subscription) {
+        let shared = Arc::new(Shared::default());
+
+        let subscriber = Subscriber {
+            filter,
+            sink: Sink::Queue(Arc::clone(&shared)),
+        };
+
+        (subscriber, Subscription { shared })
+    }
+
+    /// Whether the receiving end is still there
+    pub(crate) fn is_active(&self) -> bool {
+        match &self.sink {
+            // a closed channel is only noticed when sending
+            Sink::Channel(_) => true,
+            Sink::Queue(shared) => !shared.queue.lock().unwrap().closed,
+        }
+    }
+
+    /// Queues `event` if the filter lets it through. Returns false once the
+    /// receiving end is gone, meaning the subscriber can be discarded.
+    pub(crate) fn deliver(&self, event: &DeviceManagerEvent) -> bool {
+        if !self.filter.matches(event) {
+            return self.is_active();
+        }
+
+        let shared = match &self.sink {
+            Sink::Channel(tx) => return tx.send(event.clone()).is_ok(),
+            Sink::Queue(shared) => shared,
+        };
+
+        let mut queue = shared.queue.lock().unwrap();
+
+        if queue.closed {
+            return false;
+        }
+
+        match self.filter.bound {
+            Some((capacity, _)) if queue.events.len() < capacity => {
+                queue.events.push_back(event.clone())
+            }
+            Some((_, LagPolicy::CoalescePerDevice)) => {
+                queue.dropped += 1;
+
+                match queue.events.iter().rposition(|x| supersedes(event, x)) {
+                    Some(idx) => {
+                        let queued = queue.events.remove(idx).unwrap();
+                        queue.events.extend(coalesce(queued, event.clone()));
+                    }
+                    None => {
+                        queue.events.pop_front();
+                        queue.events.push_back(event.clone());
+                    }
+                }
+            }
+            Some((_, LagPolicy::DropOldest)) => {
+                queue.dropped += 1;
+                queue.events.pop_front();
+                queue.events.push_back(event.clone());
+            }
+            None => queue.events.push_back(event.clone()),
+        }
+
+        shared.ready.notify_all();
+
+        true
+    }
+}
+
+impl Drop for Subscriber {
+    fn drop(&mut self) {
+        // wakes up the receiver, which then sees that no more events will come
+        if let Sink::Queue(shared) = &self.sink {
+            let mut queue = shared.queue.lock().unwrap();
+            queue.closed = true;
+            shared.ready.notify_all();
+        }
+    }
+}
+
+/// Events of a device manager matching an [`EventFilter`], see
+/// [`crate::device::DeviceManager::subscribe`].
+///
+/// The receiving methods mirror those of [`std::sync::mpsc::Receiver`], and
+/// report the subscription as disconnected once it is cancelled or the device
+/// manager is shut down. Dropping the subscription cancels it.
+pub struct Subscription {
+    shared: Arc<Shared>,
+}
+
+impl Subscription {
+    /// Waits for the next event
+    pub fn recv(&self) -> Result<DeviceManagerEvent, RecvError> {
+        let queue = self.shared.queue.lock().unwrap();
+        let mut queue = self
+            .shared
+            .ready
+            .wait_while(queue, |queue| queue.events.is_empty() && !queue.closed)
+            .unwrap();
+
+        queue.events.pop_front().ok_or(RecvError)
+    }
+
+    /// Waits for the next event, for at most `timeout`
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<DeviceManagerEvent, RecvTimeoutError> {
+        let deadline = match Instant::now().checked_add(timeout) {
+            Some(deadline) => deadline,
+            // too far in the future to ever be reached
+            None => return self.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+        let mut queue = self.shared.queue.lock().unwrap();
+
+        loop {
+            if let Some(event) = queue.events.pop_front() {
+                return Ok(event);
+            }
+
+            if queue.closed {
+                return Err(RecvTimeoutError::Disconnected);
+            }
+
+            let now = Instant::now();
+
+            if now >= deadline {
+                return Err(RecvTimeoutError::Timeout);
+            }
+
+            queue = self
+                .shared
+                .ready
+                .wait_timeout(queue, deadline - now)
+                .unwrap()
+                .0;
+        }
+    }
+
+    pub fn try_recv(&self) -> Result<DeviceManagerEvent, TryRecvError> {
+        let mut queue = self.shared.queue.lock().unwrap();
+
+        match queue.events.pop_front() {
+            Some(event) => Ok(event),
+            None if queue.closed => Err(TryRecvError::Disconnected),
+            None => Err(TryRecvError::Empty),
+        }
+    }
+
+    /// Returns an iterator blocking for events, which ends when the
+    /// subscription does.
+    pub fn iter(&self) -> impl Iterator<Item = DeviceManagerEvent> + '_ {
+        std::iter::from_fn(move || self.recv().ok())
+    }
+
+    /// Number of events that were discarded, or merged into others, because
+    /// the subscription was full.
+    pub fn dropped(&self) -> u64 {
+        self.shared.queue.lock().unwrap().dropped
+    }
+
+    /// Returns a handle that cancels the subscription, which can be sent to
+    /// other threads.
+    pub fn unsubscribe_handle(&self) -> UnsubscribeHandle {
+        UnsubscribeHandle(Arc::downgrade(&self.shared))
+    }
+
+    /// Stops receiving events, and discards the ones that are queued
+    pub fn unsubscribe(&self) {
+        self.shared.close();
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        self.unsubscribe();
+    }
+}
+
+/// Cancels a [`Subscription`], see [`Subscription::unsubscribe_handle`]
+#[derive(Clone)]
+pub struct UnsubscribeHandle(Weak<Shared>);
+
+impl UnsubscribeHandle {
+    /// Same as [`Subscription::unsubscribe`]. Does nothing if the
+    /// subscription is gone already.
+    pub fn unsubscribe(&self) {
+        if let Some(shared) = self.0.upgrade() {
+            shared.close();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::{DeviceProperty, PropertyValue};
+
+    fn volume_changed(device_id: &str, old: u8, new: u8) -> DeviceManagerEvent {
+        DeviceManagerEvent::DevicePropertyChanged {
+            device_id: device_id.to_owned(),
+            property: DeviceProperty::Volume,
+            old: Some(PropertyValue::Volume(old)),
+            new: PropertyValue::Volume(new),
+        }
+    }
+
+    fn battery_changed(device_id: &str, new: u8) -> DeviceManagerEvent {
+        DeviceManagerEvent::DevicePropertyChanged {
+            device_id: device_id.to_owned(),
+            property: DeviceProperty::BatteryLevel,
+            old: None,
+            new: PropertyValue::BatteryLevel(new),
+        }
+    }
+
+    fn received(subscription: &Subscription) -> Vec<String> {
+        std::iter::from_fn(|| subscription.try_recv().ok())
+            .map(|event| match event {
+                DeviceManagerEvent::DevicePropertyChanged {
+                    device_id,
+                    old,
+                    new,
+                    ..
+                } => format!("{} {:?} -> {:?}", device_id, old, new),
+                event => panic!("unexpected event: {:?}", event),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn filter_test() {
+        let (subscriber, subscription) = Subscriber::new(
+            EventFilter::all()
+                .with_devices(["kitchen"])
+                .with_kinds([EventKind::DevicePropertyChanged]),
+        );
+
+        assert!(subscriber.deliver(&volume_changed("bedroom", 10, 20)));
+        assert!(subscriber.deliver(&volume_changed("kitchen", 10, 20)));
+
+        assert_eq!(
+            received(&subscription),
+            ["kitchen Some(Volume(10)) -> Volume(20)"]
+        );
+    }
+
+    #[test]
+    fn drop_oldest_test() {
+        let (subscriber, subscription) =
+            Subscriber::new(EventFilter::all().with_capacity(2, LagPolicy::DropOldest));
+
+        for volume in 1..=4 {
+            subscriber.deliver(&volume_changed("kitchen", volume - 1, volume));
+        }
+
+        assert_eq!(subscription.dropped(), 2);
+        assert_eq!(
+            received(&subscription),
+            [
+                "kitchen Some(Volume(2)) -> Volume(3)",
+                "kitchen Some(Volume(3)) -> Volume(4)"
+            ]
+        );
+    }
+
+    #[test]
+    fn zero_capacity_test() {
+        let (subscriber, subscription) =
+            Subscriber::new(EventFilter::all().with_capacity(0, LagPolicy::DropOldest));
+
+        for volume in 1..=4 {
+            subscriber.deliver(&volume_changed("kitchen", volume - 1, volume));
+        }
+
+        assert_eq!(subscription.dropped(), 0);
+        assert_eq!(received(&subscription).len(), 4);
+    }
+
+    #[test]
+    fn coalesce_test() {
+        let (subscriber, subscription) =
+            Subscriber::new(EventFilter::all().with_capacity(2, LagPolicy::CoalescePerDevice));
+
+        subscriber.deliver(&volume_changed("kitchen", 10, 20));
+        subscriber.deliver(&battery_changed("kitchen", 50));
+        subscriber.deliver(&volume_changed("kitchen", 20, 30));
+        // nothing to merge with, the oldest event goes away
+        subscriber.deliver(&volume_changed("bedroom", 5, 6));
+
+        assert_eq!(subscription.dropped(), 2);
+        assert_eq!(
+            received(&subscription),
+            [
+                "kitchen Some(Volume(10)) -> Volume(30)",
+                "bedroom Some(Volume(5)) -> Volume(6)"
+            ]
+        );
+
+        subscriber.deliver(&volume_changed("kitchen", 30, 40));
+        subscriber.deliver(&volume_changed("bedroom", 6, 7));
+        // back to the value the receiver knows about
+        subscriber.deliver(&volume_changed("kitchen", 40, 30));
+
+        assert_eq!(
+            received(&subscription),
+            ["bedroom Some(Volume(6)) -> Volume(7)"]
+        );
+    }
+
+    #[test]
+    fn unsubscribe_test() {
+        let (subscriber, subscription) = Subscriber::new(EventFilter::all());
+        let handle = subscription.unsubscribe_handle();
+
+        assert!(subscriber.deliver(&volume_changed("kitchen", 10, 20)));
+
+        std::thread::spawn(move || handle.unsubscribe())
+            .join()
+            .unwrap();
+
+        assert!(!subscriber.is_active());
+        assert!(!subscriber.deliver(&volume_changed("kitchen", 20, 30)));
+        assert!(subscription.recv().is_err());
+
+        // the receiver is told once the device manager is gone
+        let (subscriber, subscription) = Subscriber::new(EventFilter::all());
+        drop(subscriber);
+        assert_eq!(
+            subscription.recv_timeout(Duration::from_secs(1)).err(),
+            Some(RecvTimeoutError::Disconnected)
+        );
+
+        // timeouts too long to compute a deadline for wait as long as needed
+        let (subscriber, subscription) = Subscriber::new(EventFilter::all());
+        subscriber.deliver(&volume_changed("kitchen", 10, 20));
+        assert!(subscription.recv_timeout(Duration::MAX).is_ok());
+        drop(subscriber);
+        assert_eq!(
+            subscription.recv_timeout(Duration::MAX).err(),
+            Some(RecvTimeoutError::Disconnected)
+        );
+    }
+}
@@ -21,6 +21,7 @@ pub struct Device {
     pub play_status: Option<Arc<PlayControlCommand>>,
     pub play_info: Option<Arc<PlayInfoData>>,
     pub pre_channels: Vector<PreChannel>,
+    pub online: bool,
 }
 
 impl Device {
@@ -47,6 +48,7 @@ impl From<device::Device> for Device {
                     channel: Arc::new(c),
                 })
                 .collect(),
+            online: d.is_online(),
         }
     }
 }
@@ -4,8 +4,10 @@ use druid::im::HashMap;
 use druid::widget::ViewSwitcher;
 use druid::{AppLauncher, PlatformError, Target, Widget, WindowDesc};
 
+use libratone_rs::cli_util::init_logging;
 use libratone_rs::device;
 use libratone_rs::device::{DeviceManager, DeviceManagerConfig};
+use libratone_rs::fake;
 use libratone_rs_ui::appstate::{AppState, Route};
 use libratone_rs_ui::commands;
 use libratone_rs_ui::delegate::Delegate;
@@ -22,39 +24,55 @@ fn build_ui() -> impl Widget<AppState> {
     )
 }
 
+fn create_device_manager() -> libratone_rs::Result<DeviceManager> {
+    // LIBRATONE_FAKE=1 runs against simulated speakers instead of the network
+    let config = if std::env::var_os("LIBRATONE_FAKE").is_some() {
+        fake::demo_environment().device_manager_config()?
+    } else {
+        DeviceManagerConfig::default()?
+    };
+
+    DeviceManager::new(config)
+}
+
 fn main() -> Result<(), PlatformError> {
+    // there is no command line to pass -v on, RUST_LOG sets the verbosity
+    init_logging(0);
+
     let mock_state = AppState {
         route: Route::DeviceList,
         devices: HashMap::new(),
     };
 
-    let device_manager = Arc::new(DeviceManager::new(DeviceManagerConfig::default()?)?);
+    let device_manager = Arc::new(create_device_manager().map_err(anyhow::Error::from)?);
     let device_manager_events = device_manager.listen();
 
     let window = WindowDesc::new(build_ui()).title("Libratone");
 
-    let app = AppLauncher::with_window(window)
-        .log_to_console()
-        .delegate(Delegate {
-            device_manager: Arc::clone(&device_manager),
-        });
+    let app = AppLauncher::with_window(window).delegate(Delegate {
+        device_manager: Arc::clone(&device_manager),
+    });
 
     let event_sink = app.get_external_handle();
 
     std::thread::spawn(move || {
         for event in device_manager_events {
-            println!("Device manager event: {:?}", &event);
+            tracing::debug!("device manager event: {:?}", &event);
 
             match event {
                 device::DeviceManagerEvent::DeviceDiscovered(device) => {
-                    println!(
-                        "device {} discovered at {}, fetching info",
-                        device.id(),
-                        device.addr()
+                    tracing::info!(
+                        device_id = %device.id(),
+                        addr = %device.addr(),
+                        "device discovered, fetching info"
                     );
 
                     if let Err(err) = device_manager.fetch_info(&device.id()) {
-                        println!("error fetching device info: {}", err);
+                        tracing::warn!(
+                            device_id = %device.id(),
+                            error = %err,
+                            "error fetching device info"
+                        );
                     };
 
                     event_sink
@@ -65,8 +83,17 @@ fn main() -> Result<(), PlatformError> {
                         )
                         .expect("error sending event to sink");
                 }
-                device::DeviceManagerEvent::DeviceUpdated(device) => {
-                    println!("Device update: {:?}", device);
+                device::DeviceManagerEvent::DeviceReappeared(device) => {
+                    tracing::info!(device_id = %device.id(), "device reappeared, fetching info");
+
+                    if let Err(err) = device_manager.fetch_info(&device.id()) {
+                        tracing::warn!(
+                            device_id = %device.id(),
+                            error = %err,
+                            "error fetching device info"
+                        );
+                    };
+
                     event_sink
                         .submit_command(
                             commands::DeviceUpdated::SELECTOR,
@@ -75,6 +102,28 @@ fn main() -> Result<(), PlatformError> {
                         )
                         .expect("error sending event to sink");
                 }
+                device::DeviceManagerEvent::DeviceUpdated(device)
+                | device::DeviceManagerEvent::DeviceLost(device) => {
+                    tracing::debug!(device_id = %device.id(), "device update: {:?}", device);
+                    event_sink
+                        .submit_command(
+                            commands::DeviceUpdated::SELECTOR,
+                            Box::new(device.into()),
+                            Target::Auto,
+                        )
+                        .expect("error sending event to sink");
+                }
+                device::DeviceManagerEvent::DevicePropertyChanged { device_id, .. } => {
+                    if let Some(device) = device_manager.device(&device_id) {
+                        event_sink
+                            .submit_command(
+                                commands::DeviceUpdated::SELECTOR,
+                                Box::new(device.into()),
+                                Target::Auto,
+                            )
+                            .expect("error sending event to sink");
+                    }
+                }
             }
         }
     });
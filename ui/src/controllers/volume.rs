@@ -16,13 +16,21 @@ impl<W: Widget<Device>> Controller<Device, W> for VolumeController {
         data: &Device,
         env: &Env,
     ) {
-        if old_data.volume != data.volume && data.volume.is_some() {
-            let volume = data.volume.unwrap();
-            ctx.submit_command(SendCommand::command(
-                &data.id,
-                Volume::set(volume),
-                Box::new(move |d: &mut Device| d.volume = Some(volume)),
-            ));
+        if old_data.volume != data.volume {
+            if let Some(volume) = data.volume {
+                match Volume::set(volume) {
+                    Ok(packet) => ctx.submit_command(SendCommand::command(
+                        &data.id,
+                        packet,
+                        Box::new(move |d: &mut Device| d.volume = Some(volume)),
+                    )),
+                    Err(err) => tracing::warn!(
+                        device_id = data.id.as_str(),
+                        error = %err,
+                        "error setting volume"
+                    ),
+                }
+            }
         }
 
         child.update(ctx, old_data, data, env);
@@ -30,12 +30,13 @@ impl AppDelegate<AppState> for Delegate {
             let cmd = cmd.get(SendCommand::SELECTOR).unwrap();
 
             data.devices
-                .modify_device(&cmd.device_id, |d| (&cmd.optimistic_update)(d));
+                .modify_device(&cmd.device_id, |d| (cmd.optimistic_update)(d));
 
             if let Err(err) = self.device_manager.send_packet(&cmd.device_id, &cmd.packet) {
-                println!(
-                    "error sending command to device {}: {}",
-                    &cmd.device_id, err
+                tracing::warn!(
+                    device_id = cmd.device_id.as_str(),
+                    error = %err,
+                    "error sending command to device"
                 );
             }
 
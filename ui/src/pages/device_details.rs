@@ -37,23 +37,27 @@ fn control_button(
     action: PlayControlCommand,
 ) -> impl Widget<Device> {
     Button::new(label).on_click(move |ctx: &mut EventCtx, device: &mut Device, _env| {
-        ctx.submit_command(SendCommand::command(
-            &device.id,
-            PlayControl::set(action),
-            |_| {},
-        ))
+        match PlayControl::set(action) {
+            Ok(packet) => ctx.submit_command(SendCommand::command(&device.id, packet, |_| {})),
+            Err(err) => tracing::warn!(
+                device_id = device.id.as_str(),
+                error = %err,
+                "error sending play control command"
+            ),
+        }
     })
 }
 
 fn favorite_item() -> impl Widget<(Device, PreChannel)> {
     Flex::row().with_flex_child(
         Button::new(|(_, ch): &(Device, PreChannel), _env: &_| ch.name.clone()).on_click(
-            |ctx, (device, ch), _env| {
-                ctx.submit_command(SendCommand::command(
-                    &device.id,
-                    PlayInfo::set(ch.channel.play_info_data()),
-                    |_| {},
-                ))
+            |ctx, (device, ch), _env| match PlayInfo::set(ch.channel.play_info_data()) {
+                Ok(packet) => ctx.submit_command(SendCommand::command(&device.id, packet, |_| {})),
+                Err(err) => tracing::warn!(
+                    device_id = device.id.as_str(),
+                    error = %err,
+                    "error playing favorite"
+                ),
             },
         ),
         1.0,
@@ -74,7 +78,7 @@ pub fn build_device_details() -> impl Widget<AppState> {
                     let y = y as u8;
 
                     if x != &Some(y) {
-                        *x = Some(y as u8);
+                        *x = Some(y);
                     }
                 },
             ));
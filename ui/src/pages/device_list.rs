@@ -22,7 +22,7 @@ pub fn build_device_list() -> impl Widget<AppState> {
         Flex::column().with_flex_child(
             Scroll::new(List::new(device_item).lens(AppState::devices.map(
                 |x| {
-                    let res: Vector<Device> = x.values().cloned().collect();
+                    let res: Vector<Device> = x.values().filter(|d| d.online).cloned().collect();
                     res
                 },
                 |_x, _y| {},